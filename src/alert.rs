@@ -0,0 +1,255 @@
+//! threshold alert rules.
+//!
+//! a rule reads one field of a metric over a trailing window, optionally as
+//! a share of another field, and fires when the value crosses its
+//! threshold. after firing it stays quiet for its cooldown. the server
+//! evaluates `alerts.rules` every `alerts.interval` seconds; `Evaluator`
+//! takes the time as an argument so rules can be checked against any clock.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{constants, BucketType, FindOptions, MetricData, QueryFilter, Traqq};
+
+/// alert rules and where the server sends their firings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AlertsConfig {
+    pub rules: Vec<AlertRule>,
+    /// seconds between evaluations in the server
+    pub interval: u64,
+    /// file each firing is appended to as a JSON line
+    pub file: Option<String>,
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            interval: constants::ALERT_INTERVAL,
+            file: None,
+        }
+    }
+}
+
+/// how a rule's value is compared with its threshold
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Comparison {
+    #[serde(rename = ">")]
+    Above,
+    #[serde(rename = ">=")]
+    AtLeast,
+    #[serde(rename = "<")]
+    Below,
+    #[serde(rename = "<=")]
+    AtMost,
+}
+
+impl Comparison {
+    pub fn holds(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::Above => value > threshold,
+            Comparison::AtLeast => value >= threshold,
+            Comparison::Below => value < threshold,
+            Comparison::AtMost => value <= threshold,
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Comparison::Above => ">",
+            Comparison::AtLeast => ">=",
+            Comparison::Below => "<",
+            Comparison::AtMost => "<=",
+        })
+    }
+}
+
+/// e.g. purchases below 50 in the last hour:
+/// `{"name":"few_purchases","metric":"add/event","field":"purchase","window":3600,"comparison":"<","threshold":50}`
+#[derive(Debug, Clone, Deserialize)]
+pub struct AlertRule {
+    pub name: String,
+    /// metric in the `find_str` shape, `type/pattern[/add_key]`
+    pub metric: String,
+    /// field of the metric, as read by `MetricData::value`
+    /// (`count` for bmp, `sum`, `count` or `avg` for adv summaries)
+    pub field: String,
+    /// divide by this field of the same metric, or `*` for the total of all
+    /// its fields, to alert on a rate. no value while the divisor is 0
+    #[serde(default)]
+    pub per: Option<String>,
+    /// trailing seconds the value covers, rounded out to whole buckets
+    pub window: u64,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    /// seconds after firing before the rule may fire again
+    #[serde(default)]
+    pub cooldown: u64,
+    /// buckets to read; the finest stored of minute, hour and day by default
+    #[serde(default)]
+    pub granularity: Option<BucketType>,
+}
+
+impl AlertRule {
+    /// check the rule can be evaluated against `traqq`'s config
+    pub fn validate(&self, time: &crate::TimeConfig) -> Result<(), String> {
+        let err = |msg: String| Err(format!("alert rule '{}': {}", self.name, msg));
+
+        if self.name.is_empty() {
+            return Err("alert rule without a name".to_string());
+        }
+        if let Err(e) = FindOptions::parse(&self.metric) {
+            return err(e);
+        }
+        if self.window == 0 {
+            return err("window must be positive".to_string());
+        }
+        if let Some(granularity) = self.granularity {
+            if !time.stores(granularity) {
+                return err(format!("{} buckets are not stored", granularity.name()));
+            }
+        }
+        Ok(())
+    }
+
+    /// the rule's value over the window ending at `now`, None when a rate's
+    /// divisor is 0
+    pub fn value(&self, traqq: &Traqq, now: i64) -> Result<Option<f64>, String> {
+        let opts = FindOptions {
+            merge: true,
+            ..FindOptions::parse(&self.metric)?
+        };
+        let granularity = self.granularity.unwrap_or_else(|| {
+            [BucketType::Minute, BucketType::Hourly]
+                .into_iter()
+                .find(|g| traqq.config.time.stores(*g))
+                .unwrap_or(BucketType::Daily)
+        });
+
+        let result = traqq.query_filtered(
+            now - self.window as i64,
+            now,
+            granularity,
+            &QueryFilter::from_find(&opts),
+        )?;
+        // merged bmp counts and tops cover the whole window, not a sum of buckets
+        let merged = result.find(opts);
+        let read = |field: &str| -> f64 {
+            merged
+                .iter()
+                .map(|r| match field {
+                    "*" => total(&r.result),
+                    field => r.result.value(field),
+                })
+                .sum()
+        };
+
+        let value = read(&self.field);
+        Ok(match &self.per {
+            None => Some(value),
+            Some(per) => {
+                let divisor = read(per);
+                (divisor != 0.0).then(|| value / divisor)
+            }
+        })
+    }
+}
+
+/// a rule that fired
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Firing {
+    pub rule: String,
+    /// evaluation time
+    pub timestamp: i64,
+    pub value: f64,
+    pub threshold: f64,
+    pub comparison: Comparison,
+    pub message: String,
+}
+
+/// the outcome of one evaluation pass
+#[derive(Debug, Clone, Default)]
+pub struct Evaluation {
+    pub firings: Vec<Firing>,
+    /// rules that could not be evaluated, as "rule: error"
+    pub errors: Vec<String>,
+}
+
+/// evaluates rules and tracks their cooldowns
+pub struct Evaluator {
+    rules: Vec<AlertRule>,
+    /// rule name -> last firing
+    fired: HashMap<String, i64>,
+}
+
+impl Evaluator {
+    pub fn new(rules: Vec<AlertRule>) -> Self {
+        Self {
+            rules,
+            fired: HashMap::new(),
+        }
+    }
+
+    /// check every rule at `now` (unix seconds), skipping rules still cooling
+    /// down from an earlier firing. each rule is evaluated on its own, so one
+    /// that fails to read doesn't hold back the others' firings
+    pub fn evaluate(&mut self, traqq: &Traqq, now: i64) -> Evaluation {
+        let mut evaluation = Evaluation::default();
+
+        for rule in &self.rules {
+            if let Some(last) = self.fired.get(&rule.name) {
+                if now - last < rule.cooldown as i64 {
+                    continue;
+                }
+            }
+
+            let value = match rule.value(traqq, now) {
+                Ok(Some(value)) => value,
+                Ok(None) => continue,
+                Err(e) => {
+                    evaluation.errors.push(format!("{}: {}", rule.name, e));
+                    continue;
+                }
+            };
+            if !rule.comparison.holds(value, rule.threshold) {
+                continue;
+            }
+
+            let subject = match &rule.per {
+                Some(per) => format!("{} {} per {}", rule.metric, rule.field, per),
+                None => format!("{} {}", rule.metric, rule.field),
+            };
+            evaluation.firings.push(Firing {
+                rule: rule.name.clone(),
+                timestamp: now,
+                value,
+                threshold: rule.threshold,
+                comparison: rule.comparison,
+                message: format!(
+                    "{}: {} over the last {}s is {} ({} {})",
+                    rule.name, subject, rule.window, value, rule.comparison, rule.threshold
+                ),
+            });
+            self.fired.insert(rule.name.clone(), now);
+        }
+
+        evaluation
+    }
+}
+
+/// every field of a result added up. an adv summary counts for nothing, as
+/// the per-field hash stored beside it already holds the same total
+fn total(data: &MetricData) -> f64 {
+    match data {
+        MetricData::Count(c) => *c as f64,
+        MetricData::Hash(h) => h.values().map(|v| *v as f64).sum(),
+        MetricData::FloatHash(h) => h.values().sum(),
+        MetricData::Summary { .. } => 0.0,
+        MetricData::Ranked(pairs) => pairs.iter().map(|(_, v)| v).sum(),
+    }
+}
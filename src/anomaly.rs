@@ -0,0 +1,267 @@
+//! anomaly detection over series.
+//!
+//! each point is judged against the trailing window of points before it:
+//! either by z-score (mean and standard deviation) or, more robust to
+//! earlier spikes, by median and MAD. a point outside
+//! `expected ± threshold * spread` is flagged.
+//!
+//! settings come from `mapping.anomaly`, keyed by metric in the `find_str`
+//! shape (`add/event`, `adv/event/amount`); globs are allowed and unmatched
+//! metrics use the defaults.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{constants, FindOptions, MappingConfig, MetricType, QueryFilter};
+
+/// how the expected value and spread of a window are measured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Method {
+    /// mean and standard deviation
+    #[default]
+    #[serde(alias = "z-score", alias = "z_score")]
+    Zscore,
+    /// median and median absolute deviation, scaled to match a standard
+    /// deviation on normal data
+    Mad,
+}
+
+/// detection settings for one metric
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AnomalyConfig {
+    pub method: Method,
+    /// trailing points each point is compared with
+    pub window: usize,
+    /// spreads (standard deviations or scaled MADs) a point may stray
+    pub threshold: f64,
+    /// points the window needs before anything in it is judged
+    pub min_points: usize,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        Self {
+            method: Method::default(),
+            window: constants::ANOMALY_WINDOW,
+            threshold: constants::ANOMALY_THRESHOLD,
+            min_points: constants::ANOMALY_MIN_POINTS,
+        }
+    }
+}
+
+impl AnomalyConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.window < 2 {
+            return Err(format!(
+                "anomaly window must be at least 2, got {}",
+                self.window
+            ));
+        }
+        if self.min_points < 2 || self.min_points > self.window {
+            return Err(format!(
+                "anomaly min_points must be between 2 and the window ({}), got {}",
+                self.window, self.min_points
+            ));
+        }
+        if self.threshold.is_nan() || self.threshold <= 0.0 {
+            return Err(format!(
+                "anomaly threshold must be positive, got {}",
+                self.threshold
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// a flagged point
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Anomaly {
+    /// bucket start
+    pub timestamp: i64,
+    pub value: f64,
+    /// the window's mean (zscore) or median (mad)
+    pub expected: f64,
+    /// bounds of the expected range
+    pub low: f64,
+    pub high: f64,
+    /// signed distance from expected in spreads, None when the window was flat
+    pub score: Option<f64>,
+    /// the bucket was still open: `value` is its count so far projected to
+    /// the whole bucket at the current rate
+    #[serde(default)]
+    pub projected: bool,
+}
+
+/// the flagged points of one metric field
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    pub metric: String,
+    pub field: String,
+    pub config: AnomalyConfig,
+    pub anomalies: Vec<Anomaly>,
+}
+
+/// flag the points of a series, (bucket start, value) in bucket order, that
+/// fall outside the range their trailing window predicts
+pub fn detect(points: &[(i64, f64)], config: &AnomalyConfig) -> Vec<Anomaly> {
+    let mut anomalies = Vec::new();
+
+    for (i, &(timestamp, value)) in points.iter().enumerate() {
+        let window: Vec<f64> = points[i.saturating_sub(config.window)..i]
+            .iter()
+            .map(|(_, v)| *v)
+            .collect();
+        if window.len() < config.min_points {
+            continue;
+        }
+
+        let (expected, spread) = match config.method {
+            Method::Zscore => {
+                let mean = window.iter().sum::<f64>() / window.len() as f64;
+                let variance =
+                    window.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / window.len() as f64;
+                (mean, variance.sqrt())
+            }
+            Method::Mad => {
+                let median = median(window.clone());
+                let deviations = window.iter().map(|v| (v - median).abs()).collect();
+                (median, MAD_SCALE * self::median(deviations))
+            }
+        };
+
+        let margin = config.threshold * spread;
+        let (low, high) = (expected - margin, expected + margin);
+        if (low..=high).contains(&value) {
+            continue;
+        }
+
+        anomalies.push(Anomaly {
+            timestamp,
+            value,
+            expected,
+            low,
+            high,
+            score: (spread > 0.0).then(|| (value - expected) / spread),
+            projected: false,
+        });
+    }
+
+    anomalies
+}
+
+/// settings for a metric: an exact `mapping.anomaly` entry, else the most
+/// specific glob that matches it, else the defaults
+pub fn config_for(mapping: &MappingConfig, metric: &str) -> AnomalyConfig {
+    if let Some(config) = mapping.anomaly.get(metric) {
+        return *config;
+    }
+    let Ok(opts) = FindOptions::parse(metric) else {
+        return AnomalyConfig::default();
+    };
+
+    crate::utils::most_specific(&mapping.anomaly, |rule| {
+        QueryFilter::rule_matches(
+            rule,
+            opts.metric_type.as_str(),
+            &opts.key,
+            opts.add_key.as_deref(),
+        )
+    })
+    .copied()
+    .unwrap_or_default()
+}
+
+/// check a `mapping.anomaly` key and its settings
+pub(crate) fn validate_entry(metric: &str, config: &AnomalyConfig) -> Result<(), String> {
+    // keys may glob the type too, so they aren't `FindOptions`
+    let parts: Vec<&str> = metric.split('/').collect();
+    if !(2..=3).contains(&parts.len()) || parts.iter().any(|p| p.is_empty()) {
+        return Err(format!(
+            "invalid anomaly metric: {} (expected type/pattern)",
+            metric
+        ));
+    }
+    if !crate::utils::is_glob(parts[0]) {
+        parts[0].parse::<MetricType>()?;
+    }
+    config
+        .validate()
+        .map_err(|e| format!("{} for {}", e, metric))
+}
+
+/// 1 / the 75th percentile of the standard normal distribution
+const MAD_SCALE: f64 = 1.4826;
+
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(values: &[f64]) -> Vec<(i64, f64)> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (i as i64 * 86400, *v))
+            .collect()
+    }
+
+    #[test]
+    fn test_flags_points_outside_the_window_range() {
+        let config = AnomalyConfig {
+            window: 5,
+            min_points: 3,
+            ..AnomalyConfig::default()
+        };
+        let points = series(&[10.0, 12.0, 11.0, 9.0, 10.0, 11.0, 40.0, 10.0]);
+
+        let flagged = detect(&points, &config);
+        assert_eq!(flagged.len(), 1);
+        let spike = &flagged[0];
+        assert_eq!((spike.timestamp, spike.value), (6 * 86400, 40.0));
+        assert!((spike.expected - 10.6).abs() < 1e-9);
+        assert!(spike.low < 10.6 && spike.high < 40.0);
+        assert!(spike.score.unwrap() > 3.0);
+
+        // a point needs min_points before it to be judged
+        let short = detect(&series(&[1.0, 100.0]), &config);
+        assert!(short.is_empty());
+    }
+
+    #[test]
+    fn test_mad_ignores_an_earlier_spike() {
+        let points = series(&[10.0, 12.0, 11.0, 500.0, 9.0, 13.0, 10.0, 30.0]);
+        let zscore = AnomalyConfig {
+            window: 7,
+            min_points: 7,
+            ..AnomalyConfig::default()
+        };
+        let mad = AnomalyConfig {
+            method: Method::Mad,
+            ..zscore
+        };
+
+        // the 500 inflates the standard deviation enough to hide the 30
+        assert!(detect(&points, &zscore).is_empty());
+        let flagged = detect(&points, &mad);
+        assert_eq!(flagged.len(), 1);
+        assert_eq!(flagged[0].value, 30.0);
+        assert_eq!(flagged[0].expected, 11.0);
+
+        // a flat window flags any change, without a score
+        let mut values = vec![5.0; 7];
+        values.push(6.0);
+        let flat = detect(&series(&values), &mad);
+        assert_eq!(flat.len(), 1);
+        assert_eq!(flat[0].score, None);
+    }
+}
@@ -0,0 +1,338 @@
+//! in-process LRU cache of query results for closed buckets.
+//!
+//! a bucket that has ended only changes when an event is backfilled into it
+//! or it is compacted, so its `DayResult` can be reused until then. entries
+//! are keyed by bucket and query filter, and evicted least recently used
+//! first once their estimated size exceeds the memory budget.
+//!
+//! invalidation only sees writes made through this `Traqq`. a backfill by
+//! another process sharing the storage prefix leaves stale entries behind,
+//! so deployments with several writers should disable the cache.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{BucketType, DayResult, MetricData, QueryFilter};
+
+/// cache counters, as reported by `Traqq::cache_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// entries dropped to stay within the budget
+    pub evictions: u64,
+    /// entries dropped because their bucket was written to
+    pub invalidations: u64,
+    pub entries: usize,
+    /// estimated size of the cached results
+    pub bytes: usize,
+    pub budget: usize,
+}
+
+type Bucket = (BucketType, i64);
+
+struct Entry {
+    day: DayResult,
+    bytes: usize,
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// bucket -> filter signature -> entry
+    buckets: HashMap<Bucket, HashMap<String, Entry>>,
+    /// last use -> entry, oldest first
+    recency: BTreeMap<u64, (Bucket, String)>,
+    /// bucket -> times it was invalidated, so a read that raced a write
+    /// isn't cached
+    generations: HashMap<Bucket, u64>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+/// LRU cache of `DayResult`s with a memory budget in bytes; 0 disables it
+pub struct ResultCache {
+    budget: usize,
+    inner: Mutex<Inner>,
+}
+
+impl ResultCache {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.budget > 0
+    }
+
+    /// the cached result for a bucket read with `filter`, counting a hit or miss
+    pub fn get(
+        &self,
+        bucket_type: BucketType,
+        timestamp: i64,
+        filter: &QueryFilter,
+    ) -> Option<DayResult> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        let signature = signature(filter);
+        let found = inner
+            .buckets
+            .get_mut(&(bucket_type, timestamp))
+            .and_then(|entries| entries.get_mut(&signature))
+            .map(|entry| {
+                let last_use = entry.tick;
+                entry.tick = tick;
+                (last_use, entry.day.clone())
+            });
+
+        match found {
+            Some((last_use, day)) => {
+                inner.recency.remove(&last_use);
+                inner
+                    .recency
+                    .insert(tick, ((bucket_type, timestamp), signature));
+                inner.stats.hits += 1;
+                Some(day)
+            }
+            None => {
+                inner.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// the bucket's generation, to read before the bucket and pass to `insert`
+    pub fn generation(&self, bucket_type: BucketType, timestamp: i64) -> u64 {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner
+            .generations
+            .get(&(bucket_type, timestamp))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// cache a closed bucket's result, evicting older entries to fit the budget.
+    /// skipped if the bucket was invalidated since `generation` was read, as
+    /// the result may predate that write
+    pub fn insert(
+        &self,
+        bucket_type: BucketType,
+        filter: &QueryFilter,
+        day: &DayResult,
+        generation: u64,
+    ) {
+        let bytes = estimated_size(day);
+        if bytes > self.budget {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let current = inner.generations.get(&(bucket_type, day.timestamp));
+        if current.copied().unwrap_or(0) != generation {
+            return;
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        let bucket = (bucket_type, day.timestamp);
+        let signature = signature(filter);
+
+        let entry = Entry {
+            day: day.clone(),
+            bytes,
+            tick,
+        };
+        if let Some(old) = inner
+            .buckets
+            .entry(bucket)
+            .or_default()
+            .insert(signature.clone(), entry)
+        {
+            inner.recency.remove(&old.tick);
+            inner.stats.bytes -= old.bytes;
+            inner.stats.entries -= 1;
+        }
+        inner.recency.insert(tick, (bucket, signature));
+        inner.stats.bytes += bytes;
+        inner.stats.entries += 1;
+
+        while inner.stats.bytes > self.budget {
+            let Some((_, (bucket, signature))) = inner.recency.pop_first() else {
+                break;
+            };
+            inner.remove(bucket, &signature);
+            inner.stats.evictions += 1;
+        }
+    }
+
+    /// drop every cached result for a bucket
+    pub fn invalidate(&self, bucket_type: BucketType, timestamp: i64) {
+        if !self.enabled() {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        *inner
+            .generations
+            .entry((bucket_type, timestamp))
+            .or_default() += 1;
+        let Some(entries) = inner.buckets.remove(&(bucket_type, timestamp)) else {
+            return;
+        };
+        for entry in entries.into_values() {
+            inner.recency.remove(&entry.tick);
+            inner.stats.bytes -= entry.bytes;
+            inner.stats.entries -= 1;
+            inner.stats.invalidations += 1;
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        CacheStats {
+            budget: self.budget,
+            ..inner.stats
+        }
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, bucket: Bucket, signature: &str) {
+        let Some(entries) = self.buckets.get_mut(&bucket) else {
+            return;
+        };
+        if let Some(entry) = entries.remove(signature) {
+            self.stats.bytes -= entry.bytes;
+            self.stats.entries -= 1;
+        }
+        if entries.is_empty() {
+            self.buckets.remove(&bucket);
+        }
+    }
+}
+
+/// the parts of a filter that change what a bucket read returns
+fn signature(filter: &QueryFilter) -> String {
+    format!("{:?}|{:?}|{:?}", filter.accept, filter.ignore, filter.top)
+}
+
+/// rough heap footprint of a result: its strings plus a fixed cost per value
+fn estimated_size(day: &DayResult) -> usize {
+    const VALUE: usize = 32;
+
+    let results: usize = day
+        .results
+        .iter()
+        .map(|r| {
+            let strings = r.metric_type.len()
+                + r.location.len()
+                + r.key.iter().map(|k| k.len() + VALUE).sum::<usize>()
+                + r.add_key.as_ref().map_or(0, String::len);
+            let data = match &r.result {
+                MetricData::Count(_) | MetricData::Summary { .. } => VALUE,
+                MetricData::Hash(h) => h.keys().map(|k| k.len() + VALUE).sum(),
+                MetricData::FloatHash(h) => h.keys().map(|k| k.len() + VALUE).sum(),
+                MetricData::Ranked(pairs) => pairs.iter().map(|(m, _)| m.len() + VALUE).sum(),
+            };
+            strings + data + 4 * VALUE
+        })
+        .sum();
+
+    day.date.len() + results + 2 * VALUE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MetricResult;
+
+    fn day(timestamp: i64, fields: usize) -> DayResult {
+        let hash = (0..fields).map(|i| (format!("f{}", i), 1)).collect();
+        DayResult {
+            date: timestamp.to_string(),
+            timestamp,
+            results: vec![MetricResult {
+                metric_type: "add".to_string(),
+                key: vec!["event".to_string()],
+                location: format!("q:add:d:{}:event", timestamp),
+                add_key: None,
+                result: MetricData::Hash(hash),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_hits_misses_and_invalidation() {
+        let cache = ResultCache::new(1 << 20);
+        let filter = QueryFilter::default();
+        assert!(cache.get(BucketType::Daily, 0, &filter).is_none());
+
+        cache.insert(BucketType::Daily, &filter, &day(0, 3), 0);
+        assert_eq!(
+            cache.get(BucketType::Daily, 0, &filter).unwrap().timestamp,
+            0
+        );
+
+        // a different filter or bucket type is a different entry
+        let top = QueryFilter {
+            top: Some(5),
+            ..QueryFilter::default()
+        };
+        assert!(cache.get(BucketType::Daily, 0, &top).is_none());
+        assert!(cache.get(BucketType::Hourly, 0, &filter).is_none());
+
+        cache.invalidate(BucketType::Daily, 0);
+        assert!(cache.get(BucketType::Daily, 0, &filter).is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 4));
+        assert_eq!((stats.entries, stats.bytes, stats.invalidations), (0, 0, 1));
+    }
+
+    #[test]
+    fn test_read_racing_a_write_is_not_cached() {
+        let cache = ResultCache::new(1 << 20);
+        let filter = QueryFilter::default();
+
+        // a query reads the bucket, then a write lands before it caches
+        let generation = cache.generation(BucketType::Daily, 0);
+        cache.invalidate(BucketType::Daily, 0);
+        cache.insert(BucketType::Daily, &filter, &day(0, 3), generation);
+        assert!(cache.get(BucketType::Daily, 0, &filter).is_none());
+
+        let generation = cache.generation(BucketType::Daily, 0);
+        cache.insert(BucketType::Daily, &filter, &day(0, 3), generation);
+        assert!(cache.get(BucketType::Daily, 0, &filter).is_some());
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let filter = QueryFilter::default();
+        let size = estimated_size(&day(0, 10));
+        let cache = ResultCache::new(size * 2);
+
+        cache.insert(BucketType::Daily, &filter, &day(0, 10), 0);
+        cache.insert(BucketType::Daily, &filter, &day(1, 10), 0);
+        // touch 0 so 1 is the oldest
+        assert!(cache.get(BucketType::Daily, 0, &filter).is_some());
+        cache.insert(BucketType::Daily, &filter, &day(2, 10), 0);
+
+        assert!(cache.get(BucketType::Daily, 1, &filter).is_none());
+        assert!(cache.get(BucketType::Daily, 0, &filter).is_some());
+        assert!(cache.get(BucketType::Daily, 2, &filter).is_some());
+
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.evictions), (2, 1));
+        assert!(stats.bytes <= stats.budget);
+
+        // a result larger than the whole budget is never cached
+        cache.insert(BucketType::Daily, &filter, &day(3, 100), 0);
+        assert!(cache.get(BucketType::Daily, 3, &filter).is_none());
+    }
+}
@@ -0,0 +1,79 @@
+//! catalog of recorded metrics.
+//!
+//! `Traqq::catalog` lists what each day's key-tracking set holds: the metric
+//! types, patterns and add_keys written that day, and how many fields each
+//! hash or leaderboard has. only field counts are read, never values.
+
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+/// one metric tracked in a bucket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    pub metric_type: String,
+    /// the pattern, e.g. "event" or "event~geo"
+    pub pattern: String,
+    /// for adv: the value key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub add_key: Option<String>,
+    /// distinct fields in the hash or leaderboard, None for bmp
+    pub fields: Option<usize>,
+}
+
+impl CatalogEntry {
+    /// the metric in the `find_str` shape, `type/pattern[/add_key]`
+    pub fn metric(&self) -> String {
+        match &self.add_key {
+            Some(add_key) => format!("{}/{}/{}", self.metric_type, self.pattern, add_key),
+            None => format!("{}/{}", self.metric_type, self.pattern),
+        }
+    }
+}
+
+/// the metrics tracked in one bucket, sorted by type, pattern and add_key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogDay {
+    pub date: String,
+    pub timestamp: i64,
+    pub metrics: Vec<CatalogEntry>,
+}
+
+/// the metrics tracked over a range, a day at a time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Catalog {
+    pub days: Vec<CatalogDay>,
+}
+
+impl Catalog {
+    /// every metric tracked on any day, in the `find_str` shape
+    pub fn metrics(&self) -> Vec<String> {
+        self.entries()
+            .map(CatalogEntry::metric)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// the distinct patterns of a metric type
+    pub fn patterns(&self, metric_type: &str) -> Vec<String> {
+        self.entries()
+            .filter(|e| e.metric_type == metric_type)
+            .map(|e| e.pattern.clone())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// the largest field count a metric reached on any day
+    pub fn cardinality(&self, metric: &str) -> Option<usize> {
+        self.entries()
+            .filter(|e| e.metric() == metric)
+            .filter_map(|e| e.fields)
+            .max()
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &CatalogEntry> {
+        self.days.iter().flat_map(|d| &d.metrics)
+    }
+}
@@ -1,7 +1,10 @@
 use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::net::TcpStream;
 
+use crate::alert::Firing;
 use crate::server::Response;
+use crate::stream::Page;
+use crate::{BucketType, FindOptions, MetricType};
 
 /// TCP client for the traqq server.
 /// sends newline-delimited JSON commands and reads JSON responses.
@@ -30,24 +33,189 @@ impl Client {
 
     /// query a time range
     pub fn query(&mut self, min: i64, max: i64) -> Result<Response, String> {
+        self.query_by(min, max, BucketType::Daily)
+    }
+
+    /// query a time range at the given granularity
+    pub fn query_by(
+        &mut self,
+        min: i64,
+        max: i64,
+        granularity: BucketType,
+    ) -> Result<Response, String> {
         let cmd = serde_json::json!({
             "cmd": "query",
             "min": min,
             "max": max,
+            "granularity": granularity,
         });
         self.send(cmd)
     }
 
     /// query the last N days
     pub fn query_days(&mut self, days: i32) -> Result<Response, String> {
+        self.query_days_by(days, BucketType::Daily, None)
+    }
+
+    /// query the last N days at the given granularity,
+    /// optionally reading `top` entries per leaderboard
+    pub fn query_days_by(
+        &mut self,
+        days: i32,
+        granularity: BucketType,
+        top: Option<usize>,
+    ) -> Result<Response, String> {
         let cmd = serde_json::json!({
             "cmd": "query_days",
             "days": days,
+            "granularity": granularity,
+            "top": top,
+        });
+        self.send(cmd)
+    }
+
+    /// query a calendar range expression, e.g. `last 7d` or `2026-09`
+    pub fn query_range(
+        &mut self,
+        range: &str,
+        granularity: BucketType,
+        top: Option<usize>,
+    ) -> Result<Response, String> {
+        let cmd = serde_json::json!({
+            "cmd": "query_range",
+            "range": range,
+            "granularity": granularity,
+            "top": top,
+        });
+        self.send(cmd)
+    }
+
+    /// query a range expression from whole months or weeks where rollups
+    /// cover it, otherwise days; see `Traqq::query_auto`
+    pub fn query_auto(&mut self, range: &str, top: Option<usize>) -> Result<Response, String> {
+        let cmd = serde_json::json!({
+            "cmd": "query_range",
+            "range": range,
+            "auto": true,
+            "top": top,
+        });
+        self.send(cmd)
+    }
+
+    /// stream a range expression bucket by bucket, optionally paging every
+    /// hash and leaderboard. the connection serves nothing else until the
+    /// stream is finished or dropped.
+    pub fn query_stream(
+        &mut self,
+        range: &str,
+        granularity: BucketType,
+        page: Option<Page>,
+    ) -> Result<ResponseStream<'_>, String> {
+        let mut cmd = serde_json::json!({
+            "cmd": "query_stream",
+            "range": range,
+            "granularity": granularity,
+        });
+        if let Some(page) = page {
+            cmd["offset"] = serde_json::json!(page.offset);
+            cmd["limit"] = serde_json::json!(page.limit);
+        }
+        self.write_command(cmd)?;
+        Ok(ResponseStream {
+            client: self,
+            done: false,
+        })
+    }
+
+    /// fetch one or more fields of a metric ("type/key") as zero-filled
+    /// series over a range expression
+    pub fn series(
+        &mut self,
+        metric: &str,
+        fields: &[String],
+        range: &str,
+        granularity: BucketType,
+    ) -> Result<Response, String> {
+        let cmd = serde_json::json!({
+            "cmd": "series",
+            "metric": metric,
+            "fields": fields,
+            "range": range,
+            "granularity": granularity,
+        });
+        self.send(cmd)
+    }
+
+    /// run a text query, e.g. `sum add/event~geo group by geo last 7d`
+    pub fn ql(&mut self, query: &str) -> Result<Response, String> {
+        self.send(serde_json::json!({ "cmd": "ql", "query": query }))
+    }
+
+    /// compare two range expressions, e.g. `this_week` and `last_week`
+    pub fn compare(&mut self, range: &str, previous: &str) -> Result<Response, String> {
+        let cmd = serde_json::json!({
+            "cmd": "compare",
+            "range": range,
+            "previous": previous,
+        });
+        self.send(cmd)
+    }
+
+    /// flag points of a metric's fields within a range expression that stray
+    /// from their trailing window
+    pub fn anomalies(
+        &mut self,
+        metric: &str,
+        fields: &[String],
+        range: &str,
+        granularity: BucketType,
+    ) -> Result<Response, String> {
+        let cmd = serde_json::json!({
+            "cmd": "anomalies",
+            "metric": metric,
+            "fields": fields,
+            "range": range,
+            "granularity": granularity,
+        });
+        self.send(cmd)
+    }
+
+    /// list the metrics tracked on each day of a range expression
+    pub fn catalog(&mut self, range: &str) -> Result<Response, String> {
+        let cmd = serde_json::json!({
+            "cmd": "catalog",
+            "range": range,
         });
         self.send(cmd)
     }
 
-    /// query and find specific metrics
+    /// subscribe to the server's alert firings. the connection carries
+    /// nothing else afterwards, so the client is consumed
+    pub fn subscribe_alerts(mut self) -> Result<AlertSubscription, String> {
+        let ack = self.send(serde_json::json!({ "cmd": "alerts" }))?;
+        if !ack.success {
+            return Err(ack.error.unwrap_or_default());
+        }
+        Ok(AlertSubscription { client: self })
+    }
+
+    /// compact hourly buckets that ended before `before`, or those older than
+    /// the server's configured age when `None`
+    pub fn compact(&mut self, before: Option<i64>) -> Result<Response, String> {
+        let cmd = serde_json::json!({
+            "cmd": "compact",
+            "before": before,
+        });
+        self.send(cmd)
+    }
+
+    /// hit and miss counters of the server's result cache
+    pub fn cache_stats(&mut self) -> Result<Response, String> {
+        self.send(serde_json::json!({ "cmd": "cache_stats" }))
+    }
+
+    /// query and find specific metrics. an unknown `metric_type` is an error
+    /// before anything is sent
     pub fn find(
         &mut self,
         min: i64,
@@ -56,35 +224,140 @@ impl Client {
         key: &str,
         add_key: Option<&str>,
         merge: bool,
+    ) -> Result<Response, String> {
+        self.find_typed(min, max, metric_type.parse()?, key, add_key, merge)
+    }
+
+    /// query and find specific metrics of a typed metric type
+    pub fn find_typed(
+        &mut self,
+        min: i64,
+        max: i64,
+        metric_type: MetricType,
+        key: &str,
+        add_key: Option<&str>,
+        merge: bool,
+    ) -> Result<Response, String> {
+        let opts = FindOptions {
+            metric_type,
+            key: key.to_string(),
+            add_key: add_key.map(String::from),
+            merge,
+            top: None,
+        };
+        self.find_with(min, max, &opts)
+    }
+
+    /// query and find specific metrics with full find options
+    pub fn find_with(
+        &mut self,
+        min: i64,
+        max: i64,
+        opts: &FindOptions,
     ) -> Result<Response, String> {
         let mut cmd = serde_json::json!({
             "cmd": "find",
             "min": min,
             "max": max,
-            "metric_type": metric_type,
-            "key": key,
-            "merge": merge,
+            "metric_type": opts.metric_type,
+            "key": opts.key,
+            "merge": opts.merge,
+            "top": opts.top,
         });
-        if let Some(ak) = add_key {
+        if let Some(ak) = &opts.add_key {
             cmd["add_key"] = serde_json::json!(ak);
         }
         self.send(cmd)
     }
 
     fn send(&mut self, cmd: serde_json::Value) -> Result<Response, String> {
+        self.write_command(cmd)?;
+        self.read_response()
+    }
+
+    fn write_command(&mut self, cmd: serde_json::Value) -> Result<(), String> {
         let line = serde_json::to_string(&cmd).map_err(|e| e.to_string())?;
 
         self.writer
             .write_all(line.as_bytes())
             .map_err(|e| e.to_string())?;
         self.writer.write_all(b"\n").map_err(|e| e.to_string())?;
-        self.writer.flush().map_err(|e| e.to_string())?;
+        self.writer.flush().map_err(|e| e.to_string())
+    }
 
+    fn read_response(&mut self) -> Result<Response, String> {
         let mut response_line = String::new();
-        self.reader
+        let read = self
+            .reader
             .read_line(&mut response_line)
             .map_err(|e| e.to_string())?;
+        if read == 0 {
+            return Err("connection closed".to_string());
+        }
 
         serde_json::from_str(&response_line).map_err(|e| format!("invalid response: {}", e))
     }
 }
+
+/// the chunks of a streamed response, read from the connection as they are
+/// iterated. each holds one bucket in `data`; a server error is yielded as
+/// `Err` and ends the stream. dropping it early reads the rest.
+pub struct ResponseStream<'a> {
+    client: &'a mut Client,
+    done: bool,
+}
+
+impl Iterator for ResponseStream<'_> {
+    type Item = Result<Response, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let response = match self.client.read_response() {
+            Ok(response) => response,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+        self.done = response.done;
+        match (response.success, response.done) {
+            (false, _) => Some(Err(response.error.unwrap_or_default())),
+            (true, true) => None,
+            (true, false) => Some(Ok(response)),
+        }
+    }
+}
+
+impl Drop for ResponseStream<'_> {
+    fn drop(&mut self) {
+        // leave the connection at the start of the next response
+        for _ in self.by_ref() {}
+    }
+}
+
+/// alert firings as the server sends them; blocks until the next one
+pub struct AlertSubscription {
+    client: Client,
+}
+
+impl Iterator for AlertSubscription {
+    type Item = Result<Firing, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let response = match self.client.read_response() {
+            Ok(response) => response,
+            // the connection closed or broke
+            Err(_) => return None,
+        };
+        if !response.success {
+            return Some(Err(response.error.unwrap_or_default()));
+        }
+        Some(
+            serde_json::from_value(response.data.unwrap_or_default())
+                .map_err(|e| format!("invalid alert: {}", e)),
+        )
+    }
+}
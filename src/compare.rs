@@ -0,0 +1,254 @@
+//! period-over-period comparison of two query results.
+//!
+//! each metric is merged across its period, then matched with the same
+//! metric in the other period field by field. leaderboards are read deeper
+//! than they are shown, so a member that fell out of the top in one period
+//! still carries its value and rank from that period.
+
+use std::collections::{BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{MetricData, MetricResult, QueryResult};
+
+/// how a field relates across the two periods
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldStatus {
+    /// present in both periods
+    Matched,
+    /// only in the current period
+    New,
+    /// only in the previous period
+    Disappeared,
+    /// top only: shown in one period and below everything read in the other,
+    /// so its value there is unknown and reported as 0
+    BelowDepth,
+}
+
+/// one field's values in both periods
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub current: f64,
+    pub previous: f64,
+    /// current - previous
+    pub delta: f64,
+    /// delta as a percentage of previous, None when previous is 0
+    pub percent: Option<f64>,
+    pub status: FieldStatus,
+    /// top only: 1-based rank in each period
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rank: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_rank: Option<usize>,
+}
+
+impl FieldChange {
+    /// places moved up the leaderboard (negative when it dropped)
+    pub fn rank_change(&self) -> Option<i64> {
+        Some(self.previous_rank? as i64 - self.rank? as i64)
+    }
+}
+
+/// a metric matched across both periods
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricComparison {
+    pub metric_type: String,
+    pub key: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub add_key: Option<String>,
+    /// adv only: the sum/count summary rather than the per-field values,
+    /// which share the same type, key and add_key
+    pub summary: bool,
+    pub fields: Vec<FieldChange>,
+}
+
+/// the result of comparing two periods
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Comparison {
+    pub metrics: Vec<MetricComparison>,
+    /// warnings from both queries
+    pub warnings: Vec<String>,
+}
+
+impl Comparison {
+    /// the comparison for a metric, by `find_str` shorthand ("type/key[/add_key]").
+    /// None when the metric is in neither period, an error when `query` is invalid.
+    /// for adv this is the per-field comparison; see `metric_summary`
+    pub fn metric(&self, query: &str) -> Result<Option<&MetricComparison>, String> {
+        self.lookup(query, false)
+    }
+
+    /// the comparison of an adv metric's sum/count summary
+    pub fn metric_summary(&self, query: &str) -> Result<Option<&MetricComparison>, String> {
+        self.lookup(query, true)
+    }
+
+    fn lookup(&self, query: &str, summary: bool) -> Result<Option<&MetricComparison>, String> {
+        let opts = crate::FindOptions::parse(query)?;
+        let mut key: Vec<&str> = opts.key.split('~').collect();
+        key.sort();
+
+        Ok(self.metrics.iter().find(|m| {
+            let mut metric_key: Vec<&str> = m.key.iter().map(String::as_str).collect();
+            metric_key.sort();
+            m.metric_type == opts.metric_type.as_str()
+                && m.summary == summary
+                && metric_key == key
+                && (opts.add_key.is_none() || m.add_key == opts.add_key)
+        }))
+    }
+}
+
+impl MetricComparison {
+    /// the change for a single field
+    pub fn field(&self, field: &str) -> Option<&FieldChange> {
+        self.fields.iter().find(|f| f.field == field)
+    }
+}
+
+/// compare every metric in `current` with the same metric in `previous`.
+/// leaderboards show the members `top_depth` ranks for their pattern in
+/// either period; `read_depth` is how deep both results were read
+pub fn compare(
+    current: &QueryResult,
+    previous: &QueryResult,
+    top_depth: impl Fn(&str) -> usize,
+    read_depth: usize,
+) -> Comparison {
+    let identity = |r: &MetricResult| {
+        let mut key = r.key.clone();
+        key.sort();
+        format!(
+            "{}/{}/{}/{}",
+            r.metric_type,
+            key.join("~"),
+            r.add_key.as_deref().unwrap_or(""),
+            matches!(r.result, MetricData::Summary { .. })
+        )
+    };
+
+    let mut previous_by_identity: HashMap<String, MetricResult> = previous
+        .merged()
+        .into_iter()
+        .map(|r| (identity(&r), r))
+        .collect();
+
+    let mut metrics = Vec::new();
+    let depths = |r: &MetricResult| (top_depth(&r.pattern_id()), read_depth);
+    for result in current.merged() {
+        let before = previous_by_identity.remove(&identity(&result));
+        metrics.push(compare_metric(
+            Some(&result),
+            before.as_ref(),
+            depths(&result),
+        ));
+    }
+    for result in previous_by_identity.into_values() {
+        metrics.push(compare_metric(None, Some(&result), depths(&result)));
+    }
+    metrics.sort_by(|a, b| {
+        (&a.metric_type, &a.key, &a.add_key, a.summary).cmp(&(
+            &b.metric_type,
+            &b.key,
+            &b.add_key,
+            b.summary,
+        ))
+    });
+
+    let mut warnings = current.warnings.clone();
+    warnings.extend(previous.warnings.iter().map(|w| format!("previous: {}", w)));
+
+    Comparison { metrics, warnings }
+}
+
+/// `(shown, read)`: a leaderboard shows the members ranked within `shown`
+/// in either period out of the `read` entries fetched for each
+fn compare_metric(
+    current: Option<&MetricResult>,
+    previous: Option<&MetricResult>,
+    (shown, read): (usize, usize),
+) -> MetricComparison {
+    let template = current.or(previous).expect("at least one side");
+    let current_values = current.map(|r| field_values(&r.result)).unwrap_or_default();
+    let previous_values = previous
+        .map(|r| field_values(&r.result))
+        .unwrap_or_default();
+    // a full list may have left out members that are only below the read
+    let truncated = |values: &HashMap<String, (f64, Option<usize>)>| {
+        values.values().any(|(_, rank)| rank.is_some()) && values.len() >= read
+    };
+    let (current_truncated, previous_truncated) =
+        (truncated(&current_values), truncated(&previous_values));
+
+    let names: BTreeSet<&String> = current_values
+        .iter()
+        .chain(previous_values.iter())
+        .filter(|(_, (_, rank))| rank.is_none_or(|rank| rank <= shown))
+        .map(|(name, _)| name)
+        .collect();
+    let mut fields: Vec<FieldChange> = names
+        .into_iter()
+        .map(|name| {
+            let now = current_values.get(name);
+            let before = previous_values.get(name);
+            let (current, rank) = now.copied().unwrap_or((0.0, None));
+            let (previous, previous_rank) = before.copied().unwrap_or((0.0, None));
+            let delta = current - previous;
+            let status = match (now, before) {
+                (Some(_), None) if previous_truncated => FieldStatus::BelowDepth,
+                (None, Some(_)) if current_truncated => FieldStatus::BelowDepth,
+                (Some(_), None) => FieldStatus::New,
+                (None, Some(_)) => FieldStatus::Disappeared,
+                _ => FieldStatus::Matched,
+            };
+
+            FieldChange {
+                field: name.clone(),
+                current,
+                previous,
+                delta,
+                percent: (previous != 0.0 && status != FieldStatus::BelowDepth)
+                    .then(|| delta / previous.abs() * 100.0),
+                status,
+                rank,
+                previous_rank,
+            }
+        })
+        .collect();
+
+    // leaderboards read best to worst, members that dropped out last
+    if template.metric_type == "top" {
+        fields.sort_by_key(|f| (f.rank.unwrap_or(usize::MAX), f.previous_rank));
+    }
+
+    MetricComparison {
+        metric_type: template.metric_type.clone(),
+        key: template.key.clone(),
+        add_key: template.add_key.clone(),
+        summary: matches!(template.result, MetricData::Summary { .. }),
+        fields,
+    }
+}
+
+/// field -> (value, rank) for a merged payload
+fn field_values(data: &MetricData) -> HashMap<String, (f64, Option<usize>)> {
+    match data {
+        MetricData::Count(c) => HashMap::from([("count".to_string(), (*c as f64, None))]),
+        MetricData::Hash(h) => h
+            .iter()
+            .map(|(k, v)| (k.clone(), (*v as f64, None)))
+            .collect(),
+        MetricData::FloatHash(h) => h.iter().map(|(k, v)| (k.clone(), (*v, None))).collect(),
+        MetricData::Summary { sum, count } => HashMap::from([
+            ("sum".to_string(), (*sum, None)),
+            ("count".to_string(), (*count as f64, None)),
+        ]),
+        MetricData::Ranked(pairs) => pairs
+            .iter()
+            .enumerate()
+            .map(|(i, (member, score))| (member.clone(), (*score, Some(i + 1))))
+            .collect(),
+    }
+}
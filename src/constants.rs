@@ -5,3 +5,21 @@ pub const MAX_COMBINATIONS: usize = 1000;
 pub const MAX_METRICS_PER_EVENT: usize = 1000;
 pub const INVALID_CHARS: [char; 2] = ['~', ':'];
 pub const DEFAULT_MAPPING_CONFIG_ADD: &[&str] = &["event"];
+pub const MAX_PATTERN_CARDINALITY: usize = 0;
+pub const MAX_PATTERN_EXPANSIONS: usize = 64;
+pub const OVERFLOW_FIELD: &str = "__other__";
+pub const TOP_DEPTH: usize = 250;
+/// how many times deeper than `top_depth` compare reads each leaderboard
+pub const COMPARE_TOP_DEPTH_FACTOR: usize = 2;
+pub const MINUTE_BUCKET_TTL: u64 = 6 * 3600;
+pub const BUCKET_RETENTION: u64 = 0;
+pub const COMPACT_HOURLY_AFTER: u64 = 0;
+pub const COMPACTION_INTERVAL: u64 = 3600;
+/// off unless configured: the cache is per process and can't see other writers
+pub const QUERY_CACHE_BYTES: usize = 0;
+pub const ANOMALY_WINDOW: usize = 14;
+pub const ANOMALY_THRESHOLD: f64 = 3.0;
+pub const ANOMALY_MIN_POINTS: usize = 7;
+/// share of a still-open bucket that must have passed before it is scored
+pub const ANOMALY_MIN_ELAPSED: f64 = 0.25;
+pub const ALERT_INTERVAL: u64 = 60;
@@ -8,13 +8,22 @@
 //! It provides a flexible configuration system for mapping event properties into different
 //! types of Redis commands, enabling complex queries without post-processing.
 
+pub mod alert;
+pub mod anomaly;
+pub mod cache;
+pub mod catalog;
 pub mod client;
+pub mod compare;
 pub mod constants;
+pub mod ql;
+pub mod query;
+pub mod range;
 pub mod server;
 pub mod storage;
+pub mod stream;
 pub mod utils;
 
-use chrono::{DateTime, Timelike, Utc};
+use chrono::{DateTime, Datelike, Timelike, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::{HashMap, HashSet};
@@ -26,20 +35,35 @@ pub mod prelude {
     #[cfg(feature = "redis-storage")]
     pub use crate::storage::redis::RedisStorage;
     pub use crate::{
+        anomaly::AnomalyConfig,
+        query::Query,
         storage::{memory::MemoryStorage, Storage, StorageError},
-        AddValueConfig, BucketType, CommandMetadata, DayResult, FindOptions, IncomingEvent,
-        LimitsConfig, MappingConfig, MetricData, MetricResult, ProcessedEvent, QueryResult,
-        StorageCommand, StorageCommandType, TimeConfig, Traqq, TraqqConfig,
+        AddValueConfig, BucketType, CommandMetadata, CompactionResult, DayResult, FindOptions,
+        IncomingEvent, LimitsConfig, MappingConfig, MetricData, MetricResult, MetricType,
+        ProcessedEvent, QueryFilter, QueryResult, Series, StorageCommand, StorageCommandType,
+        TimeConfig, Traqq, TraqqConfig,
     };
 }
 
 /// Represents the time bucket granularity for metric aggregation
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum BucketType {
     /// Daily aggregation bucket
+    #[default]
+    #[serde(rename = "day", alias = "d", alias = "daily")]
     Daily,
     /// Hourly aggregation bucket
+    #[serde(rename = "hour", alias = "h", alias = "hourly")]
     Hourly,
+    /// Minute aggregation bucket, expires after `TimeConfig::minute_ttl`
+    #[serde(rename = "minute", alias = "m", alias = "minutely")]
+    Minute,
+    /// ISO week (Monday-Sunday) rollup bucket
+    #[serde(rename = "week", alias = "w", alias = "weekly")]
+    Weekly,
+    /// Calendar month rollup bucket
+    #[serde(rename = "month", alias = "M", alias = "monthly")]
+    Monthly,
 }
 
 impl BucketType {
@@ -48,6 +72,108 @@ impl BucketType {
         match self {
             Self::Daily => "d",
             Self::Hourly => "h",
+            Self::Minute => "m",
+            Self::Weekly => "w",
+            Self::Monthly => "M",
+        }
+    }
+
+    /// Returns the human-readable granularity name
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Daily => "day",
+            Self::Hourly => "hour",
+            Self::Minute => "minute",
+            Self::Weekly => "week",
+            Self::Monthly => "month",
+        }
+    }
+
+    /// Returns the adjective used in `TimeConfig` option names (`store_<adjective>`)
+    pub const fn adjective(&self) -> &'static str {
+        match self {
+            Self::Daily => "daily",
+            Self::Hourly => "hourly",
+            Self::Minute => "minutely",
+            Self::Weekly => "weekly",
+            Self::Monthly => "monthly",
+        }
+    }
+
+    /// Returns the nominal bucket length in seconds
+    pub const fn seconds(&self) -> i64 {
+        match self {
+            Self::Daily => 86400,
+            Self::Hourly => 3600,
+            Self::Minute => 60,
+            Self::Weekly => 7 * 86400,
+            Self::Monthly => 31 * 86400,
+        }
+    }
+}
+
+impl std::str::FromStr for BucketType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" | "d" | "daily" => Ok(Self::Daily),
+            "hour" | "h" | "hourly" => Ok(Self::Hourly),
+            "minute" | "m" | "minutely" => Ok(Self::Minute),
+            "week" | "w" | "weekly" => Ok(Self::Weekly),
+            "month" | "M" | "monthly" => Ok(Self::Monthly),
+            other => Err(format!("unknown granularity: {}", other)),
+        }
+    }
+}
+
+/// The kind of metric a mapping produces, and the storage structure behind it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricType {
+    /// HyperLogLog unique counts
+    Bmp,
+    /// hash counters
+    #[default]
+    Add,
+    /// hash value accumulators, per field and as a sum/count summary
+    #[serde(alias = "addv")]
+    Adv,
+    /// sorted set leaderboards
+    Top,
+}
+
+impl MetricType {
+    /// Returns the string representation used in storage keys and `FindOptions`
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Bmp => "bmp",
+            Self::Add => "add",
+            Self::Adv => "adv",
+            Self::Top => "top",
+        }
+    }
+}
+
+impl std::fmt::Display for MetricType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for MetricType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bmp" => Ok(Self::Bmp),
+            "add" => Ok(Self::Add),
+            "adv" | "addv" => Ok(Self::Adv),
+            "top" => Ok(Self::Top),
+            other => Err(format!(
+                "unknown metric type: '{}' (expected bmp, add, adv or top)",
+                other
+            )),
         }
     }
 }
@@ -57,19 +183,84 @@ impl BucketType {
 pub struct TimeConfig {
     /// Whether to store hourly metrics in addition to daily
     pub store_hourly: bool,
+    /// Whether to store minute metrics in addition to daily
+    #[serde(default)]
+    pub store_minutely: bool,
+    /// Seconds before minute buckets expire, always positive
+    #[serde(default = "default_minute_ttl")]
+    pub minute_ttl: u64,
+    /// Seconds to keep hourly buckets (0 keeps them forever)
+    #[serde(default)]
+    pub hourly_retention: u64,
+    /// Seconds to keep daily buckets (0 keeps them forever)
+    #[serde(default)]
+    pub daily_retention: u64,
+    /// Seconds to keep weekly buckets (0 keeps them forever)
+    #[serde(default)]
+    pub weekly_retention: u64,
+    /// Seconds to keep monthly buckets (0 keeps them forever)
+    #[serde(default)]
+    pub monthly_retention: u64,
+    /// Seconds after which hourly buckets are compacted away (0 keeps them)
+    #[serde(default)]
+    pub compact_hourly_after: u64,
+    /// Whether to store ISO week rollups in addition to daily
+    #[serde(default)]
+    pub store_weekly: bool,
+    /// Whether to store calendar month rollups in addition to daily
+    #[serde(default)]
+    pub store_monthly: bool,
     /// Timezone for bucket calculations (e.g., "UTC", "America/New_York")
     pub timezone: String,
 }
 
+fn default_minute_ttl() -> u64 {
+    constants::MINUTE_BUCKET_TTL
+}
+
 impl Default for TimeConfig {
     fn default() -> Self {
         Self {
             store_hourly: false,
+            store_minutely: false,
+            minute_ttl: constants::MINUTE_BUCKET_TTL,
+            hourly_retention: constants::BUCKET_RETENTION,
+            daily_retention: constants::BUCKET_RETENTION,
+            weekly_retention: constants::BUCKET_RETENTION,
+            monthly_retention: constants::BUCKET_RETENTION,
+            compact_hourly_after: constants::COMPACT_HOURLY_AFTER,
+            store_weekly: false,
+            store_monthly: false,
             timezone: constants::TIMEZONE.into(),
         }
     }
 }
 
+impl TimeConfig {
+    /// Whether buckets of the given type are written at record time
+    pub fn stores(&self, bucket_type: BucketType) -> bool {
+        match bucket_type {
+            BucketType::Daily => true,
+            BucketType::Hourly => self.store_hourly,
+            BucketType::Minute => self.store_minutely,
+            BucketType::Weekly => self.store_weekly,
+            BucketType::Monthly => self.store_monthly,
+        }
+    }
+
+    /// Seconds keys of the given bucket type live for, if they expire
+    pub fn retention(&self, bucket_type: BucketType) -> Option<u64> {
+        let retention = match bucket_type {
+            BucketType::Minute => return Some(self.minute_ttl),
+            BucketType::Hourly => self.hourly_retention,
+            BucketType::Daily => self.daily_retention,
+            BucketType::Weekly => self.weekly_retention,
+            BucketType::Monthly => self.monthly_retention,
+        };
+        (retention > 0).then_some(retention)
+    }
+}
+
 /// Configuration for metric pattern mapping
 #[derive(Debug, Clone, Deserialize)]
 pub struct MappingConfig {
@@ -81,6 +272,12 @@ pub struct MappingConfig {
     pub add_value: Vec<AddValueConfig>,
     /// Patterns for sorted set (top-N / leaderboard) metrics
     pub top: Vec<String>,
+    /// Entries read per top pattern (globs allowed); others read `TOP_DEPTH`
+    #[serde(default)]
+    pub top_depth: HashMap<String, usize>,
+    /// Anomaly detection settings per metric (`type/pattern[/add_key]`, globs allowed)
+    #[serde(default)]
+    pub anomaly: HashMap<String, anomaly::AnomalyConfig>,
 }
 
 impl Default for MappingConfig {
@@ -90,6 +287,8 @@ impl Default for MappingConfig {
             add: vec!["event".into()],
             add_value: vec![],
             top: vec![],
+            top_depth: HashMap::new(),
+            anomaly: HashMap::new(),
         }
     }
 }
@@ -114,21 +313,59 @@ pub struct LimitsConfig {
     pub max_combinations: usize,
     /// Maximum metrics allowed per event
     pub max_metrics_per_event: usize,
+    /// Maximum distinct fields per add/adv/top key per bucket, 0 disables the cap.
+    /// once reached, new values are counted under `OVERFLOW_FIELD`; the check
+    /// and the write are one atomic storage call
+    #[serde(default)]
+    pub max_pattern_cardinality: usize,
+    /// Per-pattern caps (globs allowed, the most specific match wins), in
+    /// place of `max_pattern_cardinality`; 0 leaves a pattern uncapped
+    #[serde(default)]
+    pub pattern_cardinality: HashMap<String, usize>,
+    /// Maximum concrete patterns a wildcard mapping pattern expands to per
+    /// event; further combinations are dropped
+    #[serde(default = "default_max_pattern_expansions")]
+    pub max_pattern_expansions: usize,
+    /// memory budget in bytes for cached results of closed buckets, 0 (the
+    /// default) disables the cache. the cache is per process: only enable it
+    /// when a single process writes to the storage prefix, as other writers'
+    /// backfills don't invalidate its entries
+    #[serde(default)]
+    pub query_cache_bytes: usize,
+}
+
+fn default_max_pattern_expansions() -> usize {
+    constants::MAX_PATTERN_EXPANSIONS
 }
 
 impl Default for LimitsConfig {
     fn default() -> Self {
         Self {
-            max_field_length: constants::MAX_FIELD_LENGTH.into(),
-            max_value_length: constants::MAX_VALUE_LENGTH.into(),
-            max_combinations: constants::MAX_COMBINATIONS.into(),
-            max_metrics_per_event: constants::MAX_METRICS_PER_EVENT.into(),
+            max_field_length: constants::MAX_FIELD_LENGTH,
+            max_value_length: constants::MAX_VALUE_LENGTH,
+            max_combinations: constants::MAX_COMBINATIONS,
+            max_metrics_per_event: constants::MAX_METRICS_PER_EVENT,
+            max_pattern_cardinality: constants::MAX_PATTERN_CARDINALITY,
+            pattern_cardinality: HashMap::new(),
+            max_pattern_expansions: constants::MAX_PATTERN_EXPANSIONS,
+            query_cache_bytes: constants::QUERY_CACHE_BYTES,
         }
     }
 }
 
+impl LimitsConfig {
+    /// Distinct fields allowed per bucket for a concrete pattern, 0 for no cap
+    pub fn cardinality_cap(&self, pattern: &str) -> usize {
+        utils::most_specific(&self.pattern_cardinality, |declared| {
+            utils::pattern_match(declared, pattern)
+        })
+        .copied()
+        .unwrap_or(self.max_pattern_cardinality)
+    }
+}
+
 /// Primary configuration for the Traqq system
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct TraqqConfig {
     /// Time-based configuration settings
     pub time: TimeConfig,
@@ -136,16 +373,9 @@ pub struct TraqqConfig {
     pub mapping: MappingConfig,
     /// Processing limits and constraints
     pub limits: LimitsConfig,
-}
-
-impl Default for TraqqConfig {
-    fn default() -> Self {
-        Self {
-            time: TimeConfig::default(),
-            mapping: MappingConfig::default(),
-            limits: LimitsConfig::default(),
-        }
-    }
+    /// Threshold alert rules evaluated by the server
+    #[serde(default)]
+    pub alerts: alert::AlertsConfig,
 }
 
 /// Represents an incoming event before processing
@@ -228,15 +458,6 @@ pub struct CommandMetadata {
 }
 
 impl TraqqConfig {
-    /// Creates a new configuration with default values
-    pub fn default() -> Self {
-        Self {
-            time: TimeConfig::default(),
-            mapping: MappingConfig::default(),
-            limits: LimitsConfig::default(),
-        }
-    }
-
     /// Validates the configuration settings
     ///
     /// # Returns
@@ -246,11 +467,17 @@ impl TraqqConfig {
     /// # Validation Rules
     /// - Timezone must be valid
     /// - Patterns must be unique
-    /// - Mapping patterns must be valid
+    /// - Mapping patterns must be valid (wildcard segments included)
     pub fn validate(&self) -> Result<(), String> {
         // Validate timezone
         utils::parse_timezone(&self.time.timezone)?;
 
+        // minute buckets always expire; 0 would mean at once here but
+        // forever for the other retentions, so it's refused
+        if self.time.minute_ttl == 0 {
+            return Err("minute_ttl must be positive".to_string());
+        }
+
         // Track unique patterns
         let mut unique_patterns = HashSet::new();
 
@@ -259,6 +486,7 @@ impl TraqqConfig {
             if !unique_patterns.insert(pattern) {
                 return Err(format!("duplicate pattern: {}", pattern));
             }
+            utils::validate_mapping_pattern(pattern)?;
         }
 
         // Validate additive patterns
@@ -275,12 +503,48 @@ impl TraqqConfig {
                 return Err(format!("duplicate pattern: {}", config.key));
             }
             utils::validate_mapping_pattern(&config.key)?;
+            if utils::is_glob(&config.add_key) {
+                return Err(format!(
+                    "add_key cannot contain wildcards: {}",
+                    config.add_key
+                ));
+            }
         }
 
         // Validate top patterns
         for pattern in &self.mapping.top {
             utils::validate_mapping_pattern(pattern)?;
         }
+        for (pattern, depth) in &self.mapping.top_depth {
+            if !self
+                .mapping
+                .top
+                .iter()
+                .any(|top| utils::pattern_match(pattern, top))
+            {
+                return Err(format!("top_depth for unknown top pattern: {}", pattern));
+            }
+            if *depth == 0 {
+                return Err(format!("top_depth must be positive: {}", pattern));
+            }
+        }
+        for pattern in self.limits.pattern_cardinality.keys() {
+            utils::validate_mapping_pattern(pattern)?;
+        }
+        if self.limits.max_pattern_expansions == 0 {
+            return Err("max_pattern_expansions must be positive".to_string());
+        }
+        for (metric, config) in &self.mapping.anomaly {
+            anomaly::validate_entry(metric, config)?;
+        }
+
+        let mut rule_names = HashSet::new();
+        for rule in &self.alerts.rules {
+            if !rule_names.insert(&rule.name) {
+                return Err(format!("duplicate alert rule: {}", rule.name));
+            }
+            rule.validate(&self.time)?;
+        }
 
         Ok(())
     }
@@ -289,13 +553,73 @@ impl TraqqConfig {
         &self,
         timestamp: DateTime<Utc>,
     ) -> Result<Vec<(i64, BucketType)>, String> {
-        let tz = utils::parse_timezone(&self.time.timezone)?;
-        let local_time = timestamp.with_timezone(&tz);
         let mut buckets = Vec::new();
 
-        // daily bucket is enabled by default
-        let daily = local_time
+        // daily bucket is enabled by default, the rest by config
+        for bucket_type in [
+            BucketType::Daily,
+            BucketType::Hourly,
+            BucketType::Minute,
+            BucketType::Weekly,
+            BucketType::Monthly,
+        ] {
+            if self.time.stores(bucket_type) {
+                let start = self.bucket_start(timestamp.timestamp(), bucket_type)?;
+                buckets.push((start, bucket_type));
+            }
+        }
+
+        Ok(buckets)
+    }
+
+    /// Returns the start (unix seconds) of the bucket containing `timestamp`,
+    /// computed in the configured timezone
+    pub fn bucket_start(&self, timestamp: i64, bucket_type: BucketType) -> Result<i64, String> {
+        let tz = utils::parse_timezone(&self.time.timezone)?;
+        let local_time = DateTime::from_timestamp(timestamp, 0)
+            .ok_or_else(|| format!("invalid timestamp: {}", timestamp))?
+            .with_timezone(&tz);
+
+        let date = local_time.date_naive();
+        let (date, hour, minute) = match bucket_type {
+            BucketType::Daily => (date, 0, 0),
+            BucketType::Hourly => (date, local_time.hour(), 0),
+            BucketType::Minute => (date, local_time.hour(), local_time.minute()),
+            BucketType::Weekly => {
+                let monday = date - chrono::Days::new(date.weekday().num_days_from_monday().into());
+                (monday, 0, 0)
+            }
+            BucketType::Monthly => {
+                let first = date
+                    .with_day(1)
+                    .ok_or_else(|| "failed to create month timestamp".to_string())?;
+                (first, 0, 0)
+            }
+        };
+
+        let start = date
+            .and_hms_opt(hour, minute, 0)
+            .ok_or_else(|| format!("failed to create {} timestamp", bucket_type.name()))?
+            .and_local_timezone(tz)
+            .earliest()
+            .ok_or_else(|| format!("failed to convert {} timestamp to UTC", bucket_type.name()))?
+            .timestamp();
+
+        Ok(start)
+    }
+
+    /// Returns the start of the local calendar day `days` away from the day
+    /// containing `timestamp`, in the configured timezone
+    pub fn day_offset(&self, timestamp: i64, days: i64) -> Result<i64, String> {
+        let tz = utils::parse_timezone(&self.time.timezone)?;
+        let date = DateTime::from_timestamp(timestamp, 0)
+            .ok_or_else(|| format!("invalid timestamp: {}", timestamp))?
+            .with_timezone(&tz)
             .date_naive()
+            .checked_add_signed(chrono::Duration::days(days))
+            .ok_or_else(|| format!("date out of range: {} days from {}", days, timestamp))?;
+
+        let start = date
             .and_hms_opt(0, 0, 0)
             .ok_or_else(|| "failed to create daily timestamp".to_string())?
             .and_local_timezone(tz)
@@ -303,23 +627,42 @@ impl TraqqConfig {
             .ok_or_else(|| "failed to convert daily timestamp to UTC".to_string())?
             .timestamp();
 
-        buckets.push((daily, BucketType::Daily));
-
-        // hourly bucket is enabled by config
-        if self.time.store_hourly {
-            let hourly = local_time
-                .date_naive()
-                .and_hms_opt(local_time.hour(), 0, 0)
-                .ok_or_else(|| "failed to create hourly timestamp".to_string())?
-                .and_local_timezone(tz)
-                .earliest()
-                .ok_or_else(|| "failed to convert hourly timestamp to UTC".to_string())?
-                .timestamp();
+        Ok(start)
+    }
 
-            buckets.push((hourly, BucketType::Hourly));
+    /// Returns the start of the bucket after the one starting at `start`.
+    /// steps an hour at a time past DST shifts so each bucket is visited once
+    pub fn next_bucket_start(&self, start: i64, bucket_type: BucketType) -> Result<i64, String> {
+        let mut next = start + bucket_type.seconds();
+        loop {
+            let bucket = self.bucket_start(next, bucket_type)?;
+            if bucket > start {
+                return Ok(bucket);
+            }
+            next += 3600;
         }
+    }
 
-        Ok(buckets)
+    /// Formats a bucket start as an ISO string in the configured timezone:
+    /// `YYYY-MM-DD` for days, `YYYY-Www` for weeks, `YYYY-MM` for months,
+    /// RFC 3339 for finer buckets
+    pub fn bucket_label(&self, timestamp: i64, bucket_type: BucketType) -> String {
+        let Ok(tz) = utils::parse_timezone(&self.time.timezone) else {
+            return String::new();
+        };
+        let Some(dt) = DateTime::from_timestamp(timestamp, 0) else {
+            return String::new();
+        };
+        let local = dt.with_timezone(&tz);
+
+        match bucket_type {
+            BucketType::Daily => local.format("%Y-%m-%d").to_string(),
+            BucketType::Weekly => local.format("%G-W%V").to_string(),
+            BucketType::Monthly => local.format("%Y-%m").to_string(),
+            BucketType::Hourly | BucketType::Minute => {
+                local.format("%Y-%m-%dT%H:%M:%S%:z").to_string()
+            }
+        }
     }
 }
 
@@ -364,6 +707,25 @@ impl IncomingEvent {
 
         // Sanitize properties
         if let serde_json::Value::Object(props) = &self.properties {
+            // expand wildcard segments against the property names present
+            let globs: Vec<String> = required_keys
+                .iter()
+                .filter(|k| utils::is_glob(k))
+                .cloned()
+                .collect();
+            // names that can't be part of a storage key are never expanded
+            let max_length = config.limits.max_field_length;
+            for glob in &globs {
+                required_keys.remove(glob);
+                required_keys.extend(
+                    props
+                        .keys()
+                        .filter(|k| utils::is_expandable_name(k, max_length))
+                        .filter(|k| utils::glob_match(glob, k))
+                        .cloned(),
+                );
+            }
+
             let mut sanitized_props = serde_json::Map::new();
 
             for (key, value) in props {
@@ -402,12 +764,21 @@ impl IncomingEvent {
 }
 
 impl ProcessedEvent {
-    pub fn from_incoming(mut event: IncomingEvent, config: &TraqqConfig) -> Result<Self, String> {
+    pub fn from_incoming(event: IncomingEvent, config: &TraqqConfig) -> Result<Self, String> {
+        Self::from_incoming_at(event, config, Utc::now())
+    }
+
+    /// process an event as if it happened at `timestamp`, e.g. for backfills
+    pub fn from_incoming_at(
+        mut event: IncomingEvent,
+        config: &TraqqConfig,
+        timestamp: DateTime<Utc>,
+    ) -> Result<Self, String> {
         event.validate_and_sanitize(config)?;
 
         let mut processed = ProcessedEvent {
             event_name: event.event.clone(),
-            timestamp: Utc::now(),
+            timestamp,
             raw_properties: HashMap::new(),
             combined_properties: HashMap::new(),
             numeric_values: HashMap::new(),
@@ -498,13 +869,70 @@ impl ProcessedEvent {
         Ok(processed)
     }
 
+    /// expand wildcard segments of a mapping pattern against this event's
+    /// base properties. returns one key list per concrete pattern, at most
+    /// `max_pattern_expansions` in name order; patterns without wildcards
+    /// yield themselves.
+    fn expand_pattern(&self, pattern: &str, limits: &LimitsConfig) -> Vec<Vec<String>> {
+        let segments: Vec<&str> = pattern.split('~').collect();
+        let fixed: HashSet<&str> = segments
+            .iter()
+            .copied()
+            .filter(|s| !utils::is_glob(s))
+            .collect();
+
+        let mut expanded: Vec<Vec<String>> = vec![Vec::new()];
+
+        for segment in &segments {
+            let candidates: Vec<String> = if utils::is_glob(segment) {
+                let mut matched: Vec<String> = self
+                    .raw_properties
+                    .keys()
+                    .filter(|k| utils::is_expandable_name(k, limits.max_field_length))
+                    .filter(|k| !fixed.contains(k.as_str()))
+                    .filter(|k| utils::glob_match(segment, k))
+                    .cloned()
+                    .collect();
+                matched.sort();
+                matched
+            } else {
+                vec![segment.to_string()]
+            };
+
+            let mut next = Vec::new();
+            for keys in &expanded {
+                for candidate in &candidates {
+                    if keys.contains(candidate) {
+                        continue;
+                    }
+                    let mut keys = keys.clone();
+                    keys.push(candidate.clone());
+                    next.push(keys);
+                }
+            }
+            // a product of several wildcard segments grows fast
+            next.truncate(limits.max_pattern_expansions);
+            expanded = next;
+        }
+
+        expanded
+    }
+
     // create a list of redis commands to execute, in the future
     // this could be extended to other adapters or a custom persistence layer
     fn process_metrics(&mut self, config: &TraqqConfig) -> Result<(), String> {
         let buckets = config.get_time_buckets(self.timestamp)?;
 
         // bitmap (bmp:) using HyperLogLog
-        for bitmap_key in &config.mapping.bitmap {
+        let bitmap_keys: Vec<String> = config
+            .mapping
+            .bitmap
+            .iter()
+            .flat_map(|p| self.expand_pattern(p, &config.limits))
+            .map(|keys| keys.join("~"))
+            .collect();
+
+        for bitmap_key in &utils::dedup(bitmap_keys) {
             if let Some(value) = self.raw_properties.get(bitmap_key) {
                 if !value.is_empty() {
                     self.bitmap_metrics.push(value.clone());
@@ -527,9 +955,15 @@ impl ProcessedEvent {
         }
 
         // add (add:)
-        for add_pattern in &config.mapping.add {
-            let keys: Vec<String> = add_pattern.split('~').map(String::from).collect();
-            let sorted_keys = utils::sort_keys(&keys);
+        let add_patterns: Vec<Vec<String>> = config
+            .mapping
+            .add
+            .iter()
+            .flat_map(|p| self.expand_pattern(p, &config.limits))
+            .map(|keys| utils::sort_keys(&keys))
+            .collect();
+
+        for sorted_keys in utils::dedup(add_patterns) {
             let mut values = Vec::new();
             let mut has_all_keys = true;
 
@@ -569,9 +1003,22 @@ impl ProcessedEvent {
         }
 
         // add_value (adv:)
-        for add_value_config in &config.mapping.add_value {
-            let keys: Vec<String> = add_value_config.key.split('~').map(String::from).collect();
-            let sorted_keys = utils::sort_keys(&keys);
+        let add_value_patterns: Vec<(&AddValueConfig, Vec<String>)> = config
+            .mapping
+            .add_value
+            .iter()
+            .flat_map(|c| {
+                self.expand_pattern(&c.key, &config.limits)
+                    .into_iter()
+                    .map(move |keys| (c, utils::sort_keys(&keys)))
+            })
+            .collect();
+        let mut seen_add_value: HashSet<(&str, Vec<String>)> = HashSet::new();
+
+        for (add_value_config, sorted_keys) in add_value_patterns {
+            if !seen_add_value.insert((&add_value_config.add_key, sorted_keys.clone())) {
+                continue;
+            }
             let mut values = Vec::new();
             let mut has_all_keys = true;
 
@@ -649,9 +1096,15 @@ impl ProcessedEvent {
         }
 
         // top (top:) using sorted sets
-        for top_pattern in &config.mapping.top {
-            let keys: Vec<String> = top_pattern.split('~').map(String::from).collect();
-            let sorted_keys = utils::sort_keys(&keys);
+        let top_patterns: Vec<Vec<String>> = config
+            .mapping
+            .top
+            .iter()
+            .flat_map(|p| self.expand_pattern(p, &config.limits))
+            .map(|keys| utils::sort_keys(&keys))
+            .collect();
+
+        for sorted_keys in utils::dedup(top_patterns) {
             let mut values = Vec::new();
             let mut has_all_keys = true;
 
@@ -746,6 +1199,171 @@ pub struct MetricResult {
     pub result: MetricData,
 }
 
+impl MetricResult {
+    /// true if values were redirected to the overflow field
+    /// because the pattern hit its cardinality cap
+    pub fn is_capped(&self) -> bool {
+        match &self.result {
+            MetricData::Hash(h) => h.contains_key(constants::OVERFLOW_FIELD),
+            MetricData::FloatHash(h) => h.contains_key(constants::OVERFLOW_FIELD),
+            MetricData::Ranked(pairs) => pairs.iter().any(|(m, _)| m == constants::OVERFLOW_FIELD),
+            _ => false,
+        }
+    }
+
+    /// pattern segments sorted and joined, so `geo~event` and `event~geo` match
+    fn pattern_id(&self) -> String {
+        let mut key = self.key.clone();
+        key.sort();
+        key.join("~")
+    }
+
+    /// name each value of a compound field after its key segment:
+    /// `purchase~US` under `event~geo` is [("event", "purchase"), ("geo", "US")].
+    /// the overflow field stands in for every dimension
+    pub fn dimensions<'a>(&'a self, field: &'a str) -> Vec<(&'a str, &'a str)> {
+        let mut values: Vec<&str> = field.split('~').collect();
+        if values.len() != self.key.len() {
+            values = vec![field; self.key.len()];
+        }
+        self.key.iter().map(String::as_str).zip(values).collect()
+    }
+
+    /// keep the fields whose `dimension` matches `value`, which may be a glob
+    pub fn filter_dimension(&self, dimension: &str, value: &str) -> Result<MetricResult, String> {
+        let index = self.dimension_index(dimension)?;
+        Ok(self.rekey_fields(|field| {
+            let (_, segment) = self.dimensions(field)[index];
+            utils::glob_match(value, segment).then(|| field.to_string())
+        }))
+    }
+
+    /// sum the fields down to a subset of the key's dimensions, in the order
+    /// given: `event~geo` grouped by `["geo"]` totals each geo over all events.
+    /// an empty subset leaves one total under the field ""
+    pub fn group_by(&self, dimensions: &[&str]) -> Result<MetricResult, String> {
+        let indexes = dimensions
+            .iter()
+            .map(|d| self.dimension_index(d))
+            .collect::<Result<Vec<usize>, String>>()?;
+
+        let mut grouped = self.rekey_fields(|field| {
+            let named = self.dimensions(field);
+            let values: Vec<&str> = indexes.iter().map(|&i| named[i].1).collect();
+            Some(values.join("~"))
+        });
+        grouped.key = dimensions.iter().map(|d| d.to_string()).collect();
+        Ok(grouped)
+    }
+
+    /// keep `limit` fields after skipping `offset`, ranking hash fields by
+    /// value (highest first, ties by name) as leaderboards already are.
+    /// counts and summaries are returned unchanged
+    pub fn page(&self, offset: usize, limit: usize) -> MetricResult {
+        fn window<V: Copy + PartialOrd>(
+            fields: impl Iterator<Item = (String, V)>,
+            offset: usize,
+            limit: usize,
+        ) -> Vec<(String, V)> {
+            let mut ranked: Vec<(String, V)> = fields.collect();
+            ranked.sort_by(|a, b| {
+                b.1.partial_cmp(&a.1)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+                    .then_with(|| a.0.cmp(&b.0))
+            });
+            ranked.into_iter().skip(offset).take(limit).collect()
+        }
+
+        let result = match &self.result {
+            MetricData::Hash(h) => MetricData::Hash(
+                window(h.iter().map(|(k, v)| (k.clone(), *v)), offset, limit)
+                    .into_iter()
+                    .collect(),
+            ),
+            MetricData::FloatHash(h) => MetricData::FloatHash(
+                window(h.iter().map(|(k, v)| (k.clone(), *v)), offset, limit)
+                    .into_iter()
+                    .collect(),
+            ),
+            MetricData::Ranked(pairs) => {
+                MetricData::Ranked(pairs.iter().skip(offset).take(limit).cloned().collect())
+            }
+            other => other.clone(),
+        };
+
+        MetricResult {
+            result,
+            ..self.clone()
+        }
+    }
+
+    fn dimension_index(&self, dimension: &str) -> Result<usize, String> {
+        if matches!(
+            self.result,
+            MetricData::Count(_) | MetricData::Summary { .. }
+        ) {
+            return Err(format!(
+                "{} result for {} has no per-field values to drill into",
+                self.metric_type,
+                self.key.join("~")
+            ));
+        }
+        self.key.iter().position(|k| k == dimension).ok_or_else(|| {
+            format!(
+                "unknown dimension '{}': {}/{} has {}",
+                dimension,
+                self.metric_type,
+                self.key.join("~"),
+                self.key.join(", ")
+            )
+        })
+    }
+
+    /// a copy with each field renamed by `rename` (None drops it), summing
+    /// fields that end up with the same name
+    fn rekey_fields(&self, rename: impl Fn(&str) -> Option<String>) -> MetricResult {
+        fn sum_by<'a, V: Copy + Default + std::ops::AddAssign>(
+            fields: impl Iterator<Item = (&'a String, V)>,
+            rename: &impl Fn(&str) -> Option<String>,
+        ) -> HashMap<String, V> {
+            let mut out: HashMap<String, V> = HashMap::new();
+            for (field, value) in fields {
+                if let Some(name) = rename(field) {
+                    *out.entry(name).or_default() += value;
+                }
+            }
+            out
+        }
+
+        let result = match &self.result {
+            MetricData::Hash(h) => {
+                MetricData::Hash(sum_by(h.iter().map(|(k, v)| (k, *v)), &rename))
+            }
+            MetricData::FloatHash(h) => {
+                MetricData::FloatHash(sum_by(h.iter().map(|(k, v)| (k, *v)), &rename))
+            }
+            MetricData::Ranked(pairs) => {
+                let mut ranked: Vec<(String, f64)> =
+                    sum_by(pairs.iter().map(|(k, v)| (k, *v)), &rename)
+                        .into_iter()
+                        .collect();
+                ranked.sort_by(|a, b| {
+                    b.1.partial_cmp(&a.1)
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                        .then_with(|| a.0.cmp(&b.0))
+                });
+                MetricData::Ranked(ranked)
+            }
+            other => other.clone(),
+        };
+
+        MetricResult {
+            result,
+            ..self.clone()
+        }
+    }
+}
+
 /// the data payload of a metric result, varies by type
 #[derive(Debug, Clone, Serialize)]
 pub enum MetricData {
@@ -761,95 +1379,352 @@ pub enum MetricData {
     Ranked(Vec<(String, f64)>),
 }
 
-/// results for a single day
+impl MetricData {
+    /// a single number out of the payload: the count for bmp, a field's
+    /// value for hashes, a member's score for top, and "sum", "count" or
+    /// "avg" for summaries. missing fields are 0.
+    pub fn value(&self, field: &str) -> f64 {
+        match self {
+            MetricData::Count(c) => *c as f64,
+            MetricData::Hash(h) => h.get(field).copied().unwrap_or(0) as f64,
+            MetricData::FloatHash(h) => h.get(field).copied().unwrap_or(0.0),
+            MetricData::Summary { sum, count } => match field {
+                "sum" => *sum,
+                "count" => *count as f64,
+                "avg" if *count > 0 => sum / *count as f64,
+                _ => 0.0,
+            },
+            MetricData::Ranked(pairs) => pairs
+                .iter()
+                .find(|(member, _)| member == field)
+                .map_or(0.0, |(_, score)| *score),
+        }
+    }
+}
+
+/// results for a single time bucket (a day unless queried at another granularity)
 #[derive(Debug, Clone, Serialize)]
 pub struct DayResult {
-    /// ISO date string (YYYY-MM-DD)
+    /// ISO label in the configured timezone: YYYY-MM-DD for days,
+    /// YYYY-Www for weeks, YYYY-MM for months, RFC 3339 for hours/minutes
     pub date: String,
-    /// unix timestamp of the day bucket
+    /// unix timestamp of the bucket start
     pub timestamp: i64,
     /// all metric results for this day
     pub results: Vec<MetricResult>,
 }
 
+impl DayResult {
+    /// a notice for each result that hit its cardinality cap
+    pub fn warnings(&self) -> Vec<String> {
+        self.results
+            .iter()
+            .filter(|r| r.is_capped())
+            .map(|r| format!("cardinality cap reached: {} ({})", r.location, self.date))
+            .collect()
+    }
+}
+
 /// options for finding specific metrics in query results
+#[derive(Debug, Clone, Default)]
 pub struct FindOptions {
-    /// metric type: "bmp", "add", "adv", "top"
-    pub metric_type: String,
+    /// metric type to find
+    pub metric_type: MetricType,
     /// pattern key to match (e.g., "event" or "event~geo")
     pub key: String,
     /// for addv: filter by add_key
     pub add_key: Option<String>,
     /// merge results across all days into a single result
     pub merge: bool,
+    /// for top: keep only the first N entries, also after merging
+    pub top: Option<usize>,
+}
+
+impl FindOptions {
+    /// parse the `find_str` shorthand: "type/key" or "type/key/add_key".
+    /// an unknown type is an error, as with the typed `metric_type`
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = query.split('/').collect();
+        if parts.len() < 2 || parts.len() > 3 || parts.iter().any(|p| p.is_empty()) {
+            return Err(format!("invalid metric: {} (expected type/key)", query));
+        }
+        Ok(Self {
+            metric_type: parts[0].parse()?,
+            key: parts[1].to_string(),
+            add_key: parts.get(2).map(|s| s.to_string()),
+            ..Self::default()
+        })
+    }
+}
+
+/// one field of a metric over time, a point per bucket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Series {
+    /// the field read from each bucket, as `MetricData::value` takes it
+    pub field: String,
+    /// (bucket start, value) in bucket order
+    pub points: Vec<(i64, f64)>,
+}
+
+/// limits which tracked keys a query reads, before any values are fetched.
+///
+/// rules use the `find_str` shape, `type/pattern` or `adv/pattern/add_key`,
+/// where each part may be a glob (e.g. `add/event~*`, `*/geo`). a rule with
+/// no `/` matches the pattern of any type. compound patterns match in any
+/// segment order.
+#[derive(Debug, Clone, Default)]
+pub struct QueryFilter {
+    /// keys must match one of these rules; empty accepts everything
+    pub accept: Vec<String>,
+    /// keys matching any of these rules are skipped
+    pub ignore: Vec<String>,
+    /// entries to read from each top leaderboard, overriding `top_depth`
+    pub top: Option<usize>,
+    /// skip the range-wide bmp and top unions that merged finds use, for
+    /// reads that only look at buckets
+    pub skip_merges: bool,
+}
+
+impl QueryFilter {
+    /// accept only the keys a `find` with these options would return
+    pub fn from_find(opts: &FindOptions) -> Self {
+        let mut rule = format!("{}/{}", opts.metric_type, opts.key);
+        if opts.metric_type == MetricType::Adv {
+            if let Some(add_key) = &opts.add_key {
+                rule = format!("{}/{}", rule, add_key);
+            }
+        }
+        Self {
+            accept: vec![rule],
+            ignore: Vec::new(),
+            top: opts.top,
+            skip_merges: !opts.merge,
+        }
+    }
+
+    /// true if a key identified by "type:rest" should be read
+    pub fn allows(&self, identity: &str) -> bool {
+        let Some((metric_type, rest)) = identity.split_once(':') else {
+            return false;
+        };
+        // adv keys carry "add_key:pattern[:i]"
+        let (add_key, pattern) = match metric_type {
+            "adv" => match rest.trim_end_matches(":i").split_once(':') {
+                Some((add_key, pattern)) => (Some(add_key), pattern),
+                None => return false,
+            },
+            _ => (None, rest),
+        };
+
+        let matches = |rule: &String| Self::rule_matches(rule, metric_type, pattern, add_key);
+        (self.accept.is_empty() || self.accept.iter().any(matches))
+            && !self.ignore.iter().any(matches)
+    }
+
+    fn rule_matches(rule: &str, metric_type: &str, pattern: &str, add_key: Option<&str>) -> bool {
+        let parts: Vec<&str> = rule.split('/').collect();
+        let (type_glob, pattern_glob, add_key_glob) = match parts.as_slice() {
+            [pattern_glob] => ("*", *pattern_glob, None),
+            [type_glob, pattern_glob] => (*type_glob, *pattern_glob, None),
+            [type_glob, pattern_glob, add_key_glob] => {
+                (*type_glob, *pattern_glob, Some(*add_key_glob))
+            }
+            _ => return false,
+        };
+
+        let pattern_matches = utils::pattern_match(pattern_glob, pattern);
+        let add_key_matches = match (add_key_glob, add_key) {
+            (None, _) => true,
+            (Some(glob), Some(add_key)) => utils::glob_match(glob, add_key),
+            (Some(_), None) => false,
+        };
+
+        utils::glob_match(type_glob, metric_type) && pattern_matches && add_key_matches
+    }
 }
 
 /// the complete result of a query, organized by day
 pub struct QueryResult {
     pub days: Vec<DayResult>,
+    /// notices about the data, e.g. patterns that hit their cardinality cap
+    pub warnings: Vec<String>,
+    /// distinct count across the whole range per bmp pattern, from merged
+    /// hyperloglogs. used by merged finds instead of summing daily counts.
+    /// empty when the query's filter set `skip_merges`.
+    pub unique_counts: HashMap<String, u64>,
+    /// top entries across the whole range per top pattern, from sorted sets
+    /// unioned in storage. used by merged finds instead of summing each
+    /// bucket's truncated list.
+    pub merged_tops: HashMap<String, Vec<(String, f64)>>,
 }
 
 impl QueryResult {
+    /// build a result from day results, collecting warnings
+    pub fn new(days: Vec<DayResult>) -> Self {
+        let warnings = days.iter().flat_map(DayResult::warnings).collect();
+
+        Self {
+            days,
+            warnings,
+            unique_counts: HashMap::new(),
+            merged_tops: HashMap::new(),
+        }
+    }
+
     /// find metrics matching the given options.
     /// returns per-day results, or a merged single result.
     pub fn find(&self, opts: FindOptions) -> Vec<MetricResult> {
-        let target_key: Vec<String> = {
-            let mut k: Vec<String> = opts.key.split('~').map(String::from).collect();
-            k.sort();
-            k
-        };
-
-        let mut matches: Vec<MetricResult> = Vec::new();
-
-        for day in &self.days {
-            for result in &day.results {
-                if result.metric_type != opts.metric_type {
-                    continue;
-                }
-
-                let mut result_key = result.key.clone();
-                result_key.sort();
+        let mut matches: Vec<MetricResult> = self
+            .days
+            .iter()
+            .flat_map(|day| &day.results)
+            .filter(|result| Self::matches(result, &opts))
+            .cloned()
+            .collect();
 
-                if result_key != target_key {
-                    continue;
-                }
+        if opts.merge && !matches.is_empty() {
+            let merged = Self::merge_results(&matches, opts.metric_type.as_str());
+            matches = vec![self.with_range_totals(merged)];
+        }
 
-                if opts.metric_type == "adv" {
-                    if let Some(ref want_add_key) = opts.add_key {
-                        if result.add_key.as_ref() != Some(want_add_key) {
-                            continue;
-                        }
-                    }
+        if let Some(limit) = opts.top {
+            for result in &mut matches {
+                if let MetricData::Ranked(pairs) = &mut result.result {
+                    pairs.truncate(limit);
                 }
-
-                matches.push(result.clone());
             }
         }
 
-        if opts.merge && !matches.is_empty() {
-            vec![Self::merge_results(&matches, &opts.metric_type)]
-        } else {
-            matches
-        }
+        matches
     }
 
-    /// shorthand find: "type/key" or "type/key/add_key"
+    /// shorthand find: "type/key" or "type/key/add_key". empty when `query`
+    /// is malformed or names an unknown type; see `try_find_str`
     pub fn find_str(&self, query: &str) -> Vec<MetricResult> {
-        let parts: Vec<&str> = query.split('/').collect();
-        if parts.len() < 2 {
-            return Vec::new();
-        }
-        let opts = FindOptions {
-            metric_type: parts[0].to_string(),
-            key: parts[1].to_string(),
-            add_key: parts.get(2).map(|s| s.to_string()),
-            merge: false,
-        };
-        self.find(opts)
+        self.try_find_str(query).unwrap_or_default()
     }
 
-    fn merge_results(results: &[MetricResult], metric_type: &str) -> MetricResult {
-        let first = &results[0];
+    /// like `find_str`, but a malformed query or unknown type is an error
+    pub fn try_find_str(&self, query: &str) -> Result<Vec<MetricResult>, String> {
+        Ok(self.find(FindOptions::parse(query)?))
+    }
+
+    /// one point per bucket for `field` of the metric named by `query`
+    /// ("type/key" or "type/key/add_key"), zero where a bucket has no data.
+    /// see `MetricData::value` for what `field` means per type.
+    pub fn series(&self, query: &str, field: &str) -> Result<Vec<(i64, f64)>, String> {
+        let opts = FindOptions::parse(query)?;
+
+        Ok(self
+            .days
+            .iter()
+            .map(|day| {
+                let value = day
+                    .results
+                    .iter()
+                    .filter(|result| Self::matches(result, &opts))
+                    .map(|result| result.result.value(field))
+                    .sum();
+                (day.timestamp, value)
+            })
+            .collect())
+    }
+
+    /// a series for each of several fields of the same metric
+    pub fn series_many(&self, query: &str, fields: &[String]) -> Result<Vec<Series>, String> {
+        fields
+            .iter()
+            .map(|field| {
+                Ok(Series {
+                    field: field.clone(),
+                    points: self.series(query, field)?,
+                })
+            })
+            .collect()
+    }
+
+    /// every metric merged across all buckets, one result each. bmp and top
+    /// use the range-wide `unique_counts` and `merged_tops`.
+    pub fn merged(&self) -> Vec<MetricResult> {
+        // metric -> its per-bucket results. adv summaries and per-label
+        // hashes share a pattern, so the payload kind is part of the identity
+        let mut grouped: HashMap<String, Vec<MetricResult>> = HashMap::new();
+        for result in self.days.iter().flat_map(|day| &day.results) {
+            let kind = match result.result {
+                MetricData::Summary { .. } => "summary",
+                _ => "",
+            };
+            let identity = format!(
+                "{}/{}/{}/{}",
+                result.metric_type,
+                result.pattern_id(),
+                result.add_key.as_deref().unwrap_or(""),
+                kind
+            );
+            grouped.entry(identity).or_default().push(result.clone());
+        }
+
+        let mut merged: Vec<MetricResult> = grouped
+            .into_values()
+            .map(|group| self.with_range_totals(Self::merge_results(&group, &group[0].metric_type)))
+            .collect();
+
+        merged.sort_by_key(|r| {
+            (
+                r.metric_type.clone(),
+                r.pattern_id(),
+                r.add_key.clone(),
+                matches!(r.result, MetricData::Summary { .. }),
+            )
+        });
+        merged
+    }
+
+    /// swap a summed bmp or top merge for the exact range-wide one, if known
+    fn with_range_totals(&self, mut merged: MetricResult) -> MetricResult {
+        let pattern = merged.pattern_id();
+        match merged.metric_type.as_str() {
+            "bmp" => {
+                if let Some(count) = self.unique_counts.get(&pattern) {
+                    merged.result = MetricData::Count(*count);
+                }
+            }
+            "top" => {
+                if let Some(pairs) = self.merged_tops.get(&pattern) {
+                    merged.result = MetricData::Ranked(pairs.clone());
+                }
+            }
+            _ => {}
+        }
+        merged
+    }
+
+    /// true if a result is the metric `opts` asks for.
+    /// compound keys match in any order.
+    fn matches(result: &MetricResult, opts: &FindOptions) -> bool {
+        if result.metric_type != opts.metric_type.as_str() {
+            return false;
+        }
+
+        let mut target_key: Vec<&str> = opts.key.split('~').collect();
+        target_key.sort();
+        let mut result_key: Vec<&str> = result.key.iter().map(String::as_str).collect();
+        result_key.sort();
+        if result_key != target_key {
+            return false;
+        }
+
+        match (&opts.add_key, opts.metric_type) {
+            (Some(want_add_key), MetricType::Adv) => result.add_key.as_ref() == Some(want_add_key),
+            _ => true,
+        }
+    }
+
+    /// merge results for one metric. bmp counts and truncated top lists are
+    /// summed here, which is approximate; `find` replaces them with
+    /// `unique_counts` and `merged_tops`
+    fn merge_results(results: &[MetricResult], metric_type: &str) -> MetricResult {
+        let first = &results[0];
         let merged_data = match metric_type {
             "bmp" => {
                 let total: u64 = results
@@ -928,6 +1803,17 @@ impl QueryResult {
     }
 }
 
+/// outcome of a compaction run
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CompactionResult {
+    /// hourly buckets removed
+    pub buckets: usize,
+    /// storage keys deleted, including key-tracking sets
+    pub keys: usize,
+    /// days left in place because a rollup did not cover their hours
+    pub warnings: Vec<String>,
+}
+
 /// the primary interface for recording and querying metrics.
 /// owns a config and storage backend.
 pub struct Traqq {
@@ -935,6 +1821,8 @@ pub struct Traqq {
     storage: Box<dyn storage::Storage>,
     /// key prefix for all storage keys
     prefix: String,
+    /// results of closed buckets, see `cache`
+    cache: cache::ResultCache,
 }
 
 impl Traqq {
@@ -945,6 +1833,7 @@ impl Traqq {
     ) -> Result<Self, String> {
         config.validate()?;
         Ok(Self {
+            cache: cache::ResultCache::new(config.limits.query_cache_bytes),
             config,
             storage,
             prefix: prefix.to_string(),
@@ -954,127 +1843,1106 @@ impl Traqq {
     /// record an event: validate, process, and persist to storage.
     /// returns the processed event for inspection if needed.
     pub fn record(&self, event: IncomingEvent) -> Result<ProcessedEvent, String> {
-        let processed = ProcessedEvent::from_incoming(event, &self.config)?;
+        self.record_at(event, Utc::now())
+    }
+
+    /// record an event into the buckets for `timestamp` instead of now
+    pub fn record_at(
+        &self,
+        event: IncomingEvent,
+        timestamp: DateTime<Utc>,
+    ) -> Result<ProcessedEvent, String> {
+        let processed = ProcessedEvent::from_incoming_at(event, &self.config, timestamp)?;
         self.execute_commands(&processed)?;
         Ok(processed)
     }
 
     /// execute all storage commands from a processed event and track keys
     fn execute_commands(&self, processed: &ProcessedEvent) -> Result<(), String> {
-        let mut tracked_keys: Vec<String> = Vec::new();
+        // keys grouped by the "bucket_type:timestamp" they were written to
+        let mut tracked_keys: HashMap<String, Vec<String>> = HashMap::new();
 
         for cmd in &processed.commands {
             let prefixed_key = format!("{}:{}", self.prefix, cmd.key);
+            let field = cmd.value.as_str();
+            let overflow = constants::OVERFLOW_FIELD;
 
-            match &cmd.command_type {
-                StorageCommandType::HyperLogLog => {
-                    self.storage
-                        .hyperloglog_add(&prefixed_key, &cmd.value)
-                        .map_err(|e| e.to_string())?;
-                }
-                StorageCommandType::HashIncrement => {
-                    self.storage
-                        .hash_increment(&prefixed_key, &cmd.value, 1)
-                        .map_err(|e| e.to_string())?;
-                }
-                StorageCommandType::HashIncrementFloat(amount) => {
-                    self.storage
-                        .hash_increment_float(&prefixed_key, &cmd.value, *amount)
-                        .map_err(|e| e.to_string())?;
-                }
-                StorageCommandType::SortedSetIncrement(amount) => {
-                    self.storage
-                        .sorted_set_increment(&prefixed_key, &cmd.value, *amount)
-                        .map_err(|e| e.to_string())?;
+            match (&cmd.command_type, self.cardinality_cap(&prefixed_key, cmd)) {
+                (StorageCommandType::HyperLogLog, _) => {
+                    self.storage.hyperloglog_add(&prefixed_key, field).map(drop)
                 }
+                (StorageCommandType::HashIncrement, None) => self
+                    .storage
+                    .hash_increment(&prefixed_key, field, 1)
+                    .map(drop),
+                (StorageCommandType::HashIncrement, Some(cap)) => self
+                    .storage
+                    .hash_increment_capped(&prefixed_key, field, 1, cap, overflow)
+                    .map(drop),
+                (StorageCommandType::HashIncrementFloat(amount), None) => self
+                    .storage
+                    .hash_increment_float(&prefixed_key, field, *amount)
+                    .map(drop),
+                (StorageCommandType::HashIncrementFloat(amount), Some(cap)) => self
+                    .storage
+                    .hash_increment_float_capped(&prefixed_key, field, *amount, cap, overflow)
+                    .map(drop),
+                (StorageCommandType::SortedSetIncrement(amount), None) => self
+                    .storage
+                    .sorted_set_increment(&prefixed_key, field, *amount)
+                    .map(drop),
+                (StorageCommandType::SortedSetIncrement(amount), Some(cap)) => self
+                    .storage
+                    .sorted_set_increment_capped(&prefixed_key, field, *amount, cap, overflow)
+                    .map(drop),
             }
+            .map_err(|e| e.to_string())?;
 
-            tracked_keys.push(prefixed_key);
+            let bucket_id: Vec<&str> = cmd.key.splitn(4, ':').skip(1).take(2).collect();
+            tracked_keys
+                .entry(bucket_id.join(":"))
+                .or_default()
+                .push(prefixed_key);
         }
 
-        // key tracking: store all keys generated for each time bucket
+        // key tracking: store the keys generated for each time bucket
         // so the query path can discover what to read
         let buckets = self.config.get_time_buckets(processed.timestamp)?;
         for (bucket, bucket_type) in &buckets {
             let keys_key = format!("{}:k:{}:{}", self.prefix, bucket_type.as_str(), bucket);
-            if !tracked_keys.is_empty() {
-                self.storage
-                    .set_add(&keys_key, &tracked_keys)
+            let bucket_id = format!("{}:{}", bucket_type.as_str(), bucket);
+            if let Some(keys) = tracked_keys.get(&bucket_id) {
+                let retention = self.config.time.retention(*bucket_type);
+                let set_is_new = match retention {
+                    Some(_) => !self.storage.exists(&keys_key).map_err(|e| e.to_string())?,
+                    None => false,
+                };
+                let added = self
+                    .storage
+                    .set_add_new(&keys_key, keys)
                     .map_err(|e| e.to_string())?;
+
+                // index hourly buckets so compaction can find them
+                if !added.is_empty() && *bucket_type == BucketType::Hourly {
+                    self.storage
+                        .set_add(&self.bucket_index_key(*bucket_type), &[bucket.to_string()])
+                        .map_err(|e| e.to_string())?;
+                }
+
+                // apply the bucket's retention to the keys this write created,
+                // and to the tracking set when this write created it
+                if let Some(ttl) = retention {
+                    let set = set_is_new.then_some(&keys_key);
+                    for key in added.iter().chain(set) {
+                        self.storage.expire(key, ttl).map_err(|e| e.to_string())?;
+                    }
+                }
             }
+            // a backfilled event changes a bucket that may already be cached.
+            // invalidating after the tracking set is written also bumps the
+            // bucket's generation, so a query that read it before this write
+            // doesn't cache what it read
+            self.cache.invalidate(*bucket_type, *bucket);
         }
 
         Ok(())
     }
 
+    /// the per-pattern cardinality cap for a command's key, None when it is
+    /// uncapped. once a key holds that many fields, unseen values are
+    /// redirected to the overflow field while existing fields keep counting
+    fn cardinality_cap(&self, key: &str, cmd: &StorageCommand) -> Option<usize> {
+        let cap = self
+            .config
+            .limits
+            .cardinality_cap(&cmd.metadata.keys.join("~"));
+        let guarded = match cmd.metadata.metric_type.as_str() {
+            "add" | "top" => true,
+            // summary hashes only ever hold sum/count
+            "adv" => !key.ends_with(":i"),
+            _ => false,
+        };
+
+        (cap > 0 && guarded && cmd.value != constants::OVERFLOW_FIELD).then_some(cap)
+    }
+
+    /// compact hourly buckets that ended before `before` (unix seconds).
+    /// each day's hours are checked against the daily rollup, and the weekly
+    /// and monthly ones when stored, then deleted with their key-tracking
+    /// sets. days whose rollups don't cover the hourly data are kept.
+    pub fn compact(&self, before: i64) -> Result<CompactionResult, String> {
+        let index_key = self.bucket_index_key(BucketType::Hourly);
+        let indexed = self
+            .storage
+            .set_members(&index_key)
+            .map_err(|e| e.to_string())?;
+
+        // hourly bucket starts grouped by the day they belong to
+        let mut days: std::collections::BTreeMap<i64, Vec<i64>> = Default::default();
+        for hour in indexed.iter().filter_map(|h| h.parse::<i64>().ok()) {
+            if self.config.next_bucket_start(hour, BucketType::Hourly)? <= before {
+                let day = self.config.bucket_start(hour, BucketType::Daily)?;
+                days.entry(day).or_default().push(hour);
+            }
+        }
+
+        let mut result = CompactionResult::default();
+        for (day, hours) in days {
+            let mut keys_by_hour = Vec::new();
+            for hour in &hours {
+                let keys_key = format!("{}:k:h:{}", self.prefix, hour);
+                let keys = self
+                    .storage
+                    .set_members(&keys_key)
+                    .map_err(|e| e.to_string())?;
+                keys_by_hour.push((*hour, keys_key, keys));
+            }
+
+            let hour_keys: Vec<&String> = keys_by_hour.iter().flat_map(|(_, _, k)| k).collect();
+            if let Some(uncovered) = self.find_uncovered(day, &hour_keys)? {
+                result.warnings.push(format!(
+                    "hourly buckets for {} not compacted: {}",
+                    self.config.bucket_label(day, BucketType::Daily),
+                    uncovered
+                ));
+                continue;
+            }
+
+            for (hour, keys_key, mut keys) in keys_by_hour {
+                keys.push(keys_key);
+                result.keys += self.storage.delete(&keys).map_err(|e| e.to_string())?;
+                self.storage
+                    .set_remove(&index_key, &[hour.to_string()])
+                    .map_err(|e| e.to_string())?;
+                self.cache.invalidate(BucketType::Hourly, hour);
+                result.buckets += 1;
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// compact hourly buckets older than `time.compact_hourly_after`.
+    /// does nothing when that is 0.
+    pub fn compact_expired(&self) -> Result<CompactionResult, String> {
+        match self.config.time.compact_hourly_after {
+            0 => Ok(CompactionResult::default()),
+            age => self.compact(Utc::now().timestamp() - age as i64),
+        }
+    }
+
+    /// check that the rollups containing `day` hold at least what the given
+    /// hourly keys do. returns a description of the first metric that isn't.
+    fn find_uncovered(&self, day: i64, hour_keys: &[&String]) -> Result<Option<String>, String> {
+        // "type:pattern" -> that metric's hourly keys
+        let mut grouped: HashMap<String, Vec<String>> = HashMap::new();
+        for key in hour_keys {
+            grouped
+                .entry(self.metric_identity(key))
+                .or_default()
+                .push(key.to_string());
+        }
+
+        for bucket_type in [BucketType::Daily, BucketType::Weekly, BucketType::Monthly] {
+            if !self.config.time.stores(bucket_type) {
+                continue;
+            }
+            let bucket = self.config.bucket_start(day, bucket_type)?;
+
+            for (identity, keys) in &grouped {
+                let Some((metric_type, rest)) = identity.split_once(':') else {
+                    continue;
+                };
+                let rollup = format!(
+                    "{}:{}:{}:{}:{}",
+                    self.prefix,
+                    metric_type,
+                    bucket_type.as_str(),
+                    bucket,
+                    rest
+                );
+                if !self.covers(metric_type, &rollup, keys)? {
+                    return Ok(Some(format!(
+                        "{} {} rollup is missing hourly data for {}",
+                        self.config.bucket_label(bucket, bucket_type),
+                        bucket_type.adjective(),
+                        identity
+                    )));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// true if `rollup` accounts for everything in `keys`: unique counts must
+    /// not grow when the hourly hyperloglogs are merged in, and totals must be
+    /// at least the hourly totals (capped fields may land in the overflow
+    /// field, so totals are compared rather than individual fields)
+    fn covers(&self, metric_type: &str, rollup: &str, keys: &[String]) -> Result<bool, String> {
+        match metric_type {
+            "bmp" => {
+                let rollup_count = self
+                    .storage
+                    .hyperloglog_count(rollup)
+                    .map_err(|e| e.to_string())?;
+                let mut all = keys.to_vec();
+                all.push(rollup.to_string());
+                let merged = self
+                    .storage
+                    .hyperloglog_count_union(&all)
+                    .map_err(|e| e.to_string())?;
+                Ok(merged <= rollup_count)
+            }
+            "add" | "adv" | "top" => {
+                let mut hourly = 0.0;
+                for key in keys {
+                    hourly += self.total(metric_type, key)?;
+                }
+                let rolled = self.total(metric_type, rollup)?;
+                Ok(rolled + 1e-9 * rolled.abs().max(1.0) >= hourly)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    /// sum of every value stored under a hash or sorted set key
+    fn total(&self, metric_type: &str, key: &str) -> Result<f64, String> {
+        if metric_type == "top" {
+            let len = self
+                .storage
+                .sorted_set_len(key)
+                .map_err(|e| e.to_string())?;
+            let members = self
+                .storage
+                .sorted_set_top(key, len)
+                .map_err(|e| e.to_string())?;
+            return Ok(members.iter().map(|(_, score)| score).sum());
+        }
+
+        let fields = self.storage.hash_get_all(key).map_err(|e| e.to_string())?;
+        Ok(fields.values().filter_map(|v| v.parse::<f64>().ok()).sum())
+    }
+
+    /// set of bucket starts written for a bucket type
+    fn bucket_index_key(&self, bucket_type: BucketType) -> String {
+        format!("{}:b:{}", self.prefix, bucket_type.as_str())
+    }
+
     /// query metrics for a range of timestamps (unix seconds).
     /// returns results organized by day.
     pub fn query(&self, min: i64, max: i64) -> Result<QueryResult, String> {
+        self.query_by(min, max, BucketType::Daily)
+    }
+
+    /// query hourly buckets for a range of timestamps (unix seconds).
+    /// requires `time.store_hourly`.
+    pub fn query_hours(&self, min: i64, max: i64) -> Result<QueryResult, String> {
+        self.query_by(min, max, BucketType::Hourly)
+    }
+
+    /// query minute buckets for a range of timestamps (unix seconds).
+    /// requires `time.store_minutely`; minutes older than `minute_ttl` are gone.
+    pub fn query_minutes(&self, min: i64, max: i64) -> Result<QueryResult, String> {
+        self.query_by(min, max, BucketType::Minute)
+    }
+
+    /// query metrics for a range of timestamps at the given granularity.
+    /// each entry in the result's `days` holds one bucket.
+    pub fn query_by(
+        &self,
+        min: i64,
+        max: i64,
+        granularity: BucketType,
+    ) -> Result<QueryResult, String> {
+        self.query_filtered(min, max, granularity, &QueryFilter::default())
+    }
+
+    /// like `query_by`, but only reads the tracked keys `filter` allows
+    pub fn query_filtered(
+        &self,
+        min: i64,
+        max: i64,
+        granularity: BucketType,
+        filter: &QueryFilter,
+    ) -> Result<QueryResult, String> {
+        let days = self
+            .query_stream(min, max, granularity, filter)?
+            .collect::<Result<Vec<DayResult>, String>>()?;
+        self.merge_range(days, filter)
+    }
+
+    /// wrap buckets read for one range in a `QueryResult`, merging each bmp
+    /// and top metric across all of them
+    fn merge_range(
+        &self,
+        days: Vec<DayResult>,
+        filter: &QueryFilter,
+    ) -> Result<QueryResult, String> {
+        let mut range_keys: HashMap<String, Vec<String>> = HashMap::new();
+        for result in days.iter().flat_map(|d| &d.results) {
+            if matches!(result.metric_type.as_str(), "bmp" | "top") {
+                range_keys
+                    .entry(format!("{}:{}", result.metric_type, result.pattern_id()))
+                    .or_default()
+                    .push(result.location.clone());
+            }
+        }
+
+        self.with_range_merges(QueryResult::new(days), range_keys, filter)
+    }
+
+    /// like `query_filtered`, but yields one bucket at a time as it is read.
+    /// see `stream` for what a stream leaves out
+    pub fn query_stream(
+        &self,
+        min: i64,
+        max: i64,
+        granularity: BucketType,
+        filter: &QueryFilter,
+    ) -> Result<stream::DayStream<'_>, String> {
         // validate timezone early
         let _tz = utils::parse_timezone(&self.config.time.timezone)?;
+
+        if !self.config.time.stores(granularity) {
+            return Err(format!(
+                "{} buckets are not stored (enable time.store_{})",
+                granularity.name(),
+                granularity.adjective()
+            ));
+        }
+
+        // walk calendar buckets in the configured timezone, computing each
+        // start exactly as the write path does so DST shifts line up
+        let first = self.config.bucket_start(min, granularity)?;
+        Ok(stream::DayStream::new(
+            self,
+            first,
+            max,
+            granularity,
+            filter.clone(),
+        ))
+    }
+
+    /// query a range at the coarsest stored granularities that exactly cover
+    /// it: the whole months inside it, then whole ISO weeks in what is left,
+    /// then days, so a result may mix bucket types. bmp uniques and top
+    /// leaderboards are merged across all of them as usual. `min` is the
+    /// first day and `max` any timestamp within the last day. a rollup bucket
+    /// with no tracked keys while its days have some, as when the rollup was
+    /// enabled after recording, is read from finer buckets instead.
+    pub fn query_auto(&self, min: i64, max: i64) -> Result<QueryResult, String> {
+        self.query_auto_filtered(min, max, &QueryFilter::default())
+    }
+
+    /// like `query_auto`, but only reads the tracked keys `filter` allows
+    pub fn query_auto_filtered(
+        &self,
+        min: i64,
+        max: i64,
+        filter: &QueryFilter,
+    ) -> Result<QueryResult, String> {
+        let first_day = self.config.bucket_start(min, BucketType::Daily)?;
+        let last_day = self.config.bucket_start(max, BucketType::Daily)?;
+        let end = self.config.next_bucket_start(last_day, BucketType::Daily)?;
+        let buckets =
+            self.auto_buckets(first_day, end, &[BucketType::Monthly, BucketType::Weekly])?;
+
+        // read each run of same-typed buckets as one stream
+        let mut days = Vec::new();
+        let mut run_start = 0;
+        for i in 1..=buckets.len() {
+            if i == buckets.len() || buckets[i].1 != buckets[run_start].1 {
+                let (first, granularity) = buckets[run_start];
+                let last = buckets[i - 1].0;
+                for day in self.query_stream(first, last, granularity, filter)? {
+                    days.push(day?);
+                }
+                run_start = i;
+            }
+        }
+
+        self.merge_range(days, filter)
+    }
+
+    /// the buckets `query_auto` reads for `from..to` (bucket starts), in
+    /// order: whole buckets of the first stored granularity whose rollups
+    /// cover their days, with the gaps between them split the same way by
+    /// the remaining granularities, and days last
+    fn auto_buckets(
+        &self,
+        from: i64,
+        to: i64,
+        granularities: &[BucketType],
+    ) -> Result<Vec<(i64, BucketType)>, String> {
+        let Some((&granularity, finer)) = granularities.split_first() else {
+            let mut days = Vec::new();
+            let mut day = from;
+            while day < to {
+                days.push((day, BucketType::Daily));
+                day = self.config.next_bucket_start(day, BucketType::Daily)?;
+            }
+            return Ok(days);
+        };
+        if !self.config.time.stores(granularity) {
+            return self.auto_buckets(from, to, finer);
+        }
+
+        let mut buckets = Vec::new();
+        let mut gap_start = from;
+        let mut bucket = self.config.bucket_start(from, granularity)?;
+        if bucket < from {
+            bucket = self.config.next_bucket_start(bucket, granularity)?;
+        }
+        while bucket < to {
+            let next = self.config.next_bucket_start(bucket, granularity)?;
+            if next > to {
+                break;
+            }
+            if self.rollups_cover_days(bucket, bucket, granularity)? {
+                buckets.extend(self.auto_buckets(gap_start, bucket, finer)?);
+                buckets.push((bucket, granularity));
+                gap_start = next;
+            }
+            bucket = next;
+        }
+        buckets.extend(self.auto_buckets(gap_start, to, finer)?);
+        Ok(buckets)
+    }
+
+    /// true unless a `granularity` bucket from `first` to `last` has no
+    /// tracked keys while one of its days does
+    fn rollups_cover_days(
+        &self,
+        first: i64,
+        last: i64,
+        granularity: BucketType,
+    ) -> Result<bool, String> {
+        let tracked = |bucket_type: BucketType, bucket: i64| -> Result<bool, String> {
+            let keys_key = format!("{}:k:{}:{}", self.prefix, bucket_type.as_str(), bucket);
+            self.storage
+                .set_len(&keys_key)
+                .map(|len| len > 0)
+                .map_err(|e| e.to_string())
+        };
+
+        let mut bucket = first;
+        while bucket <= last {
+            let next = self.config.next_bucket_start(bucket, granularity)?;
+            if !tracked(granularity, bucket)? {
+                let mut day = bucket;
+                while day < next {
+                    if tracked(BucketType::Daily, day)? {
+                        return Ok(false);
+                    }
+                    day = self.config.next_bucket_start(day, BucketType::Daily)?;
+                }
+            }
+            bucket = next;
+        }
+        Ok(true)
+    }
+
+    /// query the days of another timezone, re-aggregated from hourly buckets.
+    /// requires `time.store_hourly`. each hour counts toward the target day its
+    /// bucket starts in; bmp hyperloglogs are merged, other types summed.
+    pub fn query_in_timezone(
+        &self,
+        min: i64,
+        max: i64,
+        timezone: &str,
+    ) -> Result<QueryResult, String> {
+        self.query_in_timezone_filtered(min, max, timezone, &QueryFilter::default())
+    }
+
+    /// like `query_in_timezone`, but only reads the tracked keys `filter` allows
+    pub fn query_in_timezone_filtered(
+        &self,
+        min: i64,
+        max: i64,
+        timezone: &str,
+        filter: &QueryFilter,
+    ) -> Result<QueryResult, String> {
+        utils::parse_timezone(timezone)?;
+        if !self.config.time.store_hourly {
+            return Err(format!(
+                "querying in {} requires hourly buckets (enable time.store_hourly)",
+                timezone
+            ));
+        }
+
+        let mut target = self.config.clone();
+        target.time.timezone = timezone.to_string();
+
         let mut days: Vec<DayResult> = Vec::new();
+        let mut range_keys: HashMap<String, Vec<String>> = HashMap::new();
+        let mut day = target.bucket_start(min, BucketType::Daily)?;
+        while day <= max {
+            let next_day = target.next_bucket_start(day, BucketType::Daily)?;
+            days.push(DayResult {
+                date: target.bucket_label(day, BucketType::Daily),
+                timestamp: day,
+                results: self.aggregate_hours(day, next_day, filter, &mut range_keys)?,
+            });
+            day = next_day;
+        }
 
-        // walk day-by-day from min to max
-        let day_secs: i64 = 86400;
-        let mut current = min;
+        let first = target.bucket_start(min, BucketType::Daily)?;
+        let mut result = self.with_range_merges(QueryResult::new(days), range_keys, filter)?;
+        for date in self.days_missing_hours(first, day, filter)? {
+            result.warnings.push(format!(
+                "{} has no hourly buckets for some of its metrics \
+                 (recorded before store_hourly or compacted), results are incomplete",
+                date
+            ));
+        }
+        Ok(result)
+    }
 
-        while current <= max {
-            let day_results = self.query_day(current, "d")?;
-            days.push(day_results);
-            current += day_secs;
+    /// days of the configured timezone overlapping [start, end) whose daily
+    /// bucket tracks metrics that none of its hours do, so re-aggregating
+    /// their hours would silently leave them out
+    fn days_missing_hours(
+        &self,
+        start: i64,
+        end: i64,
+        filter: &QueryFilter,
+    ) -> Result<Vec<String>, String> {
+        let tracked =
+            |timestamp: i64, bucket_type: BucketType| -> Result<HashSet<String>, String> {
+                let keys_key = format!("{}:k:{}:{}", self.prefix, bucket_type.as_str(), timestamp);
+                Ok(self
+                    .storage
+                    .set_members(&keys_key)
+                    .map_err(|e| e.to_string())?
+                    .iter()
+                    .map(|key| self.metric_identity(key))
+                    .filter(|identity| filter.allows(identity))
+                    .collect())
+            };
+
+        let mut missing = Vec::new();
+        let mut day = self.config.bucket_start(start, BucketType::Daily)?;
+        while day < end {
+            let next_day = self.config.next_bucket_start(day, BucketType::Daily)?;
+            let daily = tracked(day, BucketType::Daily)?;
+            if !daily.is_empty() {
+                let mut hourly = HashSet::new();
+                let mut hour = day;
+                while hour < next_day {
+                    hourly.extend(tracked(hour, BucketType::Hourly)?);
+                    hour = self.config.next_bucket_start(hour, BucketType::Hourly)?;
+                }
+                if !daily.is_subset(&hourly) {
+                    missing.push(self.config.bucket_label(day, BucketType::Daily));
+                }
+            }
+            day = next_day;
+        }
+        Ok(missing)
+    }
+
+    /// merge the hourly buckets starting within [start, end) into one result
+    /// per metric, collecting the hourly bmp and top keys into `range_keys`
+    fn aggregate_hours(
+        &self,
+        start: i64,
+        end: i64,
+        filter: &QueryFilter,
+        range_keys: &mut HashMap<String, Vec<String>>,
+    ) -> Result<Vec<MetricResult>, String> {
+        // "type:pattern" -> that metric's results from each hour
+        let mut grouped: HashMap<String, Vec<MetricResult>> = HashMap::new();
+
+        let mut hour = self.config.bucket_start(start, BucketType::Hourly)?;
+        if hour < start {
+            hour = self.config.next_bucket_start(hour, BucketType::Hourly)?;
+        }
+
+        while hour < end {
+            for result in self.query_bucket(hour, BucketType::Hourly, filter)?.results {
+                grouped
+                    .entry(self.metric_identity(&result.location))
+                    .or_default()
+                    .push(result);
+            }
+            hour = self.config.next_bucket_start(hour, BucketType::Hourly)?;
+        }
+
+        let mut results = Vec::new();
+        for group in grouped.into_values() {
+            let first = &group[0];
+            let keys: Vec<String> = group.iter().map(|r| r.location.clone()).collect();
+            let merged = match first.metric_type.as_str() {
+                "bmp" => MetricData::Count(
+                    self.storage
+                        .hyperloglog_count_union(&keys)
+                        .map_err(|e| e.to_string())?,
+                ),
+                "top" => MetricData::Ranked(self.union_top(&keys, &first.pattern_id(), filter)?),
+                _ => {
+                    results.push(QueryResult::merge_results(&group, &first.metric_type));
+                    continue;
+                }
+            };
+
+            range_keys
+                .entry(format!("{}:{}", first.metric_type, first.pattern_id()))
+                .or_default()
+                .extend(keys);
+            results.push(MetricResult {
+                location: String::new(),
+                result: merged,
+                ..first.clone()
+            });
+        }
+
+        Ok(results)
+    }
+
+    /// merge each bmp and top metric across all of its buckets in storage:
+    /// distinct counts from unioned hyperloglogs, leaderboards from unioned
+    /// sorted sets. `range_keys` maps "type:pattern" to the metric's keys.
+    /// does nothing when `filter.skip_merges` is set
+    fn with_range_merges(
+        &self,
+        mut result: QueryResult,
+        range_keys: HashMap<String, Vec<String>>,
+        filter: &QueryFilter,
+    ) -> Result<QueryResult, String> {
+        if filter.skip_merges {
+            return Ok(result);
+        }
+        for (identity, keys) in range_keys {
+            match identity.split_once(':') {
+                Some(("bmp", pattern)) => {
+                    let count = self
+                        .storage
+                        .hyperloglog_count_union(&keys)
+                        .map_err(|e| e.to_string())?;
+                    result.unique_counts.insert(pattern.to_string(), count);
+                }
+                Some(("top", pattern)) => {
+                    let pairs = self.union_top(&keys, pattern, filter)?;
+                    result.merged_tops.insert(pattern.to_string(), pairs);
+                }
+                _ => {}
+            }
         }
+        Ok(result)
+    }
 
-        // if hourly is enabled, also query hourly buckets
-        // for now we only return daily results in the query output
-        // hourly data is stored and can be queried separately
+    /// top entries of several sorted sets summed together, as deep as the
+    /// query or the pattern's `top_depth` asks
+    fn union_top(
+        &self,
+        keys: &[String],
+        pattern: &str,
+        filter: &QueryFilter,
+    ) -> Result<Vec<(String, f64)>, String> {
+        let depth = filter.top.unwrap_or_else(|| self.top_depth(pattern));
+        self.storage
+            .sorted_set_union_top(keys, depth)
+            .map_err(|e| e.to_string())
+    }
 
-        Ok(QueryResult { days })
+    /// strip prefix, bucket type and timestamp from a storage key,
+    /// leaving "type:pattern[:extra]"
+    fn metric_identity(&self, key: &str) -> String {
+        let unprefixed = key
+            .strip_prefix(&format!("{}:", self.prefix))
+            .unwrap_or(key);
+        let parts: Vec<&str> = unprefixed.splitn(4, ':').collect();
+        match parts.as_slice() {
+            [metric_type, _, _, rest] => format!("{}:{}", metric_type, rest),
+            _ => unprefixed.to_string(),
+        }
     }
 
     /// convenience: query the last N days
     pub fn query_days(&self, num_days: i32) -> Result<QueryResult, String> {
-        let tz = utils::parse_timezone(&self.config.time.timezone)?;
-        let now = Utc::now().with_timezone(&tz);
-        let today = now
-            .date_naive()
-            .and_hms_opt(0, 0, 0)
-            .ok_or_else(|| "failed to create timestamp".to_string())?
-            .and_local_timezone(tz)
-            .earliest()
-            .ok_or_else(|| "failed to convert timestamp".to_string())?
-            .timestamp();
+        self.query_days_by(num_days, BucketType::Daily)
+    }
 
-        let day_secs: i64 = 86400;
-        let min = today - (num_days.unsigned_abs() as i64 * day_secs);
-        let max = today;
+    /// convenience: query the last N days at the given granularity,
+    /// up to the current bucket
+    pub fn query_days_by(
+        &self,
+        num_days: i32,
+        granularity: BucketType,
+    ) -> Result<QueryResult, String> {
+        self.query_days_filtered(num_days, granularity, &QueryFilter::default())
+    }
 
-        self.query(min, max)
+    /// like `query_days_by`, but only reads the tracked keys `filter` allows
+    pub fn query_days_filtered(
+        &self,
+        num_days: i32,
+        granularity: BucketType,
+        filter: &QueryFilter,
+    ) -> Result<QueryResult, String> {
+        let now = Utc::now().timestamp();
+        let today = self.config.bucket_start(now, BucketType::Daily)?;
+
+        let min = self
+            .config
+            .day_offset(today, -(num_days.unsigned_abs() as i64))?;
+        let max = match granularity {
+            BucketType::Daily => today,
+            _ => now,
+        };
+
+        self.query_filtered(min, max, granularity, filter)
     }
 
-    /// query all metrics for a single day bucket
-    fn query_day(&self, timestamp: i64, bucket_type: &str) -> Result<DayResult, String> {
-        let keys_key = format!("{}:k:{}:{}", self.prefix, bucket_type, timestamp);
+    /// query a calendar range expression such as `2026-09`, `yesterday`,
+    /// `last 7d` or `2026-09-01..2026-09-14`, resolved in the configured
+    /// timezone. see the `range` module for the full syntax.
+    pub fn query_range(&self, expr: &str) -> Result<QueryResult, String> {
+        self.query_range_by(expr, BucketType::Daily)
+    }
 
-        let tracked_keys = self
+    /// query a calendar range expression at the given granularity
+    pub fn query_range_by(
+        &self,
+        expr: &str,
+        granularity: BucketType,
+    ) -> Result<QueryResult, String> {
+        self.query_range_filtered(expr, granularity, &QueryFilter::default())
+    }
+
+    /// like `query_range_by`, but only reads the tracked keys `filter` allows
+    pub fn query_range_filtered(
+        &self,
+        expr: &str,
+        granularity: BucketType,
+        filter: &QueryFilter,
+    ) -> Result<QueryResult, String> {
+        let (min, max) = range::parse(expr, &self.config, Utc::now().timestamp())?;
+        self.query_filtered(min, max, granularity, filter)
+    }
+
+    /// like `query_range_filtered`, but at the granularity `query_auto` picks
+    pub fn query_range_auto(
+        &self,
+        expr: &str,
+        filter: &QueryFilter,
+    ) -> Result<QueryResult, String> {
+        let (min, max) = range::parse(expr, &self.config, Utc::now().timestamp())?;
+        self.query_auto_filtered(min, max, filter)
+    }
+
+    /// run a text query such as `sum add/event~geo group by geo last 7d top 10`;
+    /// see `ql` for the syntax
+    pub fn query_str(&self, text: &str) -> Result<ql::Table, String> {
+        ql::parse(text)?.execute(self)
+    }
+
+    /// compare two calendar range expressions, e.g. `this_week` against
+    /// `last_week`: each metric is merged over its period and matched field
+    /// by field with absolute and percentage deltas. leaderboards are read
+    /// `COMPARE_TOP_DEPTH_FACTOR` times deeper than their deepest `top_depth`
+    /// so members that fell out of the top still have a previous rank
+    pub fn compare(&self, range: &str, previous: &str) -> Result<compare::Comparison, String> {
+        let deepest = self
+            .config
+            .mapping
+            .top
+            .iter()
+            .map(|pattern| self.top_depth(pattern))
+            .max()
+            .unwrap_or(constants::TOP_DEPTH);
+        let read_depth = deepest.saturating_mul(constants::COMPARE_TOP_DEPTH_FACTOR);
+        let filter = QueryFilter {
+            top: Some(read_depth),
+            ..QueryFilter::default()
+        };
+        let current = self.query_range_filtered(range, BucketType::Daily, &filter)?;
+        let before = self.query_range_filtered(previous, BucketType::Daily, &filter)?;
+        Ok(compare::compare(
+            &current,
+            &before,
+            |pattern| self.top_depth(pattern),
+            read_depth,
+        ))
+    }
+
+    /// flag points of a metric's fields (`count` for bmp) within a range
+    /// expression that stray from their trailing window, using the metric's
+    /// `mapping.anomaly` settings. buckets before the range are read to fill
+    /// the window for its first points. a bucket still open is projected to
+    /// its full length at the current rate once `ANOMALY_MIN_ELAPSED` of it
+    /// has passed, and left out before that; bmp uniques don't scale with
+    /// time, so an open bmp bucket is always left out.
+    pub fn anomalies(
+        &self,
+        metric: &str,
+        fields: &[String],
+        range: &str,
+        granularity: BucketType,
+    ) -> Result<Vec<anomaly::Report>, String> {
+        let opts = FindOptions::parse(metric)?;
+        let is_bmp = opts.metric_type == MetricType::Bmp;
+        if fields.is_empty() && !is_bmp {
+            return Err(format!(
+                "fields are required for {} metrics: {}",
+                opts.metric_type, metric
+            ));
+        }
+        let config = anomaly::config_for(&self.config.mapping, metric);
+
+        let now = Utc::now().timestamp();
+        let (min, max) = range::parse(range, &self.config, now)?;
+        let first = self.config.bucket_start(min, granularity)?;
+        let mut start = first;
+        for _ in 0..config.window {
+            start = self.config.bucket_start(start - 1, granularity)?;
+        }
+
+        let result =
+            self.query_filtered(start, max, granularity, &QueryFilter::from_find(&opts))?;
+        let fields = if fields.is_empty() {
+            vec!["count".to_string()]
+        } else {
+            fields.to_vec()
+        };
+
+        // the share of the open bucket that has passed, if the series ends in one
+        let current = self.config.bucket_start(now, granularity)?;
+        let open = match result.days.last() {
+            Some(day) if day.timestamp == current => {
+                let end = self.config.next_bucket_start(current, granularity)?;
+                Some((now - current) as f64 / (end - current) as f64)
+            }
+            _ => None,
+        };
+
+        fields
+            .into_iter()
+            .map(|field| {
+                let mut points = result.series(metric, &field)?;
+                let mut projected = None;
+                if let Some(elapsed) = open {
+                    let (timestamp, value) = points.pop().unwrap_or_default();
+                    if !is_bmp && elapsed >= constants::ANOMALY_MIN_ELAPSED {
+                        points.push((timestamp, value / elapsed));
+                        projected = Some(timestamp);
+                    }
+                }
+
+                let anomalies = anomaly::detect(&points, &config)
+                    .into_iter()
+                    .filter(|a| a.timestamp >= first)
+                    .map(|a| anomaly::Anomaly {
+                        projected: projected == Some(a.timestamp),
+                        ..a
+                    })
+                    .collect();
+                Ok(anomaly::Report {
+                    metric: metric.to_string(),
+                    field,
+                    config,
+                    anomalies,
+                })
+            })
+            .collect()
+    }
+
+    /// list the metrics tracked on each day of a range expression, with the
+    /// number of fields each hash or leaderboard holds. only the key-tracking
+    /// sets and field counts are read, not metric values.
+    pub fn catalog(&self, range: &str) -> Result<catalog::Catalog, String> {
+        let (min, max) = range::parse(range, &self.config, Utc::now().timestamp())?;
+
+        let mut days = Vec::new();
+        let mut day = self.config.bucket_start(min, BucketType::Daily)?;
+        while day <= max {
+            days.push(self.catalog_bucket(day, BucketType::Daily)?);
+            day = self.config.next_bucket_start(day, BucketType::Daily)?;
+        }
+        Ok(catalog::Catalog { days })
+    }
+
+    /// the catalog entries for a single bucket's tracked keys
+    fn catalog_bucket(
+        &self,
+        timestamp: i64,
+        bucket_type: BucketType,
+    ) -> Result<catalog::CatalogDay, String> {
+        let keys_key = format!("{}:k:{}:{}", self.prefix, bucket_type.as_str(), timestamp);
+        let tracked_keys: HashSet<String> = self
             .storage
             .set_members(&keys_key)
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .collect();
+
+        // the tracked keys per storage kind, read in one bulk call each
+        let mut hashes = Vec::new();
+        let mut sorted_sets = Vec::new();
+        let mut hyperloglogs = Vec::new();
+        for key in &tracked_keys {
+            match self.split_metric_key(key) {
+                Some(("add", _)) => hashes.push(key.clone()),
+                // the summary hash beside each adv hash only holds sum/count
+                Some(("adv", rest)) if !rest.ends_with(":i") => hashes.push(key.clone()),
+                Some(("top", _)) => sorted_sets.push(key.clone()),
+                Some(("bmp", _)) => hyperloglogs.push(key.clone()),
+                _ => {}
+            }
+        }
+        let hash_lens = self
+            .storage
+            .hash_len_many(&hashes)
             .map_err(|e| e.to_string())?;
+        let sorted_set_lens = self
+            .storage
+            .sorted_set_len_many(&sorted_sets)
+            .map_err(|e| e.to_string())?;
+        let hyperloglogs_exist = self
+            .storage
+            .exists_many(&hyperloglogs)
+            .map_err(|e| e.to_string())?;
+
+        // a key that expired while its tracking set lives on is left out
+        let lengths = hashes
+            .iter()
+            .zip(hash_lens)
+            .chain(sorted_sets.iter().zip(sorted_set_lens))
+            .filter(|(_, len)| *len > 0)
+            .map(|(key, len)| (key, Some(len)));
+        let bmps = hyperloglogs
+            .iter()
+            .zip(hyperloglogs_exist)
+            .filter(|(_, exists)| *exists)
+            .map(|(key, _)| (key, None));
+
+        let mut metrics = Vec::new();
+        for (key, fields) in lengths.chain(bmps) {
+            let Some((metric_type, rest)) = self.split_metric_key(key) else {
+                continue;
+            };
+            let (pattern, add_key) = match metric_type {
+                "adv" => match rest.split_once(':') {
+                    Some((add_key, pattern)) => (pattern, Some(add_key)),
+                    None => continue,
+                },
+                _ => (rest, None),
+            };
+
+            metrics.push(catalog::CatalogEntry {
+                metric_type: metric_type.to_string(),
+                pattern: pattern.to_string(),
+                add_key: add_key.map(String::from),
+                fields,
+            });
+        }
+        metrics.sort_by(|a, b| {
+            (&a.metric_type, &a.pattern, &a.add_key).cmp(&(&b.metric_type, &b.pattern, &b.add_key))
+        });
+
+        Ok(catalog::CatalogDay {
+            date: self.config.bucket_label(timestamp, bucket_type),
+            timestamp,
+            metrics,
+        })
+    }
 
-        let date = chrono::DateTime::from_timestamp(timestamp, 0)
-            .map(|dt| dt.format("%Y-%m-%d").to_string())
-            .unwrap_or_default();
+    /// hit and miss counters of the closed-bucket result cache
+    pub fn cache_stats(&self) -> cache::CacheStats {
+        self.cache.stats()
+    }
+
+    /// query the metrics `filter` allows for a single bucket, through the
+    /// result cache once the bucket has closed
+    fn query_bucket(
+        &self,
+        timestamp: i64,
+        bucket_type: BucketType,
+        filter: &QueryFilter,
+    ) -> Result<DayResult, String> {
+        // buckets with a retention period would outlive their keys in the cache
+        let cacheable = self.cache.enabled()
+            && self.config.time.retention(bucket_type).is_none()
+            && self.config.next_bucket_start(timestamp, bucket_type)? <= Utc::now().timestamp();
+        if !cacheable {
+            return self.read_bucket(timestamp, bucket_type, filter);
+        }
 
-        let mut results: Vec<MetricResult> = Vec::new();
+        if let Some(day) = self.cache.get(bucket_type, timestamp, filter) {
+            return Ok(day);
+        }
+        // taken before the read, so a write landing during it keeps the
+        // result out of the cache
+        let generation = self.cache.generation(bucket_type, timestamp);
+        let day = self.read_bucket(timestamp, bucket_type, filter)?;
+        self.cache.insert(bucket_type, filter, &day, generation);
+        Ok(day)
+    }
+
+    /// read the metrics `filter` allows for a single bucket from storage
+    fn read_bucket(
+        &self,
+        timestamp: i64,
+        bucket_type: BucketType,
+        filter: &QueryFilter,
+    ) -> Result<DayResult, String> {
+        let keys_key = format!("{}:k:{}:{}", self.prefix, bucket_type.as_str(), timestamp);
+
+        let tracked_keys = self
+            .storage
+            .set_members(&keys_key)
+            .map_err(|e| e.to_string())?;
+
+        let date = self.config.bucket_label(timestamp, bucket_type);
 
         // deduplicate keys (same key may appear multiple times from different events)
         let unique_keys: HashSet<String> = tracked_keys.into_iter().collect();
 
+        // group the allowed keys by data structure so each is read in one batch
+        let mut hash_keys: Vec<String> = Vec::new();
+        let mut bmp_keys: Vec<String> = Vec::new();
+        let mut top_keys: Vec<(String, usize)> = Vec::new();
         for key in unique_keys {
-            if let Some(result) = self.read_metric_key(&key)? {
-                results.push(result);
+            if !filter.allows(&self.metric_identity(&key)) {
+                continue;
+            }
+            match self.split_metric_key(&key) {
+                Some(("bmp", _)) => bmp_keys.push(key),
+                Some(("add" | "adv", _)) => hash_keys.push(key),
+                Some(("top", pattern)) => {
+                    let depth = filter.top.unwrap_or_else(|| self.top_depth(pattern));
+                    top_keys.push((key, depth));
+                }
+                _ => {}
             }
         }
 
+        let counts = self
+            .storage
+            .hyperloglog_count_many(&bmp_keys)
+            .map_err(|e| e.to_string())?;
+        let hashes = self
+            .storage
+            .hash_get_all_many(&hash_keys)
+            .map_err(|e| e.to_string())?;
+        let tops = self
+            .storage
+            .sorted_set_top_many(&top_keys)
+            .map_err(|e| e.to_string())?;
+
+        let fetched = (bmp_keys.iter().zip(counts.into_iter().map(Fetched::Count)))
+            .chain(hash_keys.iter().zip(hashes.into_iter().map(Fetched::Hash)))
+            .chain(
+                top_keys
+                    .iter()
+                    .map(|(key, _)| key)
+                    .zip(tops.into_iter().map(Fetched::Ranked)),
+            );
+        let results: Vec<MetricResult> = fetched
+            .filter_map(|(key, data)| self.metric_result(key, data))
+            .collect();
+
         Ok(DayResult {
             date,
             timestamp,
@@ -1082,9 +2950,19 @@ impl Traqq {
         })
     }
 
-    /// parse a storage key and read the appropriate data.
+    /// configured depth for a concrete top pattern, the most specific
+    /// declared key or glob winning
+    fn top_depth(&self, pattern: &str) -> usize {
+        utils::most_specific(&self.config.mapping.top_depth, |declared| {
+            utils::pattern_match(declared, pattern)
+        })
+        .copied()
+        .unwrap_or(constants::TOP_DEPTH)
+    }
+
+    /// split a storage key into its metric type and the rest after the bucket.
     /// key format: prefix:type:bucket:timestamp:pattern[:extra]
-    fn read_metric_key(&self, key: &str) -> Result<Option<MetricResult>, String> {
+    fn split_metric_key<'a>(&self, key: &'a str) -> Option<(&'a str, &'a str)> {
         // strip our prefix
         let unprefixed = key
             .strip_prefix(&format!("{}:", self.prefix))
@@ -1093,36 +2971,30 @@ impl Traqq {
         // parse: type:bucket:timestamp:rest
         let parts: Vec<&str> = unprefixed.splitn(4, ':').collect();
         if parts.len() < 4 {
-            return Ok(None);
+            return None;
         }
+        Some((parts[0], parts[3]))
+    }
 
-        let metric_type = parts[0];
-        // parts[1] = bucket type (d/h)
-        // parts[2] = timestamp
-        let rest = parts[3];
+    /// build the result for a storage key from the data read for it
+    fn metric_result(&self, key: &str, data: Fetched) -> Option<MetricResult> {
+        let (metric_type, rest) = self.split_metric_key(key)?;
 
-        match metric_type {
-            "bmp" => {
+        match (metric_type, data) {
+            ("bmp", Fetched::Count(count)) => {
                 // rest = pattern (e.g., "ip")
-                let count = self
-                    .storage
-                    .hyperloglog_count(key)
-                    .map_err(|e| e.to_string())?;
-
-                Ok(Some(MetricResult {
+                Some(MetricResult {
                     metric_type: "bmp".to_string(),
                     key: rest.split('~').map(String::from).collect(),
                     location: key.to_string(),
                     add_key: None,
                     result: MetricData::Count(count),
-                }))
+                })
             }
-            "add" => {
+            ("add", Fetched::Hash(fields)) => {
                 // rest = pattern (e.g., "event" or "event~geo")
-                let fields = self.storage.hash_get_all(key).map_err(|e| e.to_string())?;
-
                 if fields.is_empty() {
-                    return Ok(None);
+                    return None;
                 }
 
                 let hash: HashMap<String, i64> = fields
@@ -1130,15 +3002,15 @@ impl Traqq {
                     .filter_map(|(k, v)| v.parse::<i64>().ok().map(|n| (k, n)))
                     .collect();
 
-                Ok(Some(MetricResult {
+                Some(MetricResult {
                     metric_type: "add".to_string(),
                     key: rest.split('~').map(String::from).collect(),
                     location: key.to_string(),
                     add_key: None,
                     result: MetricData::Hash(hash),
-                }))
+                })
             }
-            "adv" => {
+            ("adv", Fetched::Hash(fields)) => {
                 // rest = add_key:pattern or add_key:pattern:i
                 let is_summary = rest.ends_with(":i");
                 let clean_rest = if is_summary {
@@ -1148,21 +3020,13 @@ impl Traqq {
                 };
 
                 // parse add_key:pattern
-                let adv_parts: Vec<&str> = clean_rest.splitn(2, ':').collect();
-                if adv_parts.len() < 2 {
-                    return Ok(None);
-                }
-
-                let add_key = adv_parts[0];
-                let pattern = adv_parts[1];
-
-                let fields = self.storage.hash_get_all(key).map_err(|e| e.to_string())?;
+                let (add_key, pattern) = clean_rest.split_once(':')?;
 
                 if fields.is_empty() {
-                    return Ok(None);
+                    return None;
                 }
 
-                if is_summary {
+                let result = if is_summary {
                     let sum: f64 = fields
                         .get("sum")
                         .and_then(|v| v.parse().ok())
@@ -1171,52 +3035,50 @@ impl Traqq {
                         .get("count")
                         .and_then(|v| v.parse().ok())
                         .unwrap_or(0);
-
-                    Ok(Some(MetricResult {
-                        metric_type: "adv".to_string(),
-                        key: pattern.split('~').map(String::from).collect(),
-                        location: key.to_string(),
-                        add_key: Some(add_key.to_string()),
-                        result: MetricData::Summary { sum, count },
-                    }))
+                    MetricData::Summary { sum, count }
                 } else {
-                    let hash: HashMap<String, f64> = fields
-                        .into_iter()
-                        .filter_map(|(k, v)| v.parse::<f64>().ok().map(|n| (k, n)))
-                        .collect();
+                    MetricData::FloatHash(
+                        fields
+                            .into_iter()
+                            .filter_map(|(k, v)| v.parse::<f64>().ok().map(|n| (k, n)))
+                            .collect(),
+                    )
+                };
 
-                    Ok(Some(MetricResult {
-                        metric_type: "adv".to_string(),
-                        key: pattern.split('~').map(String::from).collect(),
-                        location: key.to_string(),
-                        add_key: Some(add_key.to_string()),
-                        result: MetricData::FloatHash(hash),
-                    }))
-                }
+                Some(MetricResult {
+                    metric_type: "adv".to_string(),
+                    key: pattern.split('~').map(String::from).collect(),
+                    location: key.to_string(),
+                    add_key: Some(add_key.to_string()),
+                    result,
+                })
             }
-            "top" => {
+            ("top", Fetched::Ranked(pairs)) => {
                 // rest = pattern (e.g., "geo" or "geo~offer")
-                let pairs = self
-                    .storage
-                    .sorted_set_top(key, 250)
-                    .map_err(|e| e.to_string())?;
-
                 if pairs.is_empty() {
-                    return Ok(None);
+                    return None;
                 }
 
-                Ok(Some(MetricResult {
+                Some(MetricResult {
                     metric_type: "top".to_string(),
                     key: rest.split('~').map(String::from).collect(),
                     location: key.to_string(),
                     add_key: None,
                     result: MetricData::Ranked(pairs),
-                }))
+                })
             }
-            _ => Ok(None),
+            _ => None,
         }
     }
 }
 
+/// raw data read for one metric key by the bulk storage reads
+enum Fetched {
+    Count(u64),
+    Hash(HashMap<String, String>),
+    Ranked(Vec<(String, f64)>),
+}
+
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests;
@@ -19,6 +19,10 @@ fn main() {
         "serve" => cmd_serve(&args[2..]),
         "record" => cmd_record(&args[2..]),
         "query" => cmd_query(&args[2..]),
+        "compare" => cmd_compare(&args[2..]),
+        "anomalies" => cmd_anomalies(&args[2..]),
+        "alerts" => cmd_alerts(&args[2..]),
+        "catalog" => cmd_catalog(&args[2..]),
         "help" | "--help" | "-h" => print_usage(),
         other => {
             eprintln!("unknown command: {}", other);
@@ -37,7 +41,25 @@ fn print_usage() {
     eprintln!(
         "  traqq record --addr 127.0.0.1:9876 --event '{{\"event\":\"purchase\",\"amount\":99}}'"
     );
-    eprintln!("  traqq query  --addr 127.0.0.1:9876 --days 10");
+    eprintln!(
+        "  traqq query  --addr 127.0.0.1:9876 --days 10 [--granularity minute|hour|day|week|month] [--top N]"
+    );
+    eprintln!(
+        "  traqq query  --addr 127.0.0.1:9876 --range 'last 7d'|yesterday|2026-09|... [--auto]"
+    );
+    eprintln!("  traqq query  --addr 127.0.0.1:9876 --series add/event --fields sale,click");
+    eprintln!(
+        "  traqq query  --addr 127.0.0.1:9876 --stream --range 2025-01..2025-12 [--offset N] [--limit N]"
+    );
+    eprintln!(
+        "  traqq query  --addr 127.0.0.1:9876 --ql 'sum add/event~geo group by geo last 7d top 10'"
+    );
+    eprintln!("  traqq compare --addr 127.0.0.1:9876 --range this_week --previous last_week");
+    eprintln!(
+        "  traqq anomalies --addr 127.0.0.1:9876 --metric add/event --fields purchase [--range today]"
+    );
+    eprintln!("  traqq alerts --addr 127.0.0.1:9876");
+    eprintln!("  traqq catalog --addr 127.0.0.1:9876 [--range today]");
 }
 
 fn cmd_serve(args: &[String]) {
@@ -158,6 +180,16 @@ fn cmd_record(args: &[String]) {
 fn cmd_query(args: &[String]) {
     let mut addr = DEFAULT_ADDR.to_string();
     let mut days: i32 = 7;
+    let mut range: Option<String> = None;
+    let mut top: Option<usize> = None;
+    let mut series: Option<String> = None;
+    let mut ql: Option<String> = None;
+    let mut fields: Vec<String> = Vec::new();
+    let mut granularity = BucketType::Daily;
+    let mut stream = false;
+    let mut auto = false;
+    let mut offset: usize = 0;
+    let mut limit: Option<usize> = None;
 
     let mut i = 0;
     while i < args.len() {
@@ -170,6 +202,49 @@ fn cmd_query(args: &[String]) {
                 i += 1;
                 days = args.get(i).and_then(|s| s.parse().ok()).unwrap_or(days);
             }
+            "--top" | "-t" => {
+                i += 1;
+                top = args.get(i).and_then(|s| s.parse().ok()).or(top);
+            }
+            "--series" => {
+                i += 1;
+                series = args.get(i).cloned().or(series);
+            }
+            "--ql" | "-q" => {
+                i += 1;
+                ql = args.get(i).cloned().or(ql);
+            }
+            "--fields" | "-f" => {
+                i += 1;
+                if let Some(list) = args.get(i) {
+                    fields = list.split(',').map(String::from).collect();
+                }
+            }
+            "--range" | "-r" => {
+                i += 1;
+                range = args.get(i).cloned().or(range);
+            }
+            "--stream" => stream = true,
+            "--auto" => auto = true,
+            "--offset" => {
+                i += 1;
+                offset = args.get(i).and_then(|s| s.parse().ok()).unwrap_or(offset);
+            }
+            "--limit" | "-l" => {
+                i += 1;
+                limit = args.get(i).and_then(|s| s.parse().ok()).or(limit);
+            }
+            "--granularity" | "-g" => {
+                i += 1;
+                granularity = match args.get(i).map(|s| s.parse()) {
+                    Some(Ok(g)) => g,
+                    Some(Err(e)) => {
+                        eprintln!("{}", e);
+                        process::exit(1);
+                    }
+                    None => granularity,
+                };
+            }
             _ => {}
         }
         i += 1;
@@ -183,8 +258,71 @@ fn cmd_query(args: &[String]) {
         }
     };
 
-    match client.query_days(days) {
+    if let Some(query) = ql {
+        match client.ql(&query) {
+            Ok(r) if r.success => {
+                for w in &r.warnings {
+                    eprintln!("warning: {}", w);
+                }
+                print_table(r.data.unwrap_or_default());
+            }
+            Ok(r) => eprintln!("error: {}", r.error.unwrap_or_default()),
+            Err(e) => eprintln!("error: {}", e),
+        }
+        return;
+    }
+
+    if let Some(metric) = series {
+        // --days N covers today and the N days before it, like query_days
+        let range = range.unwrap_or_else(|| format!("last {}d", days + 1));
+        match client.series(&metric, &fields, &range, granularity) {
+            Ok(r) if r.success => print_series(r.data.unwrap_or_default()),
+            Ok(r) => eprintln!("error: {}", r.error.unwrap_or_default()),
+            Err(e) => eprintln!("error: {}", e),
+        }
+        return;
+    }
+
+    if stream {
+        // one bucket per line, printed as it arrives
+        let range = range.unwrap_or_else(|| format!("last {}d", days + 1));
+        let page = (offset > 0 || limit.is_some()).then(|| traqq::stream::Page {
+            offset,
+            limit: limit.unwrap_or(usize::MAX),
+        });
+        let chunks = match client.query_stream(&range, granularity, page) {
+            Ok(chunks) => chunks,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                process::exit(1);
+            }
+        };
+        for chunk in chunks {
+            match chunk {
+                Ok(r) => {
+                    for w in &r.warnings {
+                        eprintln!("warning: {}", w);
+                    }
+                    println!("{}", serde_json::to_string(&r.data).unwrap_or_default());
+                }
+                Err(e) => eprintln!("error: {}", e),
+            }
+        }
+        return;
+    }
+
+    let response = match &range {
+        Some(range) if auto => client.query_auto(range, top),
+        Some(range) => client.query_range(range, granularity, top),
+        None if auto => client.query_auto(&format!("last {}d", days + 1), top),
+        None => client.query_days_by(days, granularity, top),
+    };
+
+    match response {
         Ok(r) if r.success => {
+            for w in &r.warnings {
+                eprintln!("warning: {}", w);
+            }
             let json = serde_json::to_string_pretty(&r.data).unwrap_or_default();
             println!("{}", json);
         }
@@ -192,3 +330,258 @@ fn cmd_query(args: &[String]) {
         Err(e) => eprintln!("error: {}", e),
     }
 }
+
+fn cmd_compare(args: &[String]) {
+    let mut addr = DEFAULT_ADDR.to_string();
+    let mut range = "this_week".to_string();
+    let mut previous = "last_week".to_string();
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--addr" | "-a" => {
+                i += 1;
+                addr = args.get(i).cloned().unwrap_or(addr);
+            }
+            "--range" | "-r" => {
+                i += 1;
+                range = args.get(i).cloned().unwrap_or(range);
+            }
+            "--previous" | "-p" => {
+                i += 1;
+                previous = args.get(i).cloned().unwrap_or(previous);
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    let mut client = match traqq::client::Client::connect(&addr) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("failed to connect to {}: {}", addr, e);
+            process::exit(1);
+        }
+    };
+
+    match client.compare(&range, &previous) {
+        Ok(r) if r.success => {
+            for w in &r.warnings {
+                eprintln!("warning: {}", w);
+            }
+            let json = serde_json::to_string_pretty(&r.data).unwrap_or_default();
+            println!("{}", json);
+        }
+        Ok(r) => eprintln!("error: {}", r.error.unwrap_or_default()),
+        Err(e) => eprintln!("error: {}", e),
+    }
+}
+
+fn cmd_catalog(args: &[String]) {
+    let mut addr = DEFAULT_ADDR.to_string();
+    let mut range = "today".to_string();
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--addr" | "-a" => {
+                i += 1;
+                addr = args.get(i).cloned().unwrap_or(addr);
+            }
+            "--range" | "-r" => {
+                i += 1;
+                range = args.get(i).cloned().unwrap_or(range);
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    let mut client = match traqq::client::Client::connect(&addr) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("failed to connect to {}: {}", addr, e);
+            process::exit(1);
+        }
+    };
+
+    match client.catalog(&range) {
+        Ok(r) if r.success => {
+            let json = serde_json::to_string_pretty(&r.data).unwrap_or_default();
+            println!("{}", json);
+        }
+        Ok(r) => eprintln!("error: {}", r.error.unwrap_or_default()),
+        Err(e) => eprintln!("error: {}", e),
+    }
+}
+
+/// exits with status 2 when any point is flagged, so cron jobs can act on it
+fn cmd_anomalies(args: &[String]) {
+    let mut addr = DEFAULT_ADDR.to_string();
+    let mut metric: Option<String> = None;
+    let mut fields: Vec<String> = Vec::new();
+    let mut range = "today".to_string();
+    let mut granularity = BucketType::Daily;
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--addr" | "-a" => {
+                i += 1;
+                addr = args.get(i).cloned().unwrap_or(addr);
+            }
+            "--metric" | "-m" => {
+                i += 1;
+                metric = args.get(i).cloned().or(metric);
+            }
+            "--fields" | "-f" => {
+                i += 1;
+                if let Some(list) = args.get(i) {
+                    fields = list.split(',').map(String::from).collect();
+                }
+            }
+            "--range" | "-r" => {
+                i += 1;
+                range = args.get(i).cloned().unwrap_or(range);
+            }
+            "--granularity" | "-g" => {
+                i += 1;
+                granularity = match args.get(i).map(|s| s.parse()) {
+                    Some(Ok(g)) => g,
+                    Some(Err(e)) => {
+                        eprintln!("{}", e);
+                        process::exit(1);
+                    }
+                    None => granularity,
+                };
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    let Some(metric) = metric else {
+        eprintln!("--metric is required");
+        process::exit(1);
+    };
+
+    let mut client = match traqq::client::Client::connect(&addr) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("failed to connect to {}: {}", addr, e);
+            process::exit(1);
+        }
+    };
+
+    let reports: Vec<traqq::anomaly::Report> =
+        match client.anomalies(&metric, &fields, &range, granularity) {
+            Ok(r) if r.success => match serde_json::from_value(r.data.unwrap_or_default()) {
+                Ok(reports) => reports,
+                Err(e) => {
+                    eprintln!("invalid anomalies response: {}", e);
+                    process::exit(1);
+                }
+            },
+            Ok(r) => {
+                eprintln!("error: {}", r.error.unwrap_or_default());
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("error: {}", e);
+                process::exit(1);
+            }
+        };
+
+    let json = serde_json::to_string_pretty(&reports).unwrap_or_default();
+    println!("{}", json);
+    if reports.iter().any(|r| !r.anomalies.is_empty()) {
+        process::exit(2);
+    }
+}
+
+/// print alert firings as the server sends them, until it goes away
+fn cmd_alerts(args: &[String]) {
+    let mut addr = DEFAULT_ADDR.to_string();
+
+    let mut i = 0;
+    while i < args.len() {
+        if let "--addr" | "-a" = args[i].as_str() {
+            i += 1;
+            addr = args.get(i).cloned().unwrap_or(addr);
+        }
+        i += 1;
+    }
+
+    let client = match traqq::client::Client::connect(&addr) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("failed to connect to {}: {}", addr, e);
+            process::exit(1);
+        }
+    };
+
+    let firings = match client.subscribe_alerts() {
+        Ok(firings) => firings,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    };
+    for firing in firings {
+        match firing {
+            Ok(firing) => println!("{}\t{}", firing.timestamp, firing.message),
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+}
+
+/// print a query language table as tab-separated columns: the bucket when
+/// there is one, each group by dimension, then the value
+fn print_table(data: serde_json::Value) {
+    let table: traqq::ql::Table = match serde_json::from_value(data) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("invalid query response: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let bucketed = table.rows.iter().any(|r| r.bucket.is_some());
+    let mut header: Vec<&str> = Vec::new();
+    if bucketed {
+        header.push("timestamp");
+    }
+    header.extend(table.group_by.iter().map(String::as_str));
+    header.push("value");
+    println!("{}", header.join("\t"));
+
+    for row in &table.rows {
+        let mut columns: Vec<String> = Vec::new();
+        if let Some(bucket) = row.bucket {
+            columns.push(bucket.to_string());
+        }
+        columns.extend(row.group.iter().cloned());
+        columns.push(row.value.to_string());
+        println!("{}", columns.join("\t"));
+    }
+}
+
+/// print series as tab-separated columns: timestamp, then one per field
+fn print_series(data: serde_json::Value) {
+    let series: Vec<Series> = match serde_json::from_value(data) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("invalid series response: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let header: Vec<&str> = series.iter().map(|s| s.field.as_str()).collect();
+    println!("timestamp\t{}", header.join("\t"));
+
+    let rows = series.first().map_or(0, |s| s.points.len());
+    for row in 0..rows {
+        let values: Vec<String> = series.iter().map(|s| s.points[row].1.to_string()).collect();
+        println!("{}\t{}", series[0].points[row].0, values.join("\t"));
+    }
+}
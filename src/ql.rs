@@ -0,0 +1,600 @@
+//! a small text query language for ad-hoc analysis.
+//!
+//! ```text
+//! sum adv/event~geo/amount where event=purchase group by geo last 7d top 10
+//! ```
+//!
+//! `<aggregate> <metric> [where <dim>=<value> [and ...]] [group by <dim>[, ...]]
+//! [<range>] [per <granularity>] [top <n>]`
+//!
+//! - aggregates: `sum`, `count`, `avg` (adv summaries) and `uniques` (bmp)
+//! - metrics use the `find_str` shape, `type/pattern` or `adv/pattern/add_key`
+//! - dimensions are the segments of the metric's pattern, values may be globs
+//! - the range is any range expression, `today` by default. the range is
+//!   merged into one row per group unless `per day` (or hour, week, ...)
+//!   asks for a row per bucket
+//! - `top n` keeps the n largest groups, per bucket
+
+use std::collections::HashMap;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    query, range, BucketType, FindOptions, MetricData, MetricResult, MetricType, QueryFilter,
+    QueryResult, Traqq,
+};
+
+const KEYWORDS: [&str; 6] = ["where", "and", "group", "by", "per", "top"];
+
+/// how matching values are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregate {
+    Sum,
+    Count,
+    Avg,
+    Uniques,
+}
+
+/// a parsed query
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub aggregate: Aggregate,
+    pub metric_type: MetricType,
+    pub pattern: String,
+    /// adv only
+    pub add_key: Option<String>,
+    /// (dimension, value glob) pairs that must all match
+    pub filters: Vec<(String, String)>,
+    pub group_by: Vec<String>,
+    pub range: Option<String>,
+    pub per: Option<BucketType>,
+    pub top: Option<usize>,
+}
+
+/// one group's value, for a bucket or the whole range
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Row {
+    /// bucket start with `per`, None when the range is merged
+    pub bucket: Option<i64>,
+    /// values of the `group by` dimensions, in order
+    pub group: Vec<String>,
+    pub value: f64,
+}
+
+/// the rows a query produced, largest first within each bucket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Table {
+    /// the `group by` dimensions, naming the `group` columns
+    pub group_by: Vec<String>,
+    pub rows: Vec<Row>,
+    pub warnings: Vec<String>,
+}
+
+struct Token<'a> {
+    text: &'a str,
+    /// byte offset in the query
+    at: usize,
+}
+
+impl Token<'_> {
+    fn is(&self, keyword: &str) -> bool {
+        self.text.eq_ignore_ascii_case(keyword)
+    }
+
+    fn is_keyword(&self) -> bool {
+        KEYWORDS.iter().any(|k| self.is(k))
+    }
+}
+
+/// split on whitespace, with `=` and `,` as tokens of their own
+fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        if c.is_whitespace() || c == '=' || c == ',' {
+            if let Some(s) = start.take() {
+                tokens.push(Token {
+                    text: &text[s..i],
+                    at: s,
+                });
+            }
+            if !c.is_whitespace() {
+                tokens.push(Token {
+                    text: &text[i..i + 1],
+                    at: i,
+                });
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        tokens.push(Token {
+            text: &text[s..],
+            at: s,
+        });
+    }
+    tokens
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.pos)
+    }
+
+    /// an error pointing at byte offset `at` of the query
+    fn error_at(&self, at: usize, message: &str) -> String {
+        let column = self.text[..at].chars().count();
+        format!(
+            "{} (column {})\n  {}\n  {}^",
+            message,
+            column + 1,
+            self.text,
+            " ".repeat(column)
+        )
+    }
+
+    /// "expected `what`", pointing at the current token
+    fn expected(&self, what: &str) -> String {
+        match self.peek() {
+            Some(token) => self.error_at(
+                token.at,
+                &format!("expected {}, found '{}'", what, token.text),
+            ),
+            None => self.error_at(
+                self.text.len(),
+                &format!("expected {}, found end of query", what),
+            ),
+        }
+    }
+
+    /// the next token, which must not be a keyword or punctuation
+    fn word(&mut self, what: &str) -> Result<&'a str, String> {
+        match self.peek() {
+            Some(token) if !token.is_keyword() && token.text != "=" && token.text != "," => {
+                let text = token.text;
+                self.pos += 1;
+                Ok(text)
+            }
+            _ => Err(self.expected(what)),
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<(), String> {
+        match self.peek() {
+            Some(token) if token.is(keyword) => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => Err(self.expected(&format!("'{}'", keyword))),
+        }
+    }
+
+    fn duplicate(&self, seen: bool) -> Result<(), String> {
+        match self.peek() {
+            Some(token) if seen => Err(self.error_at(
+                token.at,
+                &format!("'{}' appears more than once", token.text.to_lowercase()),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// parse a query into a statement; errors point at the offending column
+pub fn parse(text: &str) -> Result<Statement, String> {
+    let mut p = Parser {
+        text,
+        tokens: tokenize(text),
+        pos: 0,
+    };
+
+    let aggregate = match p.peek().map(|t| t.text.to_lowercase()).as_deref() {
+        Some("sum") => Aggregate::Sum,
+        Some("count") => Aggregate::Count,
+        Some("avg") => Aggregate::Avg,
+        Some("uniques") => Aggregate::Uniques,
+        _ => return Err(p.expected("an aggregate (sum, count, avg or uniques)")),
+    };
+    p.pos += 1;
+
+    let metric_at = p.peek().map_or(text.len(), |t| t.at);
+    let metric = p.word("a metric such as add/event or adv/event~geo/amount")?;
+    let parts: Vec<&str> = metric.split('/').collect();
+    let metric_type = parts[0]
+        .parse::<MetricType>()
+        .map_err(|e| p.error_at(metric_at, &e))?;
+    let (pattern, add_key) = match (metric_type, parts.as_slice()) {
+        (MetricType::Adv, [_, pattern, add_key]) if !pattern.is_empty() && !add_key.is_empty() => {
+            (pattern.to_string(), Some(add_key.to_string()))
+        }
+        (MetricType::Adv, _) => {
+            return Err(p.error_at(
+                metric_at,
+                &format!("expected adv/<pattern>/<add_key>, found '{}'", metric),
+            ))
+        }
+        (_, [_, pattern]) if !pattern.is_empty() => (pattern.to_string(), None),
+        _ => {
+            return Err(p.error_at(
+                metric_at,
+                &format!("expected {}/<pattern>, found '{}'", metric_type, metric),
+            ))
+        }
+    };
+
+    let mut statement = Statement {
+        aggregate,
+        metric_type,
+        pattern,
+        add_key,
+        filters: Vec::new(),
+        group_by: Vec::new(),
+        range: None,
+        per: None,
+        top: None,
+    };
+
+    let (mut seen_where, mut seen_group) = (false, false);
+    while let Some(token) = p.peek() {
+        if token.is("where") {
+            p.duplicate(seen_where)?;
+            seen_where = true;
+            p.pos += 1;
+            loop {
+                let dimension = p.word("a dimension")?;
+                p.keyword("=")?;
+                let value = p.word("a value")?;
+                statement
+                    .filters
+                    .push((dimension.to_string(), value.to_string()));
+                match p.peek() {
+                    Some(token) if token.is("and") => p.pos += 1,
+                    _ => break,
+                }
+            }
+        } else if token.is("group") {
+            p.duplicate(seen_group)?;
+            seen_group = true;
+            p.pos += 1;
+            p.keyword("by")?;
+            loop {
+                let dimension = p.word("a dimension")?;
+                statement.group_by.push(dimension.to_string());
+                match p.peek() {
+                    Some(token) if token.text == "," => p.pos += 1,
+                    _ => break,
+                }
+            }
+        } else if token.is("top") {
+            p.duplicate(statement.top.is_some())?;
+            p.pos += 1;
+            let n = p
+                .peek()
+                .and_then(|t| t.text.parse::<usize>().ok())
+                .filter(|&n| n > 0)
+                .ok_or_else(|| p.expected("a number of rows"))?;
+            p.pos += 1;
+            statement.top = Some(n);
+        } else if token.is("per") {
+            p.duplicate(statement.per.is_some())?;
+            p.pos += 1;
+            let granularity = p
+                .peek()
+                .and_then(|t| t.text.to_lowercase().parse::<BucketType>().ok())
+                .ok_or_else(|| p.expected("a granularity (minute, hour, day, week or month)"))?;
+            p.pos += 1;
+            statement.per = Some(granularity);
+        } else if statement.range.is_none() && !token.is_keyword() && token.text != "=" {
+            // everything up to the next clause is the range expression
+            let mut words = Vec::new();
+            while let Some(token) = p.peek() {
+                if token.is_keyword() {
+                    break;
+                }
+                words.push(token.text);
+                p.pos += 1;
+            }
+            statement.range = Some(words.join(" "));
+        } else {
+            return Err(p.expected("where, group by, a range, per or top"));
+        }
+    }
+
+    check_aggregate(&statement)?;
+    Ok(statement)
+}
+
+/// reject aggregates the metric type can't answer
+fn check_aggregate(s: &Statement) -> Result<(), String> {
+    let metric = s.metric_type;
+    match (s.aggregate, metric) {
+        (Aggregate::Uniques | Aggregate::Count, MetricType::Bmp)
+        | (Aggregate::Sum | Aggregate::Count, MetricType::Add)
+        | (Aggregate::Sum | Aggregate::Count | Aggregate::Avg, MetricType::Adv)
+        | (Aggregate::Sum, MetricType::Top) => {}
+        (aggregate, _) => {
+            let supported = match metric {
+                MetricType::Bmp => "uniques or count",
+                MetricType::Add => "sum or count",
+                MetricType::Adv => "sum, count or avg",
+                MetricType::Top => "sum",
+            };
+            return Err(format!(
+                "{} is not supported for {} metrics (use {})",
+                format!("{:?}", aggregate).to_lowercase(),
+                metric,
+                supported
+            ));
+        }
+    }
+
+    let dimensional = !s.filters.is_empty() || !s.group_by.is_empty();
+    if dimensional && metric == MetricType::Bmp {
+        return Err("bmp unique counts can't be filtered or grouped by dimension".to_string());
+    }
+    if dimensional && metric == MetricType::Adv && s.aggregate != Aggregate::Sum {
+        return Err(
+            "adv count and avg come from the pattern's summary, which can't be filtered \
+             or grouped by dimension (use sum)"
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+impl Statement {
+    /// run the statement against `traqq`, reading only its metric's keys
+    pub fn execute(&self, traqq: &Traqq) -> Result<Table, String> {
+        let mapping = &traqq.config.mapping;
+        query::validate_pattern(
+            mapping,
+            self.metric_type,
+            &self.pattern,
+            self.add_key.as_deref(),
+        )?;
+
+        let dimensions: Vec<&str> = self.pattern.split('~').collect();
+        for dimension in self.filters.iter().map(|(d, _)| d).chain(&self.group_by) {
+            if !dimensions.contains(&dimension.as_str()) {
+                return Err(format!(
+                    "unknown dimension '{}': {}/{} has {}",
+                    dimension,
+                    self.metric_type,
+                    self.pattern,
+                    dimensions.join(", ")
+                ));
+            }
+        }
+
+        let range = self.range.as_deref().unwrap_or("today");
+        let (min, max) = range::parse(range, &traqq.config, Utc::now().timestamp())?;
+        let opts = FindOptions {
+            metric_type: self.metric_type,
+            key: self.pattern.clone(),
+            add_key: self.add_key.clone(),
+            merge: self.per.is_none(),
+            top: None,
+        };
+        let result = traqq.query_filtered(
+            min,
+            max,
+            self.per.unwrap_or(BucketType::Daily),
+            &QueryFilter::from_find(&opts),
+        )?;
+
+        // adv per-field hashes and summaries share a pattern
+        let wants_summary = self.metric_type == MetricType::Adv && self.aggregate != Aggregate::Sum;
+        let keep = |r: &&MetricResult| {
+            QueryResult::matches(r, &opts)
+                && (self.metric_type != MetricType::Adv
+                    || matches!(r.result, MetricData::Summary { .. }) == wants_summary)
+        };
+
+        let buckets: Vec<(Option<i64>, Vec<MetricResult>)> = if self.per.is_some() {
+            result
+                .days
+                .iter()
+                .map(|day| {
+                    let found = day.results.iter().filter(keep).cloned().collect();
+                    (Some(day.timestamp), found)
+                })
+                .collect()
+        } else {
+            let found: Vec<MetricResult> = result
+                .days
+                .iter()
+                .flat_map(|day| day.results.iter().filter(keep).cloned())
+                .collect();
+            let merged = if found.is_empty() {
+                Vec::new()
+            } else {
+                let merged = QueryResult::merge_results(&found, opts.metric_type.as_str());
+                vec![result.with_range_totals(merged)]
+            };
+            vec![(None, merged)]
+        };
+
+        let mut rows = Vec::new();
+        for (bucket, results) in buckets {
+            rows.extend(self.rows(bucket, &results));
+        }
+
+        Ok(Table {
+            group_by: self.group_by.clone(),
+            rows,
+            warnings: result.warnings,
+        })
+    }
+
+    /// aggregate one bucket's results into rows
+    fn rows(&self, bucket: Option<i64>, results: &[MetricResult]) -> Vec<Row> {
+        let mut groups: HashMap<Vec<String>, f64> = HashMap::new();
+        if self.group_by.is_empty() {
+            // one total per bucket, zero when nothing matched
+            groups.insert(Vec::new(), 0.0);
+        }
+
+        let group_by: Vec<&str> = self.group_by.iter().map(String::as_str).collect();
+        for result in results {
+            // bmp and summaries are a single value; the parser rejects
+            // filters and groups for them
+            let value = match &result.result {
+                MetricData::Count(c) => Some(*c as f64),
+                MetricData::Summary { sum, count } => Some(match self.aggregate {
+                    Aggregate::Avg if *count > 0 => sum / *count as f64,
+                    Aggregate::Avg => 0.0,
+                    Aggregate::Count => *count as f64,
+                    _ => *sum,
+                }),
+                _ => None,
+            };
+            if let Some(value) = value {
+                *groups.entry(Vec::new()).or_insert(0.0) += value;
+                continue;
+            }
+
+            // dimensions were checked against the pattern in `execute`
+            let drilled = self
+                .filters
+                .iter()
+                .try_fold(result.clone(), |r, (dimension, glob)| {
+                    r.filter_dimension(dimension, glob)
+                })
+                .and_then(|r| r.group_by(&group_by));
+            let Ok(grouped) = drilled else {
+                continue;
+            };
+
+            let fields: Vec<(&str, f64)> = match &grouped.result {
+                MetricData::Hash(h) => h.iter().map(|(k, v)| (k.as_str(), *v as f64)).collect(),
+                MetricData::FloatHash(h) => h.iter().map(|(k, v)| (k.as_str(), *v)).collect(),
+                MetricData::Ranked(pairs) => pairs.iter().map(|(k, v)| (k.as_str(), *v)).collect(),
+                _ => Vec::new(),
+            };
+            for (field, value) in fields {
+                let group: Vec<String> = grouped
+                    .dimensions(field)
+                    .into_iter()
+                    .map(|(_, value)| value.to_string())
+                    .collect();
+                *groups.entry(group).or_insert(0.0) += value;
+            }
+        }
+
+        let mut rows: Vec<Row> = groups
+            .into_iter()
+            .map(|(group, value)| Row {
+                bucket,
+                group,
+                value,
+            })
+            .collect();
+        rows.sort_by(|a, b| {
+            b.value
+                .partial_cmp(&a.value)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| a.group.cmp(&b.group))
+        });
+        if let Some(n) = self.top {
+            rows.truncate(n);
+        }
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_full_statement() {
+        let s = parse("sum adv/event~geo/amount where event=purchase group by geo last 7d top 10")
+            .unwrap();
+        assert_eq!(s.aggregate, Aggregate::Sum);
+        assert_eq!(s.metric_type, MetricType::Adv);
+        assert_eq!(s.pattern, "event~geo");
+        assert_eq!(s.add_key.as_deref(), Some("amount"));
+        assert_eq!(
+            s.filters,
+            vec![("event".to_string(), "purchase".to_string())]
+        );
+        assert_eq!(s.group_by, vec!["geo"]);
+        assert_eq!(s.range.as_deref(), Some("last 7d"));
+        assert_eq!(s.top, Some(10));
+        assert_eq!(s.per, None);
+    }
+
+    #[test]
+    fn test_parse_clauses_in_any_order() {
+        let s = parse("COUNT add/event~geo top 3 per hour group by geo, event where geo = U* and event=sale 2026-09-01..2026-09-14").unwrap();
+        assert_eq!(s.aggregate, Aggregate::Count);
+        assert_eq!(s.group_by, vec!["geo", "event"]);
+        assert_eq!(s.filters.len(), 2);
+        assert_eq!(s.filters[0], ("geo".to_string(), "U*".to_string()));
+        assert_eq!(s.per, Some(BucketType::Hourly));
+        assert_eq!(s.range.as_deref(), Some("2026-09-01..2026-09-14"));
+
+        let s = parse("uniques bmp/ip").unwrap();
+        assert_eq!((s.range, s.top), (None, None));
+    }
+
+    #[test]
+    fn test_parse_errors_point_at_the_problem() {
+        let err = parse("total add/event").unwrap_err();
+        assert!(
+            err.starts_with(
+                "expected an aggregate (sum, count, avg or uniques), found 'total' (column 1)"
+            ),
+            "{}",
+            err
+        );
+
+        let err = parse("sum ad/event").unwrap_err();
+        assert!(err.contains("unknown metric type: 'ad'"), "{}", err);
+        assert!(err.ends_with("\n  sum ad/event\n      ^"), "{}", err);
+
+        let err = parse("sum add/event where event purchase").unwrap_err();
+        assert!(
+            err.contains("expected '=', found 'purchase' (column 27)"),
+            "{}",
+            err
+        );
+
+        let err = parse("sum add/event group geo").unwrap_err();
+        assert!(err.contains("expected 'by', found 'geo'"), "{}", err);
+
+        let err = parse("sum add/event top").unwrap_err();
+        assert!(
+            err.contains("expected a number of rows, found end of query"),
+            "{}",
+            err
+        );
+
+        let err = parse("sum add/event per fortnight").unwrap_err();
+        assert!(err.contains("expected a granularity"), "{}", err);
+
+        let err = parse("sum add/event top 3 top 4").unwrap_err();
+        assert!(err.contains("'top' appears more than once"), "{}", err);
+
+        let err = parse("sum adv/event~geo").unwrap_err();
+        assert!(err.contains("expected adv/<pattern>/<add_key>"), "{}", err);
+
+        let err = parse("avg add/event").unwrap_err();
+        assert_eq!(
+            err,
+            "avg is not supported for add metrics (use sum or count)"
+        );
+
+        let err = parse("avg adv/event~geo/amount group by geo").unwrap_err();
+        assert!(err.contains("use sum"), "{}", err);
+    }
+}
@@ -0,0 +1,399 @@
+//! typed query builder.
+//!
+//! ```ignore
+//! let rows = Query::add("event~geo").range("last 7d").merge().top(10).run(&traqq)?;
+//! for row in rows.rows {
+//!     let purchases: i64 = row.value.get("purchase~US").copied().unwrap_or(0);
+//! }
+//! ```
+//!
+//! the metric type is fixed by the constructor, so the pattern is checked
+//! against the live `MappingConfig` before anything is read and each row
+//! carries the value type of that metric. the string API (`FindOptions`,
+//! `find_str`) is what a query runs through underneath.
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    range, utils, BucketType, FindOptions, MappingConfig, MetricData, MetricResult, MetricType,
+    QueryFilter, QueryResult, Traqq,
+};
+
+/// what a query reads and the value each row holds
+pub trait Kind {
+    const METRIC_TYPE: MetricType;
+    type Value;
+
+    /// the typed value of a result's data, None if it is another kind
+    fn value(data: MetricData) -> Option<Self::Value>;
+
+    /// whether a result's data is of this kind, without taking it
+    fn is(data: &MetricData) -> bool;
+}
+
+/// bmp: unique count
+#[derive(Debug, Clone, Copy)]
+pub struct Uniques;
+/// add: count per field
+#[derive(Debug, Clone, Copy)]
+pub struct Counts;
+/// adv: value total per field
+#[derive(Debug, Clone, Copy)]
+pub struct Sums;
+/// adv: sum and count of every value
+#[derive(Debug, Clone, Copy)]
+pub struct Summaries;
+/// top: members and scores, best first
+#[derive(Debug, Clone, Copy)]
+pub struct Leaderboard;
+
+/// sum and count of an adv value
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    pub sum: f64,
+    pub count: i64,
+}
+
+impl Summary {
+    /// mean value, 0 when nothing was recorded
+    pub fn avg(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum / self.count as f64
+        }
+    }
+}
+
+impl Kind for Uniques {
+    const METRIC_TYPE: MetricType = MetricType::Bmp;
+    type Value = u64;
+
+    fn value(data: MetricData) -> Option<u64> {
+        match data {
+            MetricData::Count(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    fn is(data: &MetricData) -> bool {
+        matches!(data, MetricData::Count(_))
+    }
+}
+
+impl Kind for Counts {
+    const METRIC_TYPE: MetricType = MetricType::Add;
+    type Value = HashMap<String, i64>;
+
+    fn value(data: MetricData) -> Option<Self::Value> {
+        match data {
+            MetricData::Hash(h) => Some(h),
+            _ => None,
+        }
+    }
+
+    fn is(data: &MetricData) -> bool {
+        matches!(data, MetricData::Hash(_))
+    }
+}
+
+impl Kind for Sums {
+    const METRIC_TYPE: MetricType = MetricType::Adv;
+    type Value = HashMap<String, f64>;
+
+    fn value(data: MetricData) -> Option<Self::Value> {
+        match data {
+            MetricData::FloatHash(h) => Some(h),
+            _ => None,
+        }
+    }
+
+    fn is(data: &MetricData) -> bool {
+        matches!(data, MetricData::FloatHash(_))
+    }
+}
+
+impl Kind for Summaries {
+    const METRIC_TYPE: MetricType = MetricType::Adv;
+    type Value = Summary;
+
+    fn value(data: MetricData) -> Option<Summary> {
+        match data {
+            MetricData::Summary { sum, count } => Some(Summary { sum, count }),
+            _ => None,
+        }
+    }
+
+    fn is(data: &MetricData) -> bool {
+        matches!(data, MetricData::Summary { .. })
+    }
+}
+
+impl Kind for Leaderboard {
+    const METRIC_TYPE: MetricType = MetricType::Top;
+    type Value = Vec<(String, f64)>;
+
+    fn value(data: MetricData) -> Option<Self::Value> {
+        match data {
+            MetricData::Ranked(pairs) => Some(pairs),
+            _ => None,
+        }
+    }
+
+    fn is(data: &MetricData) -> bool {
+        matches!(data, MetricData::Ranked(_))
+    }
+}
+
+/// one metric's value for a bucket, or for the whole range when merged
+#[derive(Debug, Clone, Serialize)]
+pub struct Row<V> {
+    /// bucket start, None for a merged row
+    pub bucket: Option<i64>,
+    /// the pattern keys as stored (e.g., ["event", "geo"])
+    pub key: Vec<String>,
+    pub value: V,
+}
+
+/// the rows a query returned, in bucket order
+#[derive(Debug, Clone, Serialize)]
+pub struct Rows<V> {
+    pub rows: Vec<Row<V>>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Span {
+    /// a range expression, see `range`
+    Range(String),
+    /// unix seconds, inclusive
+    Between(i64, i64),
+}
+
+/// a query for one metric, built up and then `run` against a `Traqq`
+#[derive(Debug, Clone)]
+pub struct Query<K: Kind> {
+    pattern: String,
+    add_key: Option<String>,
+    span: Span,
+    granularity: BucketType,
+    merge: bool,
+    top: Option<usize>,
+    kind: PhantomData<K>,
+}
+
+impl Query<Uniques> {
+    /// unique counts for a bitmap pattern
+    pub fn bmp(pattern: &str) -> Self {
+        Self::new(pattern, None)
+    }
+}
+
+impl Query<Counts> {
+    /// counters for an add pattern
+    pub fn add(pattern: &str) -> Self {
+        Self::new(pattern, None)
+    }
+}
+
+impl Query<Sums> {
+    /// per-field totals of `add_key` for an add_value pattern
+    pub fn adv(pattern: &str, add_key: &str) -> Self {
+        Self::new(pattern, Some(add_key))
+    }
+}
+
+impl Query<Summaries> {
+    /// sum and count of `add_key` for an add_value pattern
+    pub fn summary(pattern: &str, add_key: &str) -> Self {
+        Self::new(pattern, Some(add_key))
+    }
+}
+
+impl Query<Leaderboard> {
+    /// a top pattern's leaderboard; `top(n)` limits its depth
+    pub fn leaderboard(pattern: &str) -> Self {
+        Self::new(pattern, None)
+    }
+}
+
+impl<K: Kind> Query<K> {
+    fn new(pattern: &str, add_key: Option<&str>) -> Self {
+        Self {
+            pattern: pattern.to_string(),
+            add_key: add_key.map(String::from),
+            span: Span::Range("today".to_string()),
+            granularity: BucketType::Daily,
+            merge: false,
+            top: None,
+            kind: PhantomData,
+        }
+    }
+
+    /// read a range expression such as `last 7d` or `2026-09` (default `today`)
+    pub fn range(mut self, expr: &str) -> Self {
+        self.span = Span::Range(expr.to_string());
+        self
+    }
+
+    /// read the buckets from `min` to `max` (unix seconds)
+    pub fn between(mut self, min: i64, max: i64) -> Self {
+        self.span = Span::Between(min, max);
+        self
+    }
+
+    /// bucket size to read (default daily)
+    pub fn granularity(mut self, granularity: BucketType) -> Self {
+        self.granularity = granularity;
+        self
+    }
+
+    /// merge the buckets into one row per metric
+    pub fn merge(mut self) -> Self {
+        self.merge = true;
+        self
+    }
+
+    /// keep the first `n` leaderboard entries; no effect on other kinds
+    pub fn top(mut self, n: usize) -> Self {
+        self.top = Some(n);
+        self
+    }
+
+    /// the equivalent string-typed find
+    pub fn find_options(&self) -> FindOptions {
+        FindOptions {
+            metric_type: K::METRIC_TYPE,
+            key: self.pattern.clone(),
+            add_key: self.add_key.clone(),
+            merge: self.merge,
+            top: self.top,
+        }
+    }
+
+    /// check the pattern is one `mapping` records
+    pub fn validate(&self, mapping: &MappingConfig) -> Result<(), String> {
+        validate_pattern(
+            mapping,
+            K::METRIC_TYPE,
+            &self.pattern,
+            self.add_key.as_deref(),
+        )
+    }
+
+    /// validate against the live config, read only this metric's keys and
+    /// return its typed rows
+    pub fn run(&self, traqq: &Traqq) -> Result<Rows<K::Value>, String> {
+        self.validate(&traqq.config.mapping)?;
+
+        let (min, max) = match &self.span {
+            Span::Range(expr) => range::parse(expr, &traqq.config, Utc::now().timestamp())?,
+            Span::Between(min, max) => (*min, *max),
+        };
+        let opts = self.find_options();
+        let filter = QueryFilter {
+            top: self.top,
+            ..QueryFilter::from_find(&opts)
+        };
+        let result = traqq.query_filtered(min, max, self.granularity, &filter)?;
+        Ok(self.rows(&result, &opts))
+    }
+
+    /// pick this query's metric out of a result
+    fn rows(&self, result: &QueryResult, opts: &FindOptions) -> Rows<K::Value> {
+        // per-field sums and summaries share a pattern; keep the kind asked for
+        let of_kind = |r: &&MetricResult| QueryResult::matches(r, opts) && K::is(&r.result);
+
+        let mut found: Vec<(Option<i64>, MetricResult)> = result
+            .days
+            .iter()
+            .flat_map(|day| {
+                day.results
+                    .iter()
+                    .filter(of_kind)
+                    .map(|r| (Some(day.timestamp), r.clone()))
+            })
+            .collect();
+
+        if self.merge && !found.is_empty() {
+            let results: Vec<MetricResult> = found.into_iter().map(|(_, r)| r).collect();
+            let merged = QueryResult::merge_results(&results, opts.metric_type.as_str());
+            found = vec![(None, result.with_range_totals(merged))];
+        }
+
+        let rows = found
+            .into_iter()
+            .filter_map(|(bucket, mut r)| {
+                if let (Some(limit), MetricData::Ranked(pairs)) = (self.top, &mut r.result) {
+                    pairs.truncate(limit);
+                }
+                Some(Row {
+                    bucket,
+                    key: r.key,
+                    value: K::value(r.result)?,
+                })
+            })
+            .collect();
+
+        Rows {
+            rows,
+            warnings: result.warnings.clone(),
+        }
+    }
+}
+
+/// check that a concrete pattern (and add_key for adv) is one `mapping` records
+pub(crate) fn validate_pattern(
+    mapping: &MappingConfig,
+    metric_type: MetricType,
+    pattern: &str,
+    add_key: Option<&str>,
+) -> Result<(), String> {
+    if pattern.is_empty() || pattern.split('~').any(utils::is_glob) {
+        return Err(format!(
+            "{} query pattern must be concrete, got '{}'",
+            metric_type, pattern
+        ));
+    }
+
+    let declared: Vec<String> = match metric_type {
+        MetricType::Bmp => mapping.bitmap.clone(),
+        MetricType::Add => mapping.add.clone(),
+        MetricType::Top => mapping.top.clone(),
+        MetricType::Adv => mapping
+            .add_value
+            .iter()
+            .map(|c| format!("{}/{}", c.key, c.add_key))
+            .collect(),
+    };
+
+    let found = match metric_type {
+        MetricType::Adv => mapping.add_value.iter().any(|c| {
+            utils::pattern_match(&c.key, pattern)
+                && add_key.is_some_and(|add_key| utils::glob_match(&c.add_key, add_key))
+        }),
+        _ => declared.iter().any(|p| utils::pattern_match(p, pattern)),
+    };
+    if found {
+        return Ok(());
+    }
+
+    let wanted = match add_key {
+        Some(add_key) => format!("{}/{}", pattern, add_key),
+        None => pattern.to_string(),
+    };
+    Err(format!(
+        "no {} mapping matches '{}' (configured: {})",
+        metric_type,
+        wanted,
+        if declared.is_empty() {
+            "none".to_string()
+        } else {
+            declared.join(", ")
+        }
+    ))
+}
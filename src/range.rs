@@ -0,0 +1,192 @@
+//! calendar range expressions, resolved in the configured timezone.
+//!
+//! supported forms:
+//! - `2026-09-14` a single day
+//! - `2026-09` a calendar month
+//! - `2026-09-01..2026-09-14` an inclusive range of days or months
+//! - `today`, `yesterday`
+//! - `last 7d` the last N days, ending today
+//! - `this_week`, `last_week` ISO weeks (this week runs through today)
+//! - `this_month` / `mtd`, `last_month`
+
+use chrono::NaiveDate;
+
+use crate::{utils, BucketType, TraqqConfig};
+
+const EXPECTED: &str = "expected YYYY-MM-DD, YYYY-MM, <start>..<end>, today, yesterday, \
+                        last Nd, this_week, last_week, this_month, last_month or mtd";
+
+/// resolve a range expression relative to `now` (unix seconds).
+/// returns (min, max): the start of the first day and the last second of the
+/// last day, ready for `Traqq::query_by`.
+pub fn parse(expr: &str, config: &TraqqConfig, now: i64) -> Result<(i64, i64), String> {
+    let normalized = expr.trim().to_lowercase().replace(['_', ' '], "");
+    let today = config.bucket_start(now, BucketType::Daily)?;
+
+    let (start, last_day) = match normalized.as_str() {
+        "today" => (today, today),
+        "yesterday" => {
+            let yesterday = config.day_offset(today, -1)?;
+            (yesterday, yesterday)
+        }
+        "thisweek" => (config.bucket_start(today, BucketType::Weekly)?, today),
+        "lastweek" => {
+            let this_week = config.bucket_start(today, BucketType::Weekly)?;
+            let last_day = config.day_offset(this_week, -1)?;
+            (config.bucket_start(last_day, BucketType::Weekly)?, last_day)
+        }
+        "thismonth" | "mtd" => (config.bucket_start(today, BucketType::Monthly)?, today),
+        "lastmonth" => {
+            let this_month = config.bucket_start(today, BucketType::Monthly)?;
+            let last_day = config.day_offset(this_month, -1)?;
+            (
+                config.bucket_start(last_day, BucketType::Monthly)?,
+                last_day,
+            )
+        }
+        other => {
+            if let Some(days) = other.strip_prefix("last").and_then(|n| n.strip_suffix('d')) {
+                let days: i64 = days
+                    .parse()
+                    .ok()
+                    .filter(|&d| d > 0)
+                    .ok_or_else(|| invalid(expr))?;
+                (config.day_offset(today, 1 - days)?, today)
+            } else if let Some((from, to)) = other.split_once("..") {
+                let (start, _) = calendar_span(from, config).ok_or_else(|| invalid(expr))?;
+                let (_, last_day) = calendar_span(to, config).ok_or_else(|| invalid(expr))?;
+                if last_day < start {
+                    return Err(format!("invalid range: '{}' ends before it starts", expr));
+                }
+                (start, last_day)
+            } else {
+                calendar_span(other, config).ok_or_else(|| invalid(expr))?
+            }
+        }
+    };
+
+    let end = config.next_bucket_start(last_day, BucketType::Daily)?;
+    Ok((start, end - 1))
+}
+
+fn invalid(expr: &str) -> String {
+    format!("invalid range: '{}' ({})", expr, EXPECTED)
+}
+
+/// first and last day of an ISO date (`YYYY-MM-DD`) or month (`YYYY-MM`)
+fn calendar_span(text: &str, config: &TraqqConfig) -> Option<(i64, i64)> {
+    if let Ok(date) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        let day = local_midnight(date, config)?;
+        return Some((day, day));
+    }
+
+    let first = NaiveDate::parse_from_str(&format!("{}-01", text), "%Y-%m-%d").ok()?;
+    let start = local_midnight(first, config)?;
+    let next_month = config.next_bucket_start(start, BucketType::Monthly).ok()?;
+    let last_day = config.day_offset(next_month, -1).ok()?;
+    Some((start, last_day))
+}
+
+/// start of a calendar date in the configured timezone
+fn local_midnight(date: NaiveDate, config: &TraqqConfig) -> Option<i64> {
+    let tz = utils::parse_timezone(&config.time.timezone).ok()?;
+    date.and_hms_opt(0, 0, 0)?
+        .and_local_timezone(tz)
+        .earliest()
+        .map(|dt| dt.timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn config(timezone: &str) -> TraqqConfig {
+        let mut config = TraqqConfig::default();
+        config.time.timezone = timezone.to_string();
+        config
+    }
+
+    fn utc(y: i32, m: u32, d: u32) -> i64 {
+        chrono::Utc
+            .with_ymd_and_hms(y, m, d, 0, 0, 0)
+            .unwrap()
+            .timestamp()
+    }
+
+    // wednesday 2026-09-16, midday UTC
+    fn now() -> i64 {
+        utc(2026, 9, 16) + 12 * 3600
+    }
+
+    #[test]
+    fn test_relative_ranges() {
+        let c = config("UTC");
+        let cases = [
+            ("today", utc(2026, 9, 16), utc(2026, 9, 17)),
+            ("yesterday", utc(2026, 9, 15), utc(2026, 9, 16)),
+            ("last 7d", utc(2026, 9, 10), utc(2026, 9, 17)),
+            ("last_1d", utc(2026, 9, 16), utc(2026, 9, 17)),
+            ("this_week", utc(2026, 9, 14), utc(2026, 9, 17)),
+            ("last_week", utc(2026, 9, 7), utc(2026, 9, 14)),
+            ("mtd", utc(2026, 9, 1), utc(2026, 9, 17)),
+            ("last_month", utc(2026, 8, 1), utc(2026, 9, 1)),
+        ];
+
+        for (expr, min, end) in cases {
+            assert_eq!(parse(expr, &c, now()), Ok((min, end - 1)), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_calendar_ranges() {
+        let c = config("UTC");
+        let cases = [
+            ("2026-09-14", utc(2026, 9, 14), utc(2026, 9, 15)),
+            ("2026-02", utc(2026, 2, 1), utc(2026, 3, 1)),
+            ("2026-09-01..2026-09-14", utc(2026, 9, 1), utc(2026, 9, 15)),
+            ("2026-01..2026-03", utc(2026, 1, 1), utc(2026, 4, 1)),
+        ];
+
+        for (expr, min, end) in cases {
+            assert_eq!(parse(expr, &c, now()), Ok((min, end - 1)), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_ranges_use_configured_timezone() {
+        let c = config("America/New_York");
+        let ny = chrono_tz::America::New_York;
+
+        // 02:00 UTC on the 17th is still the 16th in new york
+        let (min, max) = parse("today", &c, utc(2026, 9, 17) + 2 * 3600).unwrap();
+        assert_eq!(
+            min,
+            ny.with_ymd_and_hms(2026, 9, 16, 0, 0, 0)
+                .unwrap()
+                .timestamp()
+        );
+        assert_eq!(
+            max + 1,
+            ny.with_ymd_and_hms(2026, 9, 17, 0, 0, 0)
+                .unwrap()
+                .timestamp()
+        );
+
+        // november has an extra hour from the fall-back shift
+        let (min, max) = parse("2026-11", &c, now()).unwrap();
+        assert_eq!(max + 1 - min, 30 * 86400 + 3600);
+    }
+
+    #[test]
+    fn test_invalid_ranges() {
+        let c = config("UTC");
+        for expr in ["", "last", "last 0d", "2026-13", "2026-09-31", "next_month"] {
+            let err = parse(expr, &c, now()).unwrap_err();
+            assert!(err.starts_with("invalid range"), "{}: {}", expr, err);
+        }
+
+        let err = parse("2026-09-14..2026-09-01", &c, now()).unwrap_err();
+        assert!(err.contains("ends before it starts"));
+    }
+}
@@ -1,11 +1,19 @@
+use std::fs::OpenOptions;
 use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::net::{TcpListener, TcpStream};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
 
-use crate::{FindOptions, IncomingEvent, Traqq};
+use crate::alert::{Evaluator, Firing};
+use crate::stream::Page;
+use crate::{
+    constants, range, BucketType, FindOptions, IncomingEvent, MetricType, QueryFilter, QueryResult,
+    Traqq,
+};
 
 /// commands the server accepts, one per line as JSON
 #[derive(Debug, Deserialize)]
@@ -17,19 +25,115 @@ pub enum Command {
     Query {
         min: i64,
         max: i64,
+        #[serde(default)]
+        granularity: BucketType,
+        #[serde(default)]
+        timezone: Option<String>,
+        #[serde(default)]
+        accept: Vec<String>,
+        #[serde(default)]
+        ignore: Vec<String>,
+        #[serde(default)]
+        top: Option<usize>,
+        /// read whole months or weeks from rollups, ignoring `granularity`
+        #[serde(default)]
+        auto: bool,
     },
     QueryDays {
         days: i32,
+        #[serde(default)]
+        granularity: BucketType,
+        #[serde(default)]
+        top: Option<usize>,
+    },
+    QueryRange {
+        range: String,
+        #[serde(default)]
+        granularity: BucketType,
+        #[serde(default)]
+        top: Option<usize>,
+        /// read whole months or weeks from rollups, ignoring `granularity`
+        #[serde(default)]
+        auto: bool,
+    },
+    /// like `query`, answered with one line per bucket and a final `done` line
+    QueryStream {
+        #[serde(default)]
+        min: Option<i64>,
+        #[serde(default)]
+        max: Option<i64>,
+        #[serde(default)]
+        range: Option<String>,
+        #[serde(default)]
+        granularity: BucketType,
+        #[serde(default)]
+        accept: Vec<String>,
+        #[serde(default)]
+        ignore: Vec<String>,
+        #[serde(default)]
+        top: Option<usize>,
+        /// page every hash and leaderboard
+        #[serde(default)]
+        offset: usize,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    Series {
+        metric: String,
+        #[serde(default)]
+        fields: Vec<String>,
+        #[serde(default)]
+        min: Option<i64>,
+        #[serde(default)]
+        max: Option<i64>,
+        #[serde(default)]
+        range: Option<String>,
+        #[serde(default)]
+        granularity: BucketType,
+    },
+    Ql {
+        query: String,
+    },
+    Compare {
+        range: String,
+        previous: String,
+    },
+    Anomalies {
+        metric: String,
+        #[serde(default)]
+        fields: Vec<String>,
+        #[serde(default = "default_range")]
+        range: String,
+        #[serde(default)]
+        granularity: BucketType,
+    },
+    /// metrics tracked per day, without their values
+    Catalog {
+        #[serde(default = "default_range")]
+        range: String,
+    },
+    CacheStats,
+    /// subscribe to alert firings; the connection carries nothing else after
+    Alerts,
+    Compact {
+        #[serde(default)]
+        before: Option<i64>,
     },
     Find {
         min: i64,
         max: i64,
+        #[serde(default)]
+        granularity: BucketType,
+        #[serde(default)]
+        timezone: Option<String>,
         metric_type: String,
         key: String,
         #[serde(default)]
         add_key: Option<String>,
         #[serde(default)]
         merge: bool,
+        #[serde(default)]
+        top: Option<usize>,
     },
 }
 
@@ -41,6 +145,11 @@ pub struct Response {
     pub data: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// set on the last line of a streamed response
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub done: bool,
 }
 
 impl Response {
@@ -49,6 +158,8 @@ impl Response {
             success: true,
             data: Some(data),
             error: None,
+            warnings: Vec::new(),
+            done: false,
         }
     }
 
@@ -57,6 +168,8 @@ impl Response {
             success: true,
             data: None,
             error: None,
+            warnings: Vec::new(),
+            done: false,
         }
     }
 
@@ -65,22 +178,47 @@ impl Response {
             success: false,
             data: None,
             error: Some(msg),
+            warnings: Vec::new(),
+            done: false,
         }
     }
+
+    fn with_warnings(mut self, warnings: Vec<String>) -> Self {
+        self.warnings = warnings;
+        self
+    }
+
+    /// mark the end of a stream
+    fn done(mut self) -> Self {
+        self.done = true;
+        self
+    }
 }
 
+/// connections subscribed to alert firings
+type Subscribers = Arc<Mutex<Vec<mpsc::Sender<Firing>>>>;
+
 /// run the traqq TCP server on the given address.
 /// blocks the calling thread.
 pub fn run(traqq: Arc<Traqq>, addr: &str) -> std::io::Result<()> {
     let listener = TcpListener::bind(addr)?;
     eprintln!("traqq server listening on {}", addr);
 
+    if traqq.config.time.compact_hourly_after > 0 {
+        spawn_compaction(Arc::clone(&traqq));
+    }
+    let subscribers = Subscribers::default();
+    if !traqq.config.alerts.rules.is_empty() {
+        spawn_alerts(Arc::clone(&traqq), Arc::clone(&subscribers));
+    }
+
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
                 let traqq = Arc::clone(&traqq);
+                let subscribers = Arc::clone(&subscribers);
                 thread::spawn(move || {
-                    if let Err(e) = handle_connection(stream, &traqq) {
+                    if let Err(e) = handle_connection(stream, &traqq, &subscribers) {
                         eprintln!("connection error: {}", e);
                     }
                 });
@@ -92,7 +230,71 @@ pub fn run(traqq: Arc<Traqq>, addr: &str) -> std::io::Result<()> {
     Ok(())
 }
 
-fn handle_connection(stream: TcpStream, traqq: &Traqq) -> std::io::Result<()> {
+/// compact old hourly buckets in the background every `COMPACTION_INTERVAL` seconds
+fn spawn_compaction(traqq: Arc<Traqq>) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(constants::COMPACTION_INTERVAL));
+        match traqq.compact_expired() {
+            Ok(result) => {
+                if result.buckets > 0 {
+                    eprintln!(
+                        "compacted {} hourly buckets ({} keys)",
+                        result.buckets, result.keys
+                    );
+                }
+                for w in &result.warnings {
+                    eprintln!("compaction: {}", w);
+                }
+            }
+            Err(e) => eprintln!("compaction error: {}", e),
+        }
+    });
+}
+
+/// evaluate the alert rules every `alerts.interval` seconds, sending each
+/// firing to stderr, the alerts file and subscribed connections
+fn spawn_alerts(traqq: Arc<Traqq>, subscribers: Subscribers) {
+    thread::spawn(move || {
+        let config = &traqq.config.alerts;
+        let mut evaluator = Evaluator::new(config.rules.clone());
+        loop {
+            thread::sleep(Duration::from_secs(config.interval.max(1)));
+            let evaluation = evaluator.evaluate(&traqq, Utc::now().timestamp());
+            for e in &evaluation.errors {
+                eprintln!("alert evaluation failed: {}", e);
+            }
+            for firing in &evaluation.firings {
+                publish(firing, config.file.as_deref(), &subscribers);
+            }
+        }
+    });
+}
+
+fn publish(firing: &Firing, file: Option<&str>, subscribers: &Subscribers) {
+    eprintln!("alert: {}", firing.message);
+
+    if let Some(path) = file {
+        let appended = serde_json::to_string(firing)
+            .map_err(std::io::Error::other)
+            .and_then(|line| {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{}", line)
+            });
+        if let Err(e) = appended {
+            eprintln!("failed to append alert to {}: {}", path, e);
+        }
+    }
+
+    // a closed connection drops its receiver
+    let mut subscribers = subscribers.lock().unwrap_or_else(|e| e.into_inner());
+    subscribers.retain(|tx| tx.send(firing.clone()).is_ok());
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    traqq: &Traqq,
+    subscribers: &Subscribers,
+) -> std::io::Result<()> {
     let reader = BufReader::new(&stream);
     let mut writer = BufWriter::new(&stream);
 
@@ -102,21 +304,107 @@ fn handle_connection(stream: TcpStream, traqq: &Traqq) -> std::io::Result<()> {
             continue;
         }
 
-        let response = match serde_json::from_str::<Command>(&line) {
-            Ok(cmd) => dispatch(cmd, traqq),
-            Err(e) => Response::err(format!("invalid command: {}", e)),
-        };
-
-        let json = serde_json::to_string(&response)
-            .unwrap_or_else(|e| format!("{{\"success\":false,\"error\":\"{}\"}}", e));
-        writer.write_all(json.as_bytes())?;
-        writer.write_all(b"\n")?;
-        writer.flush()?;
+        match serde_json::from_str::<Command>(&line) {
+            Ok(Command::QueryStream {
+                min,
+                max,
+                range,
+                granularity,
+                accept,
+                ignore,
+                top,
+                offset,
+                limit,
+            }) => {
+                let filter = QueryFilter {
+                    accept,
+                    ignore,
+                    top,
+                    ..QueryFilter::default()
+                };
+                let page = (offset > 0 || limit.is_some()).then(|| Page {
+                    offset,
+                    limit: limit.unwrap_or(usize::MAX),
+                });
+                let span = resolve_range(traqq, min, max, range);
+                stream_query(&mut writer, traqq, span, granularity, &filter, page)?;
+            }
+            Ok(Command::Alerts) => return subscribe(&mut writer, subscribers),
+            Ok(cmd) => write_response(&mut writer, &dispatch(cmd, traqq))?,
+            Err(e) => write_response(
+                &mut writer,
+                &Response::err(format!("invalid command: {}", e)),
+            )?,
+        }
     }
 
     Ok(())
 }
 
+fn write_response(writer: &mut impl Write, response: &Response) -> std::io::Result<()> {
+    let json = serde_json::to_string(response)
+        .unwrap_or_else(|e| format!("{{\"success\":false,\"error\":\"{}\"}}", e));
+    writer.write_all(json.as_bytes())?;
+    writer.write_all(b"\n")?;
+    writer.flush()
+}
+
+/// acknowledge, then write a line per alert firing until the client goes away
+fn subscribe(writer: &mut impl Write, subscribers: &Subscribers) -> std::io::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    subscribers
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(tx);
+    write_response(writer, &Response::ok_empty())?;
+
+    for firing in rx {
+        let response = match serde_json::to_value(&firing) {
+            Ok(v) => Response::ok(v),
+            Err(e) => Response::err(e.to_string()),
+        };
+        if write_response(writer, &response).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// write a line per bucket as it is read, then a `done` line. an error,
+/// before or during the stream, is sent as the `done` line instead.
+fn stream_query(
+    writer: &mut impl Write,
+    traqq: &Traqq,
+    span: Result<(i64, i64), String>,
+    granularity: BucketType,
+    filter: &QueryFilter,
+    page: Option<Page>,
+) -> std::io::Result<()> {
+    let stream = span.and_then(|(min, max)| traqq.query_stream(min, max, granularity, filter));
+    let stream = match stream {
+        Ok(stream) => stream,
+        Err(e) => return write_response(writer, &Response::err(e).done()),
+    };
+    let stream = match page {
+        Some(page) => stream.page(page),
+        None => stream,
+    };
+
+    for day in stream {
+        let chunk = day.and_then(|day| {
+            let warnings = day.warnings();
+            serde_json::to_value(&day)
+                .map(|v| Response::ok(v).with_warnings(warnings))
+                .map_err(|e| e.to_string())
+        });
+        match chunk {
+            Ok(response) => write_response(writer, &response)?,
+            Err(e) => return write_response(writer, &Response::err(e).done()),
+        }
+    }
+    write_response(writer, &Response::ok_empty().done())
+}
+
 fn dispatch(cmd: Command, traqq: &Traqq) -> Response {
     match cmd {
         Command::Record { event } => match IncomingEvent::from_json(event) {
@@ -126,41 +414,237 @@ fn dispatch(cmd: Command, traqq: &Traqq) -> Response {
             },
             Err(e) => Response::err(e),
         },
-        Command::Query { min, max } => match traqq.query(min, max) {
-            Ok(result) => match serde_json::to_value(&result.days) {
+        Command::Query {
+            min,
+            max,
+            granularity,
+            timezone,
+            accept,
+            ignore,
+            top,
+            auto,
+        } => {
+            // only the buckets are returned, so no range-wide merges
+            let filter = QueryFilter {
+                accept,
+                ignore,
+                top,
+                skip_merges: true,
+            };
+            let result = match (auto, timezone) {
+                (true, Some(_)) => Err("timezone is not supported with auto".to_string()),
+                (true, None) => traqq.query_auto_filtered(min, max, &filter),
+                (false, timezone) => run_query(traqq, min, max, granularity, timezone, &filter),
+            };
+            match result {
+                Ok(result) => match serde_json::to_value(&result.days) {
+                    Ok(v) => Response::ok(v).with_warnings(result.warnings),
+                    Err(e) => Response::err(e.to_string()),
+                },
+                Err(e) => Response::err(e),
+            }
+        }
+        Command::QueryDays {
+            days,
+            granularity,
+            top,
+        } => {
+            let filter = QueryFilter {
+                top,
+                skip_merges: true,
+                ..QueryFilter::default()
+            };
+            match traqq.query_days_filtered(days, granularity, &filter) {
+                Ok(result) => match serde_json::to_value(&result.days) {
+                    Ok(v) => Response::ok(v).with_warnings(result.warnings),
+                    Err(e) => Response::err(e.to_string()),
+                },
+                Err(e) => Response::err(e),
+            }
+        }
+        Command::QueryRange {
+            range,
+            granularity,
+            top,
+            auto,
+        } => {
+            let filter = QueryFilter {
+                top,
+                skip_merges: true,
+                ..QueryFilter::default()
+            };
+            let result = if auto {
+                traqq.query_range_auto(&range, &filter)
+            } else {
+                traqq.query_range_filtered(&range, granularity, &filter)
+            };
+            match result {
+                Ok(result) => match serde_json::to_value(&result.days) {
+                    Ok(v) => Response::ok(v).with_warnings(result.warnings),
+                    Err(e) => Response::err(e.to_string()),
+                },
+                Err(e) => Response::err(e),
+            }
+        }
+        Command::Series {
+            metric,
+            fields,
+            min,
+            max,
+            range,
+            granularity,
+        } => {
+            let opts = match FindOptions::parse(&metric) {
+                Ok(opts) => opts,
+                Err(e) => return Response::err(e),
+            };
+            // bmp series carry a single count; other types need their fields
+            let fields = match (fields.is_empty(), opts.metric_type) {
+                (false, _) => fields,
+                (true, MetricType::Bmp) => vec!["count".to_string()],
+                (true, metric_type) => {
+                    return Response::err(format!(
+                        "fields are required for {} metrics: {}",
+                        metric_type, metric
+                    ))
+                }
+            };
+            let result = resolve_range(traqq, min, max, range).and_then(|(min, max)| {
+                traqq.query_filtered(min, max, granularity, &QueryFilter::from_find(&opts))
+            });
+            match result {
+                Ok(result) => match result.series_many(&metric, &fields) {
+                    Ok(series) => match serde_json::to_value(series) {
+                        Ok(v) => Response::ok(v).with_warnings(result.warnings),
+                        Err(e) => Response::err(e.to_string()),
+                    },
+                    Err(e) => Response::err(e),
+                },
+                Err(e) => Response::err(e),
+            }
+        }
+        Command::Ql { query } => match traqq.query_str(&query) {
+            Ok(table) => match serde_json::to_value(&table) {
+                Ok(v) => Response::ok(v).with_warnings(table.warnings),
+                Err(e) => Response::err(e.to_string()),
+            },
+            Err(e) => Response::err(e),
+        },
+        Command::Compare { range, previous } => match traqq.compare(&range, &previous) {
+            Ok(comparison) => match serde_json::to_value(&comparison) {
+                Ok(v) => Response::ok(v).with_warnings(comparison.warnings),
+                Err(e) => Response::err(e.to_string()),
+            },
+            Err(e) => Response::err(e),
+        },
+        Command::Anomalies {
+            metric,
+            fields,
+            range,
+            granularity,
+        } => match traqq.anomalies(&metric, &fields, &range, granularity) {
+            Ok(reports) => match serde_json::to_value(reports) {
                 Ok(v) => Response::ok(v),
                 Err(e) => Response::err(e.to_string()),
             },
             Err(e) => Response::err(e),
         },
-        Command::QueryDays { days } => match traqq.query_days(days) {
-            Ok(result) => match serde_json::to_value(&result.days) {
+        Command::Catalog { range } => match traqq.catalog(&range) {
+            Ok(catalog) => match serde_json::to_value(catalog) {
                 Ok(v) => Response::ok(v),
                 Err(e) => Response::err(e.to_string()),
             },
             Err(e) => Response::err(e),
         },
+        // answered line by line in `handle_connection`
+        Command::QueryStream { .. } | Command::Alerts => {
+            Response::err("streamed commands need a connection".to_string())
+        }
+        Command::CacheStats => match serde_json::to_value(traqq.cache_stats()) {
+            Ok(v) => Response::ok(v),
+            Err(e) => Response::err(e.to_string()),
+        },
+        Command::Compact { before } => {
+            let result = match before {
+                Some(before) => traqq.compact(before),
+                None => traqq.compact_expired(),
+            };
+            match result {
+                Ok(result) => match serde_json::to_value(&result) {
+                    Ok(v) => Response::ok(v),
+                    Err(e) => Response::err(e.to_string()),
+                },
+                Err(e) => Response::err(e),
+            }
+        }
         Command::Find {
             min,
             max,
+            granularity,
+            timezone,
             metric_type,
             key,
             add_key,
             merge,
-        } => match traqq.query(min, max) {
-            Ok(result) => {
-                let found = result.find(FindOptions {
-                    metric_type,
-                    key,
-                    add_key,
-                    merge,
-                });
-                match serde_json::to_value(&found) {
-                    Ok(v) => Response::ok(v),
+            top,
+        } => {
+            // reject typos instead of returning nothing
+            let metric_type = match metric_type.parse::<MetricType>() {
+                Ok(t) => t,
+                Err(e) => return Response::err(e),
+            };
+            // only read the keys this find can return
+            let opts = FindOptions {
+                metric_type,
+                key,
+                add_key,
+                merge,
+                top,
+            };
+            let filter = QueryFilter::from_find(&opts);
+            match run_query(traqq, min, max, granularity, timezone, &filter) {
+                Ok(result) => match serde_json::to_value(result.find(opts)) {
+                    Ok(v) => Response::ok(v).with_warnings(result.warnings),
                     Err(e) => Response::err(e.to_string()),
-                }
+                },
+                Err(e) => Response::err(e),
             }
-            Err(e) => Response::err(e),
-        },
+        }
+    }
+}
+
+/// run a range query, re-aggregating into another timezone when one is given
+fn run_query(
+    traqq: &Traqq,
+    min: i64,
+    max: i64,
+    granularity: BucketType,
+    timezone: Option<String>,
+    filter: &QueryFilter,
+) -> Result<QueryResult, String> {
+    match timezone {
+        Some(tz) if granularity == BucketType::Daily => {
+            traqq.query_in_timezone_filtered(min, max, &tz, filter)
+        }
+        Some(_) => Err("timezone is only supported with day granularity".to_string()),
+        None => traqq.query_filtered(min, max, granularity, filter),
+    }
+}
+
+fn default_range() -> String {
+    "today".to_string()
+}
+
+/// a range given either as min/max or as a range expression
+fn resolve_range(
+    traqq: &Traqq,
+    min: Option<i64>,
+    max: Option<i64>,
+    range: Option<String>,
+) -> Result<(i64, i64), String> {
+    match (range, min, max) {
+        (Some(expr), _, _) => range::parse(&expr, &traqq.config, Utc::now().timestamp()),
+        (None, Some(min), Some(max)) => Ok((min, max)),
+        _ => Err("either range or min and max are required".to_string()),
     }
 }
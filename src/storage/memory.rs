@@ -1,28 +1,195 @@
 use std::collections::{BTreeMap, HashMap, HashSet};
-use std::sync::RwLock;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
 
 use super::{Storage, StorageError};
 
+/// the time a `MemoryStorage` measures ttls and sweeps against: the system
+/// clock plus however far it has been advanced. clones share the offset, so
+/// a test can keep one and move a storage's time forward instead of sleeping
+#[derive(Debug, Clone, Default)]
+pub struct Clock {
+    offset: Arc<Mutex<Duration>>,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the current time, including any advance
+    pub fn now(&self) -> Instant {
+        Instant::now() + *self.offset.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// move this clock and every clone of it forward by `by`
+    pub fn advance(&self, by: Duration) {
+        *self.offset.lock().unwrap_or_else(PoisonError::into_inner) += by;
+    }
+}
+
 /// in-memory storage backend for testing and embedded use.
 ///
 /// uses rwlock-wrapped hashmaps internally. each data structure type
 /// (hash, hyperloglog, sorted set, set) has its own namespace to avoid
-/// key collisions.
+/// key collisions. expired keys are removed on next access, and writes sweep
+/// out every expired key at most once per `SWEEP_INTERVAL`, so buckets that
+/// are never read again are still freed.
 pub struct MemoryStorage {
     hashes: RwLock<HashMap<String, HashMap<String, f64>>>,
     hyperloglogs: RwLock<HashMap<String, HashSet<String>>>,
     sorted_sets: RwLock<HashMap<String, BTreeMap<String, f64>>>,
     sets: RwLock<HashMap<String, HashSet<String>>>,
+    expirations: RwLock<HashMap<String, Instant>>,
+    /// when writes last swept expired keys
+    last_sweep: Mutex<Instant>,
+    clock: Clock,
 }
 
+/// minimum time between sweeps of expired keys
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
 impl MemoryStorage {
     pub fn new() -> Self {
+        Self::with_clock(Clock::new())
+    }
+
+    /// storage whose ttls follow `clock`
+    pub fn with_clock(clock: Clock) -> Self {
         Self {
             hashes: RwLock::new(HashMap::new()),
             hyperloglogs: RwLock::new(HashMap::new()),
             sorted_sets: RwLock::new(HashMap::new()),
             sets: RwLock::new(HashMap::new()),
+            expirations: RwLock::new(HashMap::new()),
+            last_sweep: Mutex::new(clock.now()),
+            clock,
+        }
+    }
+
+    /// number of keys held across every namespace, including expired keys
+    /// not yet purged
+    pub fn key_count(&self) -> Result<usize, StorageError> {
+        let err = |e: String| StorageError::OperationFailed(e);
+        Ok(self.hashes.read().map_err(|e| err(e.to_string()))?.len()
+            + self
+                .hyperloglogs
+                .read()
+                .map_err(|e| err(e.to_string()))?
+                .len()
+            + self
+                .sorted_sets
+                .read()
+                .map_err(|e| err(e.to_string()))?
+                .len()
+            + self.sets.read().map_err(|e| err(e.to_string()))?.len())
+    }
+
+    /// remove every key whose ttl has elapsed, returning how many were removed
+    pub fn purge_expired(&self) -> Result<usize, StorageError> {
+        let now = self.clock.now();
+        let expired: Vec<String> = self
+            .expirations
+            .read()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &expired {
+            self.remove_key(key)?;
+        }
+        Ok(expired.len())
+    }
+
+    /// purge expired keys if the last sweep was `SWEEP_INTERVAL` ago.
+    /// a writer that finds another one sweeping carries on without waiting
+    fn sweep_if_due(&self) -> Result<(), StorageError> {
+        let Ok(mut last) = self.last_sweep.try_lock() else {
+            return Ok(());
+        };
+        let now = self.clock.now();
+        if now.duration_since(*last) < SWEEP_INTERVAL {
+            return Ok(());
+        }
+        *last = now;
+        drop(last);
+
+        self.purge_expired().map(|_| ())
+    }
+
+    /// drop a key from every namespace if its ttl has elapsed
+    fn purge_if_expired(&self, key: &str) -> Result<(), StorageError> {
+        let expired = self
+            .expirations
+            .read()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?
+            .get(key)
+            .is_some_and(|deadline| *deadline <= self.clock.now());
+
+        if expired {
+            self.remove_key(key)?;
         }
+        Ok(())
+    }
+
+    /// remove a key from every namespace, returning true if it existed
+    fn remove_key(&self, key: &str) -> Result<bool, StorageError> {
+        let mut removed = false;
+        removed |= self
+            .hashes
+            .write()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?
+            .remove(key)
+            .is_some();
+        removed |= self
+            .hyperloglogs
+            .write()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?
+            .remove(key)
+            .is_some();
+        removed |= self
+            .sorted_sets
+            .write()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?
+            .remove(key)
+            .is_some();
+        removed |= self
+            .sets
+            .write()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?
+            .remove(key)
+            .is_some();
+        self.expirations
+            .write()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?
+            .remove(key);
+        Ok(removed)
+    }
+
+    /// true if any namespace holds the key, expired or not
+    fn contains_key(&self, key: &str) -> Result<bool, StorageError> {
+        Ok(self
+            .hashes
+            .read()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?
+            .contains_key(key)
+            || self
+                .hyperloglogs
+                .read()
+                .map_err(|e| StorageError::OperationFailed(e.to_string()))?
+                .contains_key(key)
+            || self
+                .sorted_sets
+                .read()
+                .map_err(|e| StorageError::OperationFailed(e.to_string()))?
+                .contains_key(key)
+            || self
+                .sets
+                .read()
+                .map_err(|e| StorageError::OperationFailed(e.to_string()))?
+                .contains_key(key))
     }
 }
 
@@ -34,6 +201,8 @@ impl Default for MemoryStorage {
 
 impl Storage for MemoryStorage {
     fn hash_increment(&self, key: &str, field: &str, amount: i64) -> Result<i64, StorageError> {
+        self.purge_if_expired(key)?;
+        self.sweep_if_due()?;
         let mut hashes = self
             .hashes
             .write()
@@ -53,6 +222,8 @@ impl Storage for MemoryStorage {
         field: &str,
         amount: f64,
     ) -> Result<f64, StorageError> {
+        self.purge_if_expired(key)?;
+        self.sweep_if_due()?;
         let mut hashes = self
             .hashes
             .write()
@@ -66,7 +237,41 @@ impl Storage for MemoryStorage {
         Ok(*entry)
     }
 
+    fn hash_increment_capped(
+        &self,
+        key: &str,
+        field: &str,
+        amount: i64,
+        cap: usize,
+        overflow: &str,
+    ) -> Result<bool, StorageError> {
+        self.hash_increment_float_capped(key, field, amount as f64, cap, overflow)
+    }
+
+    fn hash_increment_float_capped(
+        &self,
+        key: &str,
+        field: &str,
+        amount: f64,
+        cap: usize,
+        overflow: &str,
+    ) -> Result<bool, StorageError> {
+        self.purge_if_expired(key)?;
+        self.sweep_if_due()?;
+        // the length check and the increment share one write lock
+        let mut hashes = self
+            .hashes
+            .write()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let fields = hashes.entry(key.to_string()).or_default();
+        let overflowed = !fields.contains_key(field) && fields.len() >= cap;
+        let field = if overflowed { overflow } else { field };
+        *fields.entry(field.to_string()).or_insert(0.0) += amount;
+        Ok(overflowed)
+    }
+
     fn hash_get_all(&self, key: &str) -> Result<HashMap<String, String>, StorageError> {
+        self.purge_if_expired(key)?;
         let hashes = self
             .hashes
             .read()
@@ -91,7 +296,29 @@ impl Storage for MemoryStorage {
         }
     }
 
+    fn hash_len(&self, key: &str) -> Result<usize, StorageError> {
+        self.purge_if_expired(key)?;
+        let hashes = self
+            .hashes
+            .read()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(hashes.get(key).map_or(0, |fields| fields.len()))
+    }
+
+    fn hash_exists(&self, key: &str, field: &str) -> Result<bool, StorageError> {
+        self.purge_if_expired(key)?;
+        let hashes = self
+            .hashes
+            .read()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(hashes
+            .get(key)
+            .is_some_and(|fields| fields.contains_key(field)))
+    }
+
     fn hyperloglog_add(&self, key: &str, value: &str) -> Result<bool, StorageError> {
+        self.purge_if_expired(key)?;
+        self.sweep_if_due()?;
         let mut hlls = self
             .hyperloglogs
             .write()
@@ -101,6 +328,7 @@ impl Storage for MemoryStorage {
     }
 
     fn hyperloglog_count(&self, key: &str) -> Result<u64, StorageError> {
+        self.purge_if_expired(key)?;
         let hlls = self
             .hyperloglogs
             .read()
@@ -111,12 +339,30 @@ impl Storage for MemoryStorage {
         }
     }
 
+    fn hyperloglog_count_union(&self, keys: &[String]) -> Result<u64, StorageError> {
+        for key in keys {
+            self.purge_if_expired(key)?;
+        }
+        let hlls = self
+            .hyperloglogs
+            .read()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let union: HashSet<&String> = keys
+            .iter()
+            .filter_map(|key| hlls.get(key))
+            .flatten()
+            .collect();
+        Ok(union.len() as u64)
+    }
+
     fn sorted_set_increment(
         &self,
         key: &str,
         member: &str,
         amount: f64,
     ) -> Result<f64, StorageError> {
+        self.purge_if_expired(key)?;
+        self.sweep_if_due()?;
         let mut ss = self
             .sorted_sets
             .write()
@@ -130,7 +376,29 @@ impl Storage for MemoryStorage {
         Ok(*entry)
     }
 
+    fn sorted_set_increment_capped(
+        &self,
+        key: &str,
+        member: &str,
+        amount: f64,
+        cap: usize,
+        overflow: &str,
+    ) -> Result<bool, StorageError> {
+        self.purge_if_expired(key)?;
+        self.sweep_if_due()?;
+        let mut ss = self
+            .sorted_sets
+            .write()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let members = ss.entry(key.to_string()).or_default();
+        let overflowed = !members.contains_key(member) && members.len() >= cap;
+        let member = if overflowed { overflow } else { member };
+        *members.entry(member.to_string()).or_insert(0.0) += amount;
+        Ok(overflowed)
+    }
+
     fn sorted_set_top(&self, key: &str, limit: usize) -> Result<Vec<(String, f64)>, StorageError> {
+        self.purge_if_expired(key)?;
         let ss = self
             .sorted_sets
             .read()
@@ -148,7 +416,54 @@ impl Storage for MemoryStorage {
         }
     }
 
+    fn sorted_set_union_top(
+        &self,
+        keys: &[String],
+        limit: usize,
+    ) -> Result<Vec<(String, f64)>, StorageError> {
+        for key in keys {
+            self.purge_if_expired(key)?;
+        }
+        let ss = self
+            .sorted_sets
+            .read()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+
+        let mut union: HashMap<&str, f64> = HashMap::new();
+        for members in keys.iter().filter_map(|key| ss.get(key)) {
+            for (member, score) in members {
+                *union.entry(member).or_insert(0.0) += score;
+            }
+        }
+
+        let mut pairs: Vec<(String, f64)> =
+            union.into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+        pairs.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
+
+    fn sorted_set_len(&self, key: &str) -> Result<usize, StorageError> {
+        self.purge_if_expired(key)?;
+        let ss = self
+            .sorted_sets
+            .read()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(ss.get(key).map_or(0, |members| members.len()))
+    }
+
+    fn sorted_set_score(&self, key: &str, member: &str) -> Result<Option<f64>, StorageError> {
+        self.purge_if_expired(key)?;
+        let ss = self
+            .sorted_sets
+            .read()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(ss.get(key).and_then(|members| members.get(member).copied()))
+    }
+
     fn set_add(&self, key: &str, members: &[String]) -> Result<usize, StorageError> {
+        self.purge_if_expired(key)?;
+        self.sweep_if_due()?;
         let mut sets = self
             .sets
             .write()
@@ -163,7 +478,23 @@ impl Storage for MemoryStorage {
         Ok(added)
     }
 
+    fn set_add_new(&self, key: &str, members: &[String]) -> Result<Vec<String>, StorageError> {
+        self.purge_if_expired(key)?;
+        self.sweep_if_due()?;
+        let mut sets = self
+            .sets
+            .write()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let set = sets.entry(key.to_string()).or_default();
+        Ok(members
+            .iter()
+            .filter(|m| set.insert((*m).clone()))
+            .cloned()
+            .collect())
+    }
+
     fn set_members(&self, key: &str) -> Result<Vec<String>, StorageError> {
+        self.purge_if_expired(key)?;
         let sets = self
             .sets
             .read()
@@ -173,4 +504,63 @@ impl Storage for MemoryStorage {
             None => Ok(Vec::new()),
         }
     }
+
+    fn set_len(&self, key: &str) -> Result<usize, StorageError> {
+        self.purge_if_expired(key)?;
+        let sets = self
+            .sets
+            .read()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(sets.get(key).map_or(0, |set| set.len()))
+    }
+
+    fn set_remove(&self, key: &str, members: &[String]) -> Result<usize, StorageError> {
+        self.purge_if_expired(key)?;
+        let mut map = self
+            .sets
+            .write()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let Some(set) = map.get_mut(key) else {
+            return Ok(0);
+        };
+        let removed = members.iter().filter(|m| set.remove(*m)).count();
+        // like redis, an emptied set no longer exists
+        if set.is_empty() {
+            map.remove(key);
+        }
+        Ok(removed)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        self.purge_if_expired(key)?;
+        self.contains_key(key)
+    }
+
+    fn expire(&self, key: &str, ttl_secs: u64) -> Result<bool, StorageError> {
+        if !self.exists(key)? {
+            return Ok(false);
+        }
+
+        let deadline = self.clock.now() + Duration::from_secs(ttl_secs);
+        self.expirations
+            .write()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?
+            .insert(key.to_string(), deadline);
+
+        if ttl_secs == 0 {
+            self.remove_key(key)?;
+        }
+        Ok(true)
+    }
+
+    fn delete(&self, keys: &[String]) -> Result<usize, StorageError> {
+        let mut removed = 0;
+        for key in keys {
+            self.purge_if_expired(key)?;
+            if self.remove_key(key)? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
 }
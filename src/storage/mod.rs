@@ -44,9 +44,37 @@ pub trait Storage: Send + Sync {
         amount: f64,
     ) -> Result<f64, StorageError>;
 
+    /// like `hash_increment`, but once the hash holds `cap` fields a field it
+    /// doesn't have is counted under `overflow` instead. the check and the
+    /// increment are one atomic step. returns true if `overflow` was counted
+    fn hash_increment_capped(
+        &self,
+        key: &str,
+        field: &str,
+        amount: i64,
+        cap: usize,
+        overflow: &str,
+    ) -> Result<bool, StorageError>;
+
+    /// `hash_increment_capped` for a float amount
+    fn hash_increment_float_capped(
+        &self,
+        key: &str,
+        field: &str,
+        amount: f64,
+        cap: usize,
+        overflow: &str,
+    ) -> Result<bool, StorageError>;
+
     /// return all field-value pairs for a hash key
     fn hash_get_all(&self, key: &str) -> Result<HashMap<String, String>, StorageError>;
 
+    /// return the number of fields in a hash
+    fn hash_len(&self, key: &str) -> Result<usize, StorageError>;
+
+    /// return true if the hash contains `field`
+    fn hash_exists(&self, key: &str, field: &str) -> Result<bool, StorageError>;
+
     // -- hyperloglog operations (bmp) --
 
     /// add a value to a hyperloglog. returns true if the cardinality changed.
@@ -55,6 +83,9 @@ pub trait Storage: Send + Sync {
     /// return the approximate cardinality of a hyperloglog
     fn hyperloglog_count(&self, key: &str) -> Result<u64, StorageError>;
 
+    /// return the approximate cardinality of the union of several hyperloglogs
+    fn hyperloglog_count_union(&self, keys: &[String]) -> Result<u64, StorageError>;
+
     // -- sorted set operations (top) --
 
     /// increment a member's score in a sorted set by `amount`
@@ -65,16 +96,105 @@ pub trait Storage: Send + Sync {
         amount: f64,
     ) -> Result<f64, StorageError>;
 
+    /// like `sorted_set_increment`, but once the set holds `cap` members a
+    /// member it doesn't have is counted under `overflow` instead, atomically.
+    /// returns true if `overflow` was counted
+    fn sorted_set_increment_capped(
+        &self,
+        key: &str,
+        member: &str,
+        amount: f64,
+        cap: usize,
+        overflow: &str,
+    ) -> Result<bool, StorageError>;
+
     /// return the top `limit` members by score (descending), with scores
     fn sorted_set_top(&self, key: &str, limit: usize) -> Result<Vec<(String, f64)>, StorageError>;
 
+    /// sum the scores of several sorted sets (like ZUNIONSTORE) and return
+    /// the top `limit` members of the union, descending
+    fn sorted_set_union_top(
+        &self,
+        keys: &[String],
+        limit: usize,
+    ) -> Result<Vec<(String, f64)>, StorageError>;
+
+    /// return the number of members in a sorted set
+    fn sorted_set_len(&self, key: &str) -> Result<usize, StorageError>;
+
+    /// return a member's score, or None if the member is absent
+    fn sorted_set_score(&self, key: &str, member: &str) -> Result<Option<f64>, StorageError>;
+
     // -- set operations (key tracking) --
 
     /// add members to a set. returns the number of new members added.
     fn set_add(&self, key: &str, members: &[String]) -> Result<usize, StorageError>;
 
+    /// like `set_add`, but returns the members that were new, in order
+    fn set_add_new(&self, key: &str, members: &[String]) -> Result<Vec<String>, StorageError>;
+
     /// return all members of a set
     fn set_members(&self, key: &str) -> Result<Vec<String>, StorageError>;
+
+    /// return the number of members in a set
+    fn set_len(&self, key: &str) -> Result<usize, StorageError>;
+
+    /// remove members from a set. returns the number of members removed.
+    fn set_remove(&self, key: &str, members: &[String]) -> Result<usize, StorageError>;
+
+    // -- key operations --
+
+    /// return true if a key of any type exists
+    fn exists(&self, key: &str) -> Result<bool, StorageError>;
+
+    /// expire a key of any type after `ttl_secs` seconds.
+    /// returns false if the key does not exist.
+    fn expire(&self, key: &str, ttl_secs: u64) -> Result<bool, StorageError>;
+
+    /// delete keys of any type. returns the number of keys that existed.
+    fn delete(&self, keys: &[String]) -> Result<usize, StorageError>;
+
+    // -- bulk reads (query path) --
+    // default implementations loop over the single-key methods; networked
+    // backends should override them to read in one round trip
+
+    /// `hash_get_all` for each key, in order
+    fn hash_get_all_many(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<HashMap<String, String>>, StorageError> {
+        keys.iter().map(|key| self.hash_get_all(key)).collect()
+    }
+
+    /// `hyperloglog_count` for each key, in order
+    fn hyperloglog_count_many(&self, keys: &[String]) -> Result<Vec<u64>, StorageError> {
+        keys.iter().map(|key| self.hyperloglog_count(key)).collect()
+    }
+
+    /// `sorted_set_top` for each (key, limit), in order
+    fn sorted_set_top_many(
+        &self,
+        keys: &[(String, usize)],
+    ) -> Result<Vec<Vec<(String, f64)>>, StorageError> {
+        keys.iter()
+            .map(|(key, limit)| self.sorted_set_top(key, *limit))
+            .collect()
+    }
+
+    /// `hash_len` for each key, in order
+    fn hash_len_many(&self, keys: &[String]) -> Result<Vec<usize>, StorageError> {
+        keys.iter().map(|key| self.hash_len(key)).collect()
+    }
+
+    /// `sorted_set_len` for each key, in order
+    fn sorted_set_len_many(&self, keys: &[String]) -> Result<Vec<usize>, StorageError> {
+        keys.iter().map(|key| self.sorted_set_len(key)).collect()
+    }
+
+    /// `exists` for each key, in order
+    fn exists_many(&self, keys: &[String]) -> Result<Vec<bool>, StorageError> {
+        keys.iter().map(|key| self.exists(key)).collect()
+    }
 }
 
 #[cfg(test)]
@@ -127,6 +247,21 @@ mod tests {
         assert!(all.is_empty());
     }
 
+    #[test]
+    fn test_hash_len_and_exists() {
+        let s = make_storage();
+        assert_eq!(s.hash_len("h1").unwrap(), 0);
+        assert!(!s.hash_exists("h1", "a").unwrap());
+
+        s.hash_increment("h1", "a", 1).unwrap();
+        s.hash_increment_float("h1", "b", 2.5).unwrap();
+        s.hash_increment("h1", "a", 1).unwrap();
+
+        assert_eq!(s.hash_len("h1").unwrap(), 2);
+        assert!(s.hash_exists("h1", "a").unwrap());
+        assert!(!s.hash_exists("h1", "c").unwrap());
+    }
+
     // -- hyperloglog tests --
 
     #[test]
@@ -148,6 +283,23 @@ mod tests {
         assert_eq!(count, 0);
     }
 
+    #[test]
+    fn test_hyperloglog_count_union() {
+        let s = make_storage();
+        s.hyperloglog_add("hll1", "a").unwrap();
+        s.hyperloglog_add("hll1", "b").unwrap();
+        s.hyperloglog_add("hll2", "b").unwrap();
+        s.hyperloglog_add("hll2", "c").unwrap();
+
+        let keys = vec![
+            "hll1".to_string(),
+            "hll2".to_string(),
+            "missing".to_string(),
+        ];
+        assert_eq!(s.hyperloglog_count_union(&keys).unwrap(), 3);
+        assert_eq!(s.hyperloglog_count_union(&[]).unwrap(), 0);
+    }
+
     // -- sorted set tests --
 
     #[test]
@@ -182,6 +334,138 @@ mod tests {
         assert_eq!(top[1].0, "b");
     }
 
+    #[test]
+    fn test_sorted_set_union_top() {
+        let s = make_storage();
+        // "c" never tops a single set but leads the union
+        s.sorted_set_increment("z1", "a", 5.0).unwrap();
+        s.sorted_set_increment("z1", "c", 4.0).unwrap();
+        s.sorted_set_increment("z2", "b", 5.0).unwrap();
+        s.sorted_set_increment("z2", "c", 4.0).unwrap();
+
+        let keys = ["z1", "z2", "missing"].map(String::from);
+        let top = s.sorted_set_union_top(&keys, 2).unwrap();
+        assert_eq!(top[0], ("c".to_string(), 8.0));
+        assert_eq!(top.len(), 2);
+
+        // the source sets are untouched
+        assert_eq!(s.sorted_set_len("z1").unwrap(), 2);
+    }
+
+    #[test]
+    fn test_bulk_reads_keep_key_order() {
+        let s = make_storage();
+        s.hash_increment("h1", "a", 1).unwrap();
+        s.hash_increment("h2", "b", 2).unwrap();
+        s.hyperloglog_add("u1", "x").unwrap();
+        s.hyperloglog_add("u2", "x").unwrap();
+        s.hyperloglog_add("u2", "y").unwrap();
+        s.sorted_set_increment("z1", "a", 1.0).unwrap();
+        s.sorted_set_increment("z1", "b", 2.0).unwrap();
+
+        let hashes = s
+            .hash_get_all_many(&["h2", "missing", "h1"].map(String::from))
+            .unwrap();
+        assert_eq!(hashes[0].get("b"), Some(&"2".to_string()));
+        assert!(hashes[1].is_empty());
+        assert_eq!(hashes[2].get("a"), Some(&"1".to_string()));
+
+        // counted per key, not as a union
+        let counts = s
+            .hyperloglog_count_many(&["u2", "u1"].map(String::from))
+            .unwrap();
+        assert_eq!(counts, vec![2, 1]);
+
+        let tops = s
+            .sorted_set_top_many(&[("z1".to_string(), 1), ("z1".to_string(), 5)])
+            .unwrap();
+        assert_eq!(tops[0], vec![("b".to_string(), 2.0)]);
+        assert_eq!(tops[1].len(), 2);
+
+        let lens = s
+            .hash_len_many(&["h1", "missing"].map(String::from))
+            .unwrap();
+        assert_eq!(lens, vec![1, 0]);
+        let lens = s
+            .sorted_set_len_many(&["missing", "z1"].map(String::from))
+            .unwrap();
+        assert_eq!(lens, vec![0, 2]);
+        let exists = s
+            .exists_many(&["u1", "missing", "z1"].map(String::from))
+            .unwrap();
+        assert_eq!(exists, vec![true, false, true]);
+
+        assert!(s.hash_get_all_many(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sorted_set_len_and_score() {
+        let s = make_storage();
+        assert_eq!(s.sorted_set_len("ss").unwrap(), 0);
+        assert_eq!(s.sorted_set_score("ss", "a").unwrap(), None);
+
+        s.sorted_set_increment("ss", "a", 1.0).unwrap();
+        s.sorted_set_increment("ss", "b", 2.0).unwrap();
+        s.sorted_set_increment("ss", "a", 3.0).unwrap();
+
+        assert_eq!(s.sorted_set_len("ss").unwrap(), 2);
+        assert_eq!(s.sorted_set_score("ss", "a").unwrap(), Some(4.0));
+        assert_eq!(s.sorted_set_score("ss", "c").unwrap(), None);
+    }
+
+    #[test]
+    fn test_capped_increments_overflow() {
+        let s = make_storage();
+        assert!(!s.hash_increment_capped("h", "a", 1, 2, "other").unwrap());
+        assert!(!s.hash_increment_capped("h", "b", 1, 2, "other").unwrap());
+        assert!(s.hash_increment_capped("h", "c", 1, 2, "other").unwrap());
+        // existing fields keep counting past the cap
+        assert!(!s.hash_increment_capped("h", "a", 2, 2, "other").unwrap());
+        let all = s.hash_get_all("h").unwrap();
+        assert_eq!((all["a"].as_str(), all["other"].as_str()), ("3", "1"));
+
+        assert!(!s
+            .hash_increment_float_capped("f", "a", 1.5, 1, "other")
+            .unwrap());
+        assert!(s
+            .hash_increment_float_capped("f", "b", 2.5, 1, "other")
+            .unwrap());
+        assert_eq!(s.hash_get_all("f").unwrap()["other"], "2.5");
+
+        assert!(!s
+            .sorted_set_increment_capped("z", "a", 1.0, 1, "other")
+            .unwrap());
+        assert!(s
+            .sorted_set_increment_capped("z", "b", 4.0, 1, "other")
+            .unwrap());
+        assert_eq!(s.sorted_set_score("z", "other").unwrap(), Some(4.0));
+        assert_eq!(s.sorted_set_score("z", "b").unwrap(), None);
+    }
+
+    #[test]
+    fn test_concurrent_capped_increments_hold_the_cap() {
+        let s = make_storage();
+        std::thread::scope(|scope| {
+            for worker in 0..8 {
+                let s = &s;
+                scope.spawn(move || {
+                    for i in 0..50 {
+                        let field = format!("{}-{}", worker, i);
+                        s.hash_increment_capped("h", &field, 1, 10, "other")
+                            .unwrap();
+                        s.sorted_set_increment_capped("z", &field, 1.0, 10, "other")
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        // the cap plus the overflow field
+        assert_eq!(s.hash_len("h").unwrap(), 11);
+        assert_eq!(s.sorted_set_len("z").unwrap(), 11);
+        assert_eq!(s.sorted_set_score("z", "other").unwrap(), Some(390.0));
+    }
+
     #[test]
     fn test_sorted_set_top_missing_key() {
         let s = make_storage();
@@ -210,6 +494,17 @@ mod tests {
         assert_eq!(members, vec!["a", "b", "c", "d"]);
     }
 
+    #[test]
+    fn test_set_add_new_returns_added_members() {
+        let s = make_storage();
+        s.set_add("s1", &["a".into()]).unwrap();
+        let new = s
+            .set_add_new("s1", &["a", "b", "c", "b"].map(String::from))
+            .unwrap();
+        assert_eq!(new, vec!["b", "c"]);
+        assert_eq!(s.set_len("s1").unwrap(), 3);
+    }
+
     #[test]
     fn test_set_members_missing_key() {
         let s = make_storage();
@@ -217,6 +512,88 @@ mod tests {
         assert!(members.is_empty());
     }
 
+    #[test]
+    fn test_set_len() {
+        let s = make_storage();
+        s.set_add("s1", &["a".into(), "b".into()]).unwrap();
+        assert_eq!(s.set_len("s1").unwrap(), 2);
+        assert_eq!(s.set_len("missing").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_set_remove() {
+        let s = make_storage();
+        s.set_add("s1", &["a".into(), "b".into()]).unwrap();
+
+        assert_eq!(s.set_remove("s1", &["a".into(), "z".into()]).unwrap(), 1);
+        assert_eq!(s.set_members("s1").unwrap(), vec!["b"]);
+        assert_eq!(s.set_remove("missing", &["a".into()]).unwrap(), 0);
+    }
+
+    // -- key tests --
+
+    #[test]
+    fn test_delete_keys_of_any_type() {
+        let s = make_storage();
+        s.hash_increment("h1", "f1", 1).unwrap();
+        s.hyperloglog_add("hll", "a").unwrap();
+        s.sorted_set_increment("z1", "a", 1.0).unwrap();
+        s.set_add("s1", &["a".into()]).unwrap();
+
+        let keys = ["h1", "hll", "z1", "s1", "missing"].map(String::from);
+        assert_eq!(s.delete(&keys).unwrap(), 4);
+
+        assert!(s.hash_get_all("h1").unwrap().is_empty());
+        assert_eq!(s.hyperloglog_count("hll").unwrap(), 0);
+        assert!(s.sorted_set_top("z1", 10).unwrap().is_empty());
+        assert!(s.set_members("s1").unwrap().is_empty());
+    }
+
+    // -- expiry tests --
+
+    #[test]
+    fn test_expire_missing_key() {
+        let s = make_storage();
+        assert!(!s.expire("nonexistent", 60).unwrap());
+    }
+
+    #[test]
+    fn test_expire_removes_key_after_ttl() {
+        let s = make_storage();
+        s.hash_increment("h1", "f1", 3).unwrap();
+        s.hyperloglog_add("hll", "a").unwrap();
+        s.set_add("s1", &["a".into()]).unwrap();
+
+        assert!(s.expire("h1", 0).unwrap());
+        assert!(s.expire("hll", 0).unwrap());
+        assert!(s.expire("s1", 3600).unwrap());
+
+        assert!(s.hash_get_all("h1").unwrap().is_empty());
+        assert_eq!(s.hyperloglog_count("hll").unwrap(), 0);
+        assert_eq!(s.set_members("s1").unwrap(), vec!["a"]);
+
+        // writing to an expired key starts fresh
+        assert_eq!(s.hash_increment("h1", "f1", 1).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_untouched_expired_keys_are_swept() {
+        let clock = super::memory::Clock::new();
+        let s = MemoryStorage::with_clock(clock.clone());
+        s.hash_increment("h1", "f1", 1).unwrap();
+        s.sorted_set_increment("z1", "a", 1.0).unwrap();
+        s.expire("h1", 1).unwrap();
+        s.expire("z1", 1).unwrap();
+        assert_eq!(s.key_count().unwrap(), 2);
+
+        clock.advance(std::time::Duration::from_secs(2));
+
+        // a write to another key frees them without either being read
+        s.hyperloglog_add("hll", "a").unwrap();
+        assert_eq!(s.key_count().unwrap(), 1);
+        assert_eq!(s.purge_expired().unwrap(), 0);
+    }
+
     // -- cross-type isolation --
 
     #[test]
@@ -283,6 +660,32 @@ mod redis_tests {
 
         let all = s.hash_get_all("traqq_test:h1").unwrap();
         assert_eq!(all.get("f1").unwrap(), "6");
+
+        // f1 and f2 fill a cap of 2, so f3 lands in the overflow field
+        assert!(!s
+            .hash_increment_capped("traqq_test:h1", "f1", 1, 2, "other")
+            .unwrap());
+        assert!(s
+            .hash_increment_capped("traqq_test:h1", "f3", 1, 2, "other")
+            .unwrap());
+        assert!(s
+            .hash_increment_float_capped("traqq_test:h1", "f4", 0.5, 3, "other")
+            .unwrap());
+        let all = s.hash_get_all("traqq_test:h1").unwrap();
+        assert_eq!(all.get("f1").unwrap(), "7");
+        assert_eq!(all.get("other").unwrap(), "1.5");
+        assert!(!all.contains_key("f3"));
+
+        assert!(!s
+            .sorted_set_increment_capped("traqq_test:z1", "a", 1.0, 1, "other")
+            .unwrap());
+        assert!(s
+            .sorted_set_increment_capped("traqq_test:z1", "b", 2.0, 1, "other")
+            .unwrap());
+        assert_eq!(
+            s.sorted_set_score("traqq_test:z1", "other").unwrap(),
+            Some(2.0)
+        );
     }
 
     #[test]
@@ -371,14 +774,17 @@ mod redis_tests {
             time: TimeConfig {
                 store_hourly: false,
                 timezone: "UTC".to_string(),
+                ..TimeConfig::default()
             },
             mapping: MappingConfig {
                 bitmap: vec!["ip".to_string()],
                 add: vec!["event".to_string()],
                 add_value: vec![],
                 top: vec!["geo".to_string()],
+                ..MappingConfig::default()
             },
             limits: LimitsConfig::default(),
+            alerts: alert::AlertsConfig::default(),
         };
 
         let t = Traqq::new(config, Box::new(s), "traqq_test").unwrap();
@@ -417,10 +823,11 @@ mod redis_tests {
 
         // bmp: 2 unique IPs
         let bmp = result.find(FindOptions {
-            metric_type: "bmp".to_string(),
+            metric_type: MetricType::Bmp,
             key: "ip".to_string(),
             add_key: None,
             merge: false,
+            top: None,
         });
         assert!(!bmp.is_empty(), "should have bmp results from redis");
         if let MetricData::Count(c) = &bmp[0].result {
@@ -429,10 +836,11 @@ mod redis_tests {
 
         // add: sale=2, click=1
         let add = result.find(FindOptions {
-            metric_type: "add".to_string(),
+            metric_type: MetricType::Add,
             key: "event".to_string(),
             add_key: None,
             merge: false,
+            top: None,
         });
         assert!(!add.is_empty());
         if let MetricData::Hash(h) = &add[0].result {
@@ -442,10 +850,11 @@ mod redis_tests {
 
         // top: US=2, UK=1
         let top = result.find(FindOptions {
-            metric_type: "top".to_string(),
+            metric_type: MetricType::Top,
             key: "geo".to_string(),
             add_key: None,
             merge: false,
+            top: None,
         });
         assert!(!top.is_empty());
         if let MetricData::Ranked(pairs) = &top[0].result {
@@ -5,10 +5,38 @@ use std::sync::Mutex;
 
 use super::{Storage, StorageError};
 
+/// increment a hash field, or the overflow field (ARGV[4]) once the hash
+/// holds ARGV[3] fields and lacks ARGV[1]. ARGV[5] is HINCRBY or HINCRBYFLOAT
+const HASH_INCREMENT_CAPPED: &str = r#"
+local field = ARGV[1]
+if redis.call('HEXISTS', KEYS[1], field) == 0
+    and redis.call('HLEN', KEYS[1]) >= tonumber(ARGV[3]) then
+    field = ARGV[4]
+end
+redis.call(ARGV[5], KEYS[1], field, ARGV[2])
+if field == ARGV[1] then return 0 end
+return 1
+"#;
+
+/// increment a sorted set member, or the overflow member (ARGV[4]) once the
+/// set holds ARGV[3] members and lacks ARGV[1]
+const SORTED_SET_INCREMENT_CAPPED: &str = r#"
+local member = ARGV[1]
+if not redis.call('ZSCORE', KEYS[1], member)
+    and redis.call('ZCARD', KEYS[1]) >= tonumber(ARGV[3]) then
+    member = ARGV[4]
+end
+redis.call('ZINCRBY', KEYS[1], ARGV[2], member)
+if member == ARGV[1] then return 0 end
+return 1
+"#;
+
 /// redis storage backend.
 ///
 /// uses a sync redis connection behind a mutex.
-/// each trait method maps directly to one or two redis commands.
+/// each trait method maps directly to one or two redis commands;
+/// the bulk reads pipeline one command per key, and the capped increments
+/// run as lua scripts so their check and write are atomic.
 pub struct RedisStorage {
     conn: Mutex<Connection>,
 }
@@ -58,6 +86,54 @@ impl Storage for RedisStorage {
         Ok(result)
     }
 
+    fn hash_increment_capped(
+        &self,
+        key: &str,
+        field: &str,
+        amount: i64,
+        cap: usize,
+        overflow: &str,
+    ) -> Result<bool, StorageError> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let overflowed: i32 = redis::Script::new(HASH_INCREMENT_CAPPED)
+            .key(key)
+            .arg(field)
+            .arg(amount)
+            .arg(cap)
+            .arg(overflow)
+            .arg("HINCRBY")
+            .invoke(&mut *conn)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(overflowed == 1)
+    }
+
+    fn hash_increment_float_capped(
+        &self,
+        key: &str,
+        field: &str,
+        amount: f64,
+        cap: usize,
+        overflow: &str,
+    ) -> Result<bool, StorageError> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let overflowed: i32 = redis::Script::new(HASH_INCREMENT_CAPPED)
+            .key(key)
+            .arg(field)
+            .arg(amount)
+            .arg(cap)
+            .arg(overflow)
+            .arg("HINCRBYFLOAT")
+            .invoke(&mut *conn)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(overflowed == 1)
+    }
+
     fn hash_get_all(&self, key: &str) -> Result<HashMap<String, String>, StorageError> {
         let mut conn = self
             .conn
@@ -69,6 +145,28 @@ impl Storage for RedisStorage {
         Ok(result)
     }
 
+    fn hash_len(&self, key: &str) -> Result<usize, StorageError> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let result: usize = conn
+            .hlen(key)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(result)
+    }
+
+    fn hash_exists(&self, key: &str, field: &str) -> Result<bool, StorageError> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let result: bool = conn
+            .hexists(key, field)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(result)
+    }
+
     fn hyperloglog_add(&self, key: &str, value: &str) -> Result<bool, StorageError> {
         let mut conn = self
             .conn
@@ -91,6 +189,21 @@ impl Storage for RedisStorage {
         Ok(result)
     }
 
+    fn hyperloglog_count_union(&self, keys: &[String]) -> Result<u64, StorageError> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        // PFCOUNT with multiple keys counts the merged hyperloglog
+        let result: u64 = conn
+            .pfcount(keys)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(result)
+    }
+
     fn sorted_set_increment(
         &self,
         key: &str,
@@ -107,6 +220,29 @@ impl Storage for RedisStorage {
         Ok(result)
     }
 
+    fn sorted_set_increment_capped(
+        &self,
+        key: &str,
+        member: &str,
+        amount: f64,
+        cap: usize,
+        overflow: &str,
+    ) -> Result<bool, StorageError> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let overflowed: i32 = redis::Script::new(SORTED_SET_INCREMENT_CAPPED)
+            .key(key)
+            .arg(member)
+            .arg(amount)
+            .arg(cap)
+            .arg(overflow)
+            .invoke(&mut *conn)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(overflowed == 1)
+    }
+
     fn sorted_set_top(&self, key: &str, limit: usize) -> Result<Vec<(String, f64)>, StorageError> {
         let mut conn = self
             .conn
@@ -126,6 +262,65 @@ impl Storage for RedisStorage {
         Ok(result)
     }
 
+    fn sorted_set_union_top(
+        &self,
+        keys: &[String],
+        limit: usize,
+    ) -> Result<Vec<(String, f64)>, StorageError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        // union into a scratch key inside MULTI so it is never visible
+        let scratch = format!("{}:union:{:016x}", keys[0], rand::random::<u64>());
+        let (result,): (Vec<(String, f64)>,) = redis::pipe()
+            .atomic()
+            .cmd("ZUNIONSTORE")
+            .arg(&scratch)
+            .arg(keys.len())
+            .arg(keys)
+            .ignore()
+            .cmd("ZREVRANGEBYSCORE")
+            .arg(&scratch)
+            .arg("+inf")
+            .arg("-inf")
+            .arg("WITHSCORES")
+            .arg("LIMIT")
+            .arg(0)
+            .arg(limit)
+            .cmd("DEL")
+            .arg(&scratch)
+            .ignore()
+            .query(&mut *conn)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(result)
+    }
+
+    fn sorted_set_len(&self, key: &str) -> Result<usize, StorageError> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let result: usize = conn
+            .zcard(key)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(result)
+    }
+
+    fn sorted_set_score(&self, key: &str, member: &str) -> Result<Option<f64>, StorageError> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let result: Option<f64> = conn
+            .zscore(key, member)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(result)
+    }
+
     fn set_add(&self, key: &str, members: &[String]) -> Result<usize, StorageError> {
         if members.is_empty() {
             return Ok(0);
@@ -140,6 +335,31 @@ impl Storage for RedisStorage {
         Ok(result)
     }
 
+    fn set_add_new(&self, key: &str, members: &[String]) -> Result<Vec<String>, StorageError> {
+        if members.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        // one SADD per member, so each reply says whether that one was new
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for member in members {
+            pipe.sadd(key, member);
+        }
+        let added: Vec<usize> = pipe
+            .query(&mut *conn)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(members
+            .iter()
+            .zip(added)
+            .filter(|(_, added)| *added > 0)
+            .map(|(member, _)| member.clone())
+            .collect())
+    }
+
     fn set_members(&self, key: &str) -> Result<Vec<String>, StorageError> {
         let mut conn = self
             .conn
@@ -150,4 +370,188 @@ impl Storage for RedisStorage {
             .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
         Ok(result)
     }
+
+    fn set_len(&self, key: &str) -> Result<usize, StorageError> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let result: usize = conn
+            .scard(key)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(result)
+    }
+
+    fn set_remove(&self, key: &str, members: &[String]) -> Result<usize, StorageError> {
+        if members.is_empty() {
+            return Ok(0);
+        }
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let result: usize = conn
+            .srem(key, members)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(result)
+    }
+
+    fn expire(&self, key: &str, ttl_secs: u64) -> Result<bool, StorageError> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let result: bool = conn
+            .expire(key, ttl_secs as i64)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(result)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let result: bool = conn
+            .exists(key)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(result)
+    }
+
+    fn delete(&self, keys: &[String]) -> Result<usize, StorageError> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let result: usize = conn
+            .del(keys)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(result)
+    }
+
+    fn hash_get_all_many(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<HashMap<String, String>>, StorageError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.hgetall(key);
+        }
+        let result: Vec<HashMap<String, String>> = pipe
+            .query(&mut *conn)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(result)
+    }
+
+    fn hyperloglog_count_many(&self, keys: &[String]) -> Result<Vec<u64>, StorageError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        // one PFCOUNT per key; a single multi-key PFCOUNT would count the union
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.pfcount(key);
+        }
+        let result: Vec<u64> = pipe
+            .query(&mut *conn)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(result)
+    }
+
+    fn sorted_set_top_many(
+        &self,
+        keys: &[(String, usize)],
+    ) -> Result<Vec<Vec<(String, f64)>>, StorageError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let mut pipe = redis::pipe();
+        for (key, limit) in keys {
+            pipe.cmd("ZREVRANGEBYSCORE")
+                .arg(key)
+                .arg("+inf")
+                .arg("-inf")
+                .arg("WITHSCORES")
+                .arg("LIMIT")
+                .arg(0)
+                .arg(*limit);
+        }
+        let result: Vec<Vec<(String, f64)>> = pipe
+            .query(&mut *conn)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(result)
+    }
+
+    fn hash_len_many(&self, keys: &[String]) -> Result<Vec<usize>, StorageError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.hlen(key);
+        }
+        let result: Vec<usize> = pipe
+            .query(&mut *conn)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(result)
+    }
+
+    fn sorted_set_len_many(&self, keys: &[String]) -> Result<Vec<usize>, StorageError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.zcard(key);
+        }
+        let result: Vec<usize> = pipe
+            .query(&mut *conn)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(result)
+    }
+
+    fn exists_many(&self, keys: &[String]) -> Result<Vec<bool>, StorageError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        // one EXISTS per key; a multi-key EXISTS only returns how many exist
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.exists(key);
+        }
+        let result: Vec<bool> = pipe
+            .query(&mut *conn)
+            .map_err(|e| StorageError::OperationFailed(e.to_string()))?;
+        Ok(result)
+    }
 }
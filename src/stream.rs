@@ -0,0 +1,85 @@
+//! bucket-at-a-time query results.
+//!
+//! `Traqq::query_stream` reads one bucket per `next()` instead of building a
+//! whole `QueryResult`, so a year of buckets is never held at once. the
+//! range-wide merges (distinct counts, unioned tops) need every bucket, so a
+//! stream only carries per-bucket results.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{BucketType, DayResult, QueryFilter, Traqq};
+
+/// a window of each hash or leaderboard: fields are ranked by value, highest
+/// first (ties by name), and `limit` of them are kept after skipping `offset`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Page {
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// iterator over a range's buckets, read from storage (or the result cache)
+/// as it advances. an error ends the stream.
+pub struct DayStream<'a> {
+    traqq: &'a Traqq,
+    /// start of the next bucket to read, None once finished
+    next: Option<i64>,
+    max: i64,
+    granularity: BucketType,
+    filter: QueryFilter,
+    page: Option<Page>,
+}
+
+impl<'a> DayStream<'a> {
+    pub(crate) fn new(
+        traqq: &'a Traqq,
+        first: i64,
+        max: i64,
+        granularity: BucketType,
+        filter: QueryFilter,
+    ) -> Self {
+        Self {
+            traqq,
+            next: Some(first),
+            max,
+            granularity,
+            filter,
+            page: None,
+        }
+    }
+
+    /// page every hash and leaderboard in the results
+    pub fn page(mut self, page: Page) -> Self {
+        self.page = Some(page);
+        self
+    }
+}
+
+impl Iterator for DayStream<'_> {
+    type Item = Result<DayResult, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let current = self.next.take().filter(|start| *start <= self.max)?;
+
+        let day = self
+            .traqq
+            .query_bucket(current, self.granularity, &self.filter)
+            .and_then(|day| {
+                self.next = Some(
+                    self.traqq
+                        .config
+                        .next_bucket_start(current, self.granularity)?,
+                );
+                Ok(day)
+            });
+
+        Some(day.map(|mut day| {
+            if let Some(page) = self.page {
+                for result in &mut day.results {
+                    *result = result.page(page.offset, page.limit);
+                }
+            }
+            day
+        }))
+    }
+}
@@ -29,14 +29,17 @@ mod tests {
             time: TimeConfig {
                 store_hourly: false,
                 timezone: "UTC".to_string(),
+                ..TimeConfig::default()
             },
             mapping: MappingConfig {
                 bitmap: vec!["ip".to_string()],
                 add: generate_add_patterns(complexity),
                 add_value: generate_value_patterns(complexity),
                 top: vec![],
+                ..MappingConfig::default()
             },
             limits: LimitsConfig::default(),
+            alerts: alert::AlertsConfig::default(),
         }
     }
 
@@ -176,6 +179,7 @@ mod tests {
             time: TimeConfig {
                 store_hourly: true,
                 timezone: "UTC".to_string(),
+                ..TimeConfig::default()
             },
             mapping: MappingConfig {
                 bitmap: vec![
@@ -205,8 +209,10 @@ mod tests {
                     },
                 ],
                 top: vec![],
+                ..MappingConfig::default()
             },
             limits: LimitsConfig::default(),
+            alerts: alert::AlertsConfig::default(),
         }
     }
 
@@ -425,9 +431,9 @@ mod tests {
             println!("Add Value Metrics: {}", processed.add_value_metrics.len());
             println!("Total Ops: {}", processed.commands.len());
 
-            assert!(processed.bitmap_metrics.len() > 0);
-            assert!(processed.add_metrics.len() > 0);
-            assert!(processed.add_value_metrics.len() > 0);
+            assert!(!processed.bitmap_metrics.is_empty());
+            assert!(!processed.add_metrics.is_empty());
+            assert!(!processed.add_value_metrics.is_empty());
         }
 
         #[test]
@@ -436,6 +442,7 @@ mod tests {
                 time: TimeConfig {
                     store_hourly: true,
                     timezone: "UTC".to_string(),
+                    ..TimeConfig::default()
                 },
                 mapping: MappingConfig {
                     bitmap: vec!["ip".to_string()],
@@ -445,8 +452,10 @@ mod tests {
                         add_key: "amount".to_string(),
                     }],
                     top: vec![],
+                    ..MappingConfig::default()
                 },
                 limits: LimitsConfig::default(),
+                alerts: alert::AlertsConfig::default(),
             };
 
             let event = IncomingEvent {
@@ -481,6 +490,119 @@ mod tests {
                 assert!(cmd.metadata.add_key.is_none());
             }
         }
+        #[test]
+        fn test_wildcard_pattern_expansion() {
+            let config = TraqqConfig {
+                time: TimeConfig {
+                    store_hourly: false,
+                    timezone: "UTC".to_string(),
+                    ..TimeConfig::default()
+                },
+                mapping: MappingConfig {
+                    bitmap: vec![],
+                    add: vec!["event~utm_*".to_string(), "event~utm_source".to_string()],
+                    add_value: vec![],
+                    top: vec!["utm_*".to_string()],
+                    ..MappingConfig::default()
+                },
+                limits: LimitsConfig::default(),
+                alerts: alert::AlertsConfig::default(),
+            };
+            config.validate().unwrap();
+
+            let event = IncomingEvent {
+                event: "visit".to_string(),
+                properties: serde_json::json!({
+                    "utm_source": "google",
+                    "utm_medium": "cpc",
+                    "geo": "US",
+                }),
+            };
+
+            let processed = ProcessedEvent::from_incoming(event, &config).unwrap();
+
+            let mut add_keys: Vec<String> = processed
+                .commands
+                .iter()
+                .filter(|c| c.metadata.metric_type == "add")
+                .map(|c| c.key.rsplit(':').next().unwrap().to_string())
+                .collect();
+            add_keys.sort();
+            // the explicit event~utm_source duplicate is only counted once
+            assert_eq!(add_keys, vec!["event~utm_medium", "event~utm_source"]);
+
+            let top_keys: Vec<&str> = processed
+                .commands
+                .iter()
+                .filter(|c| c.metadata.metric_type == "top")
+                .map(|c| c.key.rsplit(':').next().unwrap())
+                .collect();
+            assert_eq!(top_keys.len(), 2);
+            assert!(!processed.raw_properties.contains_key("geo"));
+        }
+
+        #[test]
+        fn test_wildcard_pattern_validation() {
+            let mut config = TraqqConfig::default();
+            config.mapping.add = vec!["event~*".to_string()];
+            assert!(config.validate().is_err());
+
+            let mut config = TraqqConfig::default();
+            config.mapping.add_value = vec![AddValueConfig {
+                key: "event".to_string(),
+                add_key: "amount_*".to_string(),
+            }];
+            assert!(config.validate().is_err());
+
+            let mut config = TraqqConfig::default();
+            config.limits.max_pattern_expansions = 0;
+            assert!(config.validate().is_err());
+        }
+
+        #[test]
+        fn test_wildcard_expansion_skips_unsafe_names_and_is_capped() {
+            let mut config = TraqqConfig::default();
+            config.mapping.add = vec!["event~utm_*".to_string(), "utm_*~utm_*".to_string()];
+            config.limits.max_field_length = 16;
+            config.limits.max_pattern_expansions = 3;
+            config.validate().unwrap();
+
+            let event = IncomingEvent {
+                event: "visit".to_string(),
+                properties: serde_json::json!({
+                    "utm_a": "1",
+                    "utm_b": "2",
+                    "utm_c": "3",
+                    "utm:src": "google",
+                    "utm_~x": "y",
+                    "utm_much_too_long_a_name": "z",
+                }),
+            };
+            let processed = ProcessedEvent::from_incoming(event, &config).unwrap();
+
+            let add_keys: Vec<&str> = processed
+                .commands
+                .iter()
+                .filter(|c| c.metadata.metric_type == "add")
+                .map(|c| c.key.splitn(4, ':').nth(3).unwrap())
+                .collect();
+            for key in &add_keys {
+                assert_eq!(key.split(':').count(), 1, "unsafe name in {}", key);
+                assert!(!key.contains("too_long"), "long name in {}", key);
+            }
+            // utm_*~utm_* has six orderings; the first three are a~b, a~c
+            // and b~a, the last a repeat of the first
+            assert_eq!(
+                add_keys,
+                vec![
+                    "event~utm_a",
+                    "event~utm_b",
+                    "event~utm_c",
+                    "utm_a~utm_b",
+                    "utm_a~utm_c",
+                ]
+            );
+        }
     }
 
     mod traqq_service_tests {
@@ -497,14 +619,17 @@ mod tests {
                 time: TimeConfig {
                     store_hourly: false,
                     timezone: "UTC".to_string(),
+                    ..TimeConfig::default()
                 },
                 mapping: MappingConfig {
                     bitmap: vec!["ip".to_string()],
                     add: vec!["event".to_string()],
                     add_value: vec![],
                     top: vec![],
+                    ..MappingConfig::default()
                 },
                 limits: LimitsConfig::default(),
+                alerts: alert::AlertsConfig::default(),
             };
 
             let t = make_traqq(config);
@@ -545,14 +670,17 @@ mod tests {
                 time: TimeConfig {
                     store_hourly: false,
                     timezone: "UTC".to_string(),
+                    ..TimeConfig::default()
                 },
                 mapping: MappingConfig {
                     bitmap: vec!["ip".to_string()],
                     add: vec!["event".to_string()],
                     add_value: vec![],
                     top: vec![],
+                    ..MappingConfig::default()
                 },
                 limits: LimitsConfig::default(),
+                alerts: alert::AlertsConfig::default(),
             };
 
             let t = make_traqq(config);
@@ -602,6 +730,7 @@ mod tests {
                 time: TimeConfig {
                     store_hourly: false,
                     timezone: "UTC".to_string(),
+                    ..TimeConfig::default()
                 },
                 mapping: MappingConfig {
                     bitmap: vec![],
@@ -611,8 +740,10 @@ mod tests {
                         add_key: "amount".to_string(),
                     }],
                     top: vec![],
+                    ..MappingConfig::default()
                 },
                 limits: LimitsConfig::default(),
+                alerts: alert::AlertsConfig::default(),
             };
 
             let t = make_traqq(config);
@@ -666,14 +797,17 @@ mod tests {
                 time: TimeConfig {
                     store_hourly: false,
                     timezone: "UTC".to_string(),
+                    ..TimeConfig::default()
                 },
                 mapping: MappingConfig {
                     bitmap: vec!["ip".to_string()],
                     add: vec!["event".to_string()],
                     add_value: vec![],
                     top: vec![],
+                    ..MappingConfig::default()
                 },
                 limits: LimitsConfig::default(),
+                alerts: alert::AlertsConfig::default(),
             };
 
             let t = make_traqq(config);
@@ -710,9 +844,11 @@ mod tests {
                 time: TimeConfig {
                     store_hourly: false,
                     timezone: "Not/A/Timezone".to_string(),
+                    ..TimeConfig::default()
                 },
                 mapping: MappingConfig::default(),
                 limits: LimitsConfig::default(),
+                alerts: alert::AlertsConfig::default(),
             };
 
             let result = Traqq::new(config, Box::new(MemoryStorage::new()), "test");
@@ -725,14 +861,17 @@ mod tests {
                 time: TimeConfig {
                     store_hourly: false,
                     timezone: "UTC".to_string(),
+                    ..TimeConfig::default()
                 },
                 mapping: MappingConfig {
                     bitmap: vec![],
                     add: vec![],
                     add_value: vec![],
                     top: vec!["geo".to_string(), "offer".to_string()],
+                    ..MappingConfig::default()
                 },
                 limits: LimitsConfig::default(),
+                alerts: alert::AlertsConfig::default(),
             };
 
             let t = make_traqq(config);
@@ -788,6 +927,7 @@ mod tests {
                 time: TimeConfig {
                     store_hourly: false,
                     timezone: "UTC".to_string(),
+                    ..TimeConfig::default()
                 },
                 mapping: MappingConfig {
                     bitmap: vec![],
@@ -797,8 +937,10 @@ mod tests {
                         add_key: "amount".to_string(),
                     }],
                     top: vec![],
+                    ..MappingConfig::default()
                 },
                 limits: LimitsConfig::default(),
+                alerts: alert::AlertsConfig::default(),
             };
 
             let t = make_traqq(config);
@@ -871,14 +1013,17 @@ mod tests {
                 time: TimeConfig {
                     store_hourly: false,
                     timezone: "UTC".to_string(),
+                    ..TimeConfig::default()
                 },
                 mapping: MappingConfig {
                     bitmap: vec![],
                     add: vec!["event~offer".to_string()],
                     add_value: vec![],
                     top: vec![],
+                    ..MappingConfig::default()
                 },
                 limits: LimitsConfig::default(),
+                alerts: alert::AlertsConfig::default(),
             };
 
             // event~offer pattern requires both fields, but we rely on
@@ -922,6 +1067,7 @@ mod tests {
                 time: TimeConfig {
                     store_hourly: false,
                     timezone: "UTC".to_string(),
+                    ..TimeConfig::default()
                 },
                 mapping: MappingConfig {
                     bitmap: vec![],
@@ -932,8 +1078,10 @@ mod tests {
                     ],
                     add_value: vec![],
                     top: vec![],
+                    ..MappingConfig::default()
                 },
                 limits: LimitsConfig::default(),
+                alerts: alert::AlertsConfig::default(),
             };
 
             let event = IncomingEvent {
@@ -969,6 +1117,7 @@ mod tests {
                 time: TimeConfig {
                     store_hourly: false,
                     timezone: "UTC".to_string(),
+                    ..TimeConfig::default()
                 },
                 mapping: MappingConfig {
                     bitmap: vec!["ip".to_string()],
@@ -978,8 +1127,10 @@ mod tests {
                         add_key: "amount".to_string(),
                     }],
                     top: vec!["geo".to_string()],
+                    ..MappingConfig::default()
                 },
                 limits: LimitsConfig::default(),
+                alerts: alert::AlertsConfig::default(),
             };
 
             let t = make_traqq(config);
@@ -1080,11 +1231,135 @@ mod tests {
             assert!((sum - 174.99).abs() < 0.01);
             assert_eq!(count, 3);
         }
+
+        #[test]
+        fn test_cardinality_cap_routes_new_values_to_overflow() {
+            let config = TraqqConfig {
+                time: TimeConfig {
+                    store_hourly: false,
+                    timezone: "UTC".to_string(),
+                    ..TimeConfig::default()
+                },
+                mapping: MappingConfig {
+                    bitmap: vec![],
+                    add: vec!["geo".to_string()],
+                    add_value: vec![],
+                    top: vec!["geo".to_string()],
+                    ..MappingConfig::default()
+                },
+                limits: LimitsConfig {
+                    max_pattern_cardinality: 2,
+                    ..LimitsConfig::default()
+                },
+                alerts: alert::AlertsConfig::default(),
+            };
+
+            let t = make_traqq(config);
+
+            for geo in ["US", "UK", "FR", "DE", "US"] {
+                t.record(IncomingEvent {
+                    event: "sale".to_string(),
+                    properties: serde_json::json!({ "geo": geo }),
+                })
+                .unwrap();
+            }
+
+            let now = Utc::now().timestamp();
+            let day_start = now - (now % 86400);
+            let result = t.query(day_start, day_start).unwrap();
+
+            let add = result.find_str("add/geo");
+            if let MetricData::Hash(h) = &add[0].result {
+                assert_eq!(h.get("US"), Some(&2));
+                assert_eq!(h.get("UK"), Some(&1));
+                assert_eq!(h.get(OVERFLOW_FIELD), Some(&2));
+                assert_eq!(h.len(), 3);
+            } else {
+                panic!("expected Hash data for add");
+            }
+
+            let top = result.find_str("top/geo");
+            if let MetricData::Ranked(pairs) = &top[0].result {
+                assert_eq!(pairs.len(), 3);
+                assert!(pairs.iter().any(|(m, s)| m == OVERFLOW_FIELD && *s == 2.0));
+            } else {
+                panic!("expected Ranked data for top");
+            }
+
+            assert_eq!(result.warnings.len(), 2, "add and top should both warn");
+        }
+
+        #[test]
+        fn test_cardinality_cap_per_pattern() {
+            use chrono::TimeZone;
+
+            let config = TraqqConfig {
+                mapping: MappingConfig {
+                    add: vec!["geo".to_string(), "os".to_string(), "offer".to_string()],
+                    ..MappingConfig::default()
+                },
+                limits: LimitsConfig {
+                    max_pattern_cardinality: 1,
+                    pattern_cardinality: HashMap::from([
+                        ("o*".to_string(), 2),
+                        ("offer".to_string(), 0),
+                    ]),
+                    ..LimitsConfig::default()
+                },
+                ..TraqqConfig::default()
+            };
+            let t = make_traqq(config);
+
+            let at = Utc.with_ymd_and_hms(2026, 9, 10, 12, 0, 0).unwrap();
+            for value in ["a", "b", "c"] {
+                t.record_at(
+                    IncomingEvent {
+                        event: "sale".to_string(),
+                        properties: serde_json::json!({ "geo": value, "os": value, "offer": value }),
+                    },
+                    at,
+                )
+                .unwrap();
+            }
+
+            let result = t.query(at.timestamp(), at.timestamp()).unwrap();
+            let fields = |query: &str| match &result.find_str(query)[0].result {
+                MetricData::Hash(h) => h.len(),
+                other => panic!("expected Hash data, got {:?}", other),
+            };
+            // the global cap, the glob and the exact pattern that lifts it
+            assert_eq!(fields("add/geo"), 2);
+            assert_eq!(fields("add/os"), 3);
+            assert_eq!(fields("add/offer"), 3);
+        }
+
+        #[test]
+        fn test_config_without_newer_fields_deserializes() {
+            let config: TraqqConfig = serde_json::from_value(serde_json::json!({
+                "time": { "store_hourly": true, "timezone": "UTC" },
+                "mapping": { "bitmap": ["ip"], "add": ["event"], "add_value": [], "top": [] },
+                "limits": {
+                    "max_field_length": 128,
+                    "max_value_length": 512,
+                    "max_combinations": 1000,
+                    "max_metrics_per_event": 1000
+                }
+            }))
+            .unwrap();
+
+            assert_eq!(config.time.minute_ttl, constants::MINUTE_BUCKET_TTL);
+            assert_eq!(
+                config.limits.query_cache_bytes,
+                constants::QUERY_CACHE_BYTES
+            );
+            assert!(config.mapping.top_depth.is_empty());
+            config.validate().unwrap();
+        }
     }
 
     mod query_tests {
         use super::*;
-        use crate::storage::memory::MemoryStorage;
+        use crate::storage::memory::{Clock, MemoryStorage};
 
         fn make_traqq(config: TraqqConfig) -> Traqq {
             Traqq::new(config, Box::new(MemoryStorage::new()), "q").unwrap()
@@ -1095,6 +1370,7 @@ mod tests {
                 time: TimeConfig {
                     store_hourly: false,
                     timezone: "UTC".to_string(),
+                    ..TimeConfig::default()
                 },
                 mapping: MappingConfig {
                     bitmap: vec!["ip".to_string()],
@@ -1104,41 +1380,36 @@ mod tests {
                         add_key: "amount".to_string(),
                     }],
                     top: vec!["geo".to_string()],
+                    ..MappingConfig::default()
                 },
                 limits: LimitsConfig::default(),
+                alerts: alert::AlertsConfig::default(),
             }
         }
 
         fn record_test_events(t: &Traqq) {
-            t.record(IncomingEvent {
-                event: "sale".to_string(),
-                properties: serde_json::json!({
-                    "ip": "1.1.1.1",
-                    "geo": "US",
-                    "amount": 100.0,
-                }),
-            })
-            .unwrap();
-
-            t.record(IncomingEvent {
-                event: "sale".to_string(),
-                properties: serde_json::json!({
-                    "ip": "2.2.2.2",
-                    "geo": "US",
-                    "amount": 50.0,
-                }),
-            })
-            .unwrap();
+            record_test_events_at(t, Utc::now());
+        }
 
-            t.record(IncomingEvent {
-                event: "click".to_string(),
-                properties: serde_json::json!({
-                    "ip": "1.1.1.1",
-                    "geo": "UK",
-                    "amount": 25.0,
-                }),
-            })
-            .unwrap();
+        fn record_test_events_at(t: &Traqq, at: DateTime<Utc>) {
+            for (event, ip, geo, amount) in [
+                ("sale", "1.1.1.1", "US", 100.0),
+                ("sale", "2.2.2.2", "US", 50.0),
+                ("click", "1.1.1.1", "UK", 25.0),
+            ] {
+                t.record_at(
+                    IncomingEvent {
+                        event: event.to_string(),
+                        properties: serde_json::json!({
+                            "ip": ip,
+                            "geo": geo,
+                            "amount": amount,
+                        }),
+                    },
+                    at,
+                )
+                .unwrap();
+            }
         }
 
         #[test]
@@ -1166,10 +1437,11 @@ mod tests {
             let result = t.query(day_start, day_start).unwrap();
 
             let bmp = result.find(FindOptions {
-                metric_type: "bmp".to_string(),
+                metric_type: MetricType::Bmp,
                 key: "ip".to_string(),
                 add_key: None,
                 merge: false,
+                top: None,
             });
 
             assert_eq!(bmp.len(), 1);
@@ -1190,10 +1462,11 @@ mod tests {
             let result = t.query(day_start, day_start).unwrap();
 
             let add = result.find(FindOptions {
-                metric_type: "add".to_string(),
+                metric_type: MetricType::Add,
                 key: "event".to_string(),
                 add_key: None,
                 merge: false,
+                top: None,
             });
 
             assert_eq!(add.len(), 1);
@@ -1215,10 +1488,11 @@ mod tests {
             let result = t.query(day_start, day_start).unwrap();
 
             let top = result.find(FindOptions {
-                metric_type: "top".to_string(),
+                metric_type: MetricType::Top,
                 key: "geo".to_string(),
                 add_key: None,
                 merge: false,
+                top: None,
             });
 
             assert_eq!(top.len(), 1);
@@ -1243,10 +1517,11 @@ mod tests {
 
             // find adv summaries (they have Summary data)
             let adv_results = result.find(FindOptions {
-                metric_type: "adv".to_string(),
+                metric_type: MetricType::Adv,
                 key: "event~geo".to_string(),
                 add_key: Some("amount".to_string()),
                 merge: false,
+                top: None,
             });
 
             // should have both per-label and summary results
@@ -1274,10 +1549,11 @@ mod tests {
             let result = t.query(day_start, day_start).unwrap();
 
             let adv_results = result.find(FindOptions {
-                metric_type: "adv".to_string(),
+                metric_type: MetricType::Adv,
                 key: "event~geo".to_string(),
                 add_key: Some("amount".to_string()),
                 merge: false,
+                top: None,
             });
 
             let labels: Vec<_> = adv_results
@@ -1345,10 +1621,11 @@ mod tests {
             let result = t.query(day_start - 86400, day_start).unwrap();
 
             let merged = result.find(FindOptions {
-                metric_type: "add".to_string(),
+                metric_type: MetricType::Add,
                 key: "event".to_string(),
                 add_key: None,
                 merge: true,
+                top: None,
             });
 
             // merge should produce exactly one result
@@ -1362,100 +1639,1789 @@ mod tests {
         }
 
         #[test]
-        fn test_query_find_merge_top() {
+        fn test_query_find_merge_bmp_counts_range_uniques() {
+            use chrono::TimeZone;
+
             let t = make_traqq(test_config());
-            record_test_events(&t);
+            let first = Utc.with_ymd_and_hms(2026, 9, 7, 12, 0, 0).unwrap();
+            for day in 0..3 {
+                record_sale_at(&t, "1.1.1.1", first + chrono::Duration::days(day));
+            }
+            record_sale_at(&t, "2.2.2.2", first);
 
-            let now = Utc::now().timestamp();
-            let day_start = now - (now % 86400);
-            let result = t.query(day_start - 86400, day_start).unwrap();
+            let result = t
+                .query(first.timestamp(), first.timestamp() + 2 * 86400)
+                .unwrap();
+            assert_eq!(result.find_str("bmp/ip").len(), 3);
 
+            // a returning visitor is one unique over the range, not three
             let merged = result.find(FindOptions {
-                metric_type: "top".to_string(),
-                key: "geo".to_string(),
+                metric_type: MetricType::Bmp,
+                key: "ip".to_string(),
                 add_key: None,
                 merge: true,
+                top: None,
             });
-
-            assert_eq!(merged.len(), 1);
-            if let MetricData::Ranked(pairs) = &merged[0].result {
-                assert_eq!(pairs[0].0, "US");
-                assert!((pairs[0].1 - 2.0).abs() < f64::EPSILON);
-            } else {
-                panic!("expected Ranked data after merge");
+            match &merged[0].result {
+                MetricData::Count(c) => assert_eq!(*c, 2),
+                other => panic!("expected Count data, got {:?}", other),
             }
-        }
-    }
+            assert_eq!(result.unique_counts.get("ip"), Some(&2));
 
-    mod server_client_tests {
-        use super::*;
-        use crate::client::Client;
-        use crate::server;
-        use crate::storage::memory::MemoryStorage;
-        use std::sync::Arc;
-        use std::thread;
-        use std::time::Duration;
+            // finds that don't merge skip the range-wide unions
+            let (min, max) = (first.timestamp(), first.timestamp() + 2 * 86400);
+            let opts = FindOptions::parse("bmp/ip").unwrap();
+            let unmerged = t
+                .query_filtered(min, max, BucketType::Daily, &QueryFilter::from_find(&opts))
+                .unwrap();
+            assert!(unmerged.unique_counts.is_empty());
+            assert_eq!(unmerged.find_str("bmp/ip").len(), 3);
+
+            let opts = FindOptions {
+                merge: true,
+                ..opts
+            };
+            let merged = t
+                .query_filtered(min, max, BucketType::Daily, &QueryFilter::from_find(&opts))
+                .unwrap();
+            assert_eq!(merged.unique_counts.get("ip"), Some(&2));
+        }
 
         #[test]
-        fn test_server_client_record_and_query() {
-            let config = TraqqConfig {
-                time: TimeConfig {
-                    store_hourly: false,
-                    timezone: "UTC".to_string(),
-                },
-                mapping: MappingConfig {
-                    bitmap: vec!["ip".to_string()],
-                    add: vec!["event".to_string()],
-                    add_value: vec![],
-                    top: vec!["geo".to_string()],
-                },
-                limits: LimitsConfig::default(),
+        fn test_top_depth_per_pattern_and_query() {
+            let mut config = test_config();
+            config.mapping.top_depth.insert("geo".to_string(), 2);
+            let t = make_traqq(config);
+            for geo in ["US", "US", "US", "UK", "UK", "DE"] {
+                t.record(IncomingEvent {
+                    event: "sale".to_string(),
+                    properties: serde_json::json!({ "geo": geo }),
+                })
+                .unwrap();
+            }
+
+            let ranked = |result: &MetricResult| match &result.result {
+                MetricData::Ranked(pairs) => pairs.clone(),
+                other => panic!("expected Ranked data, got {:?}", other),
             };
 
-            let traqq =
-                Arc::new(Traqq::new(config, Box::new(MemoryStorage::new()), "srv").unwrap());
+            // the pattern's declared depth
+            let now = Utc::now().timestamp();
+            let result = t.query(now - 86400, now).unwrap();
+            assert_eq!(ranked(&result.find_str("top/geo")[0]).len(), 2);
 
-            // start server on a random-ish port
-            let addr = "127.0.0.1:19876";
-            let server_traqq = Arc::clone(&traqq);
-            let _server_handle = thread::spawn(move || {
-                let _ = server::run(server_traqq, addr);
+            // a query override reads deeper
+            let filter = QueryFilter {
+                top: Some(10),
+                ..QueryFilter::default()
+            };
+            let deep = t
+                .query_filtered(now - 86400, now, BucketType::Daily, &filter)
+                .unwrap();
+            assert_eq!(ranked(&deep.find_str("top/geo")[0]).len(), 3);
+
+            // merged results are cut to the requested N
+            let merged = deep.find(FindOptions {
+                metric_type: MetricType::Top,
+                key: "geo".to_string(),
+                merge: true,
+                top: Some(1),
+                ..FindOptions::default()
             });
+            assert_eq!(ranked(&merged[0]), vec![("US".to_string(), 3.0)]);
+        }
 
-            // give the server a moment to bind
-            thread::sleep(Duration::from_millis(50));
+        #[test]
+        fn test_merged_top_unions_full_leaderboards() {
+            use chrono::TimeZone;
 
-            let mut client = Client::connect(addr).unwrap();
+            let mut config = test_config();
+            config.mapping.top_depth.insert("geo".to_string(), 1);
+            let t = make_traqq(config);
 
-            // record events
-            let r = client
-                .record(serde_json::json!({
-                    "event": "sale",
-                    "ip": "1.1.1.1",
-                    "geo": "US",
-                }))
-                .unwrap();
-            assert!(r.success, "record should succeed");
+            // FR is second every day but first over both days
+            let first = Utc.with_ymd_and_hms(2026, 9, 7, 12, 0, 0).unwrap();
+            let second = first + chrono::Duration::days(1);
+            for (at, geo, times) in [
+                (first, "US", 5),
+                (first, "FR", 4),
+                (second, "UK", 5),
+                (second, "FR", 4),
+            ] {
+                for _ in 0..times {
+                    t.record_at(
+                        IncomingEvent {
+                            event: "sale".to_string(),
+                            properties: serde_json::json!({ "geo": geo }),
+                        },
+                        at,
+                    )
+                    .unwrap();
+                }
+            }
 
-            let r = client
-                .record(serde_json::json!({
-                    "event": "click",
-                    "ip": "2.2.2.2",
-                    "geo": "UK",
-                }))
-                .unwrap();
-            assert!(r.success);
+            let result = t.query(first.timestamp(), second.timestamp()).unwrap();
+            let merged = result.find(FindOptions {
+                metric_type: MetricType::Top,
+                key: "geo".to_string(),
+                merge: true,
+                ..FindOptions::default()
+            });
+            match &merged[0].result {
+                MetricData::Ranked(pairs) => assert_eq!(pairs, &vec![("FR".to_string(), 8.0)]),
+                other => panic!("expected Ranked data, got {:?}", other),
+            }
+        }
 
-            // query days
-            let r = client.query_days(1).unwrap();
-            assert!(r.success, "query_days should succeed");
-            assert!(r.data.is_some(), "query should return data");
+        #[test]
+        fn test_top_depth_requires_known_pattern() {
+            let mut config = test_config();
+            config.mapping.top_depth.insert("city".to_string(), 10);
+            assert!(config.validate().unwrap_err().contains("city"));
 
-            // find specific metrics
-            let now = chrono::Utc::now().timestamp();
-            let day_start = now - (now % 86400);
+            config.mapping.top_depth = HashMap::from([("geo".to_string(), 0)]);
+            assert!(config.validate().is_err());
+        }
 
-            let r = client
+        #[test]
+        fn test_top_depth_globs() {
+            let mut config = test_config();
+            config.mapping.top.push("event~geo".to_string());
+            config.mapping.top_depth = HashMap::from([("c*".to_string(), 10)]);
+            assert!(config.validate().unwrap_err().contains("c*"));
+
+            // the most specific key wins, whatever the map's order
+            config.mapping.top_depth = HashMap::from([
+                ("*".to_string(), 5),
+                ("geo~*".to_string(), 7),
+                ("geo".to_string(), 3),
+            ]);
+            config.validate().unwrap();
+            let t = make_traqq(config);
+            assert_eq!(t.top_depth("geo"), 3);
+            assert_eq!(t.top_depth("event~geo"), 7);
+            assert_eq!(t.top_depth("city"), 5);
+        }
+
+        #[test]
+        fn test_query_filter_rules() {
+            let filter = QueryFilter {
+                accept: vec!["add/event~*".to_string(), "adv/*/amount".to_string()],
+                ignore: vec!["*/geo~*".to_string()],
+                ..QueryFilter::default()
+            };
+
+            assert!(filter.allows("add:event~os"));
+            assert!(filter.allows("adv:amount:event~os:i"));
+            assert!(!filter.allows("add:event"));
+            assert!(!filter.allows("top:event~os"));
+            assert!(!filter.allows("adv:price:event~os"));
+            // compound patterns match in any order, so this is also geo~event
+            assert!(!filter.allows("add:event~geo"));
+
+            let find = QueryFilter::from_find(&FindOptions {
+                metric_type: MetricType::Add,
+                key: "geo~event".to_string(),
+                add_key: None,
+                merge: false,
+                top: None,
+            });
+            assert!(find.allows("add:event~geo"));
+            assert!(!find.allows("add:event"));
+            assert!(QueryFilter::default().allows("bmp:ip"));
+        }
+
+        #[test]
+        fn test_query_filtered_reads_only_allowed_keys() {
+            let t = make_traqq(test_config());
+            record_test_events(&t);
+
+            let now = Utc::now().timestamp();
+            let filter = QueryFilter {
+                accept: vec!["add/*".to_string(), "top/geo".to_string()],
+                ignore: vec!["add/event~*".to_string()],
+                ..QueryFilter::default()
+            };
+            let result = t
+                .query_filtered(now, now, BucketType::Daily, &filter)
+                .unwrap();
+
+            let mut read: Vec<String> = result.days[0]
+                .results
+                .iter()
+                .map(|r| format!("{}/{}", r.metric_type, r.key.join("~")))
+                .collect();
+            read.sort();
+            assert_eq!(read, vec!["add/event", "top/geo"]);
+        }
+
+        #[test]
+        fn test_series_zero_fills_gaps() {
+            use chrono::TimeZone;
+
+            let t = make_traqq(test_config());
+            let first = Utc.with_ymd_and_hms(2026, 9, 7, 12, 0, 0).unwrap();
+            record_sale_at(&t, "1.1.1.1", first);
+            record_sale_at(&t, "2.2.2.2", first);
+            record_sale_at(&t, "1.1.1.1", first + chrono::Duration::days(2));
+
+            let day = |n: i64| first.timestamp() - 12 * 3600 + n * 86400;
+            let result = t.query(day(0), day(2)).unwrap();
+
+            assert_eq!(
+                result.series("add/event", "sale").unwrap(),
+                vec![(day(0), 2.0), (day(1), 0.0), (day(2), 1.0)]
+            );
+            assert_eq!(
+                result.series("bmp/ip", "").unwrap(),
+                vec![(day(0), 2.0), (day(1), 0.0), (day(2), 1.0)]
+            );
+
+            let fields = ["sum".to_string(), "avg".to_string()];
+            let amounts = result.series_many("adv/event~geo/amount", &fields).unwrap();
+            assert_eq!(amounts[0].field, "sum");
+            assert_eq!(amounts[0].points[0], (day(0), 20.0));
+            assert_eq!(amounts[1].points[0], (day(0), 10.0));
+            assert_eq!(amounts[1].points[1], (day(1), 0.0));
+
+            assert!(result.series("nonsense", "sale").is_err());
+            assert!(result.series("ad/event", "sale").is_err());
+        }
+
+        #[test]
+        fn test_compare_periods() {
+            use crate::compare::FieldStatus;
+            use chrono::TimeZone;
+
+            let t = make_traqq(test_config());
+            let record = |event: &str, geo: &str, day: u32| {
+                t.record_at(
+                    IncomingEvent {
+                        event: event.to_string(),
+                        properties: serde_json::json!({ "ip": "1.1.1.1", "geo": geo }),
+                    },
+                    Utc.with_ymd_and_hms(2026, 9, day, 12, 0, 0).unwrap(),
+                )
+                .unwrap();
+            };
+            for (event, geo) in [
+                ("sale", "US"),
+                ("sale", "DE"),
+                ("sale", "DE"),
+                ("refund", "DE"),
+            ] {
+                record(event, geo, 7);
+            }
+            for (event, geo) in [
+                ("sale", "US"),
+                ("sale", "US"),
+                ("sale", "US"),
+                ("click", "FR"),
+            ] {
+                record(event, geo, 8);
+            }
+            record("sale", "US", 8);
+
+            let comparison = t.compare("2026-09-08", "2026-09-07").unwrap();
+
+            let events = comparison.metric("add/event").unwrap().unwrap();
+            let sale = events.field("sale").unwrap();
+            assert_eq!((sale.current, sale.previous, sale.delta), (4.0, 3.0, 1.0));
+            assert!((sale.percent.unwrap() - 100.0 / 3.0).abs() < 1e-9);
+            assert_eq!(events.field("click").unwrap().status, FieldStatus::New);
+            assert_eq!(events.field("click").unwrap().percent, None);
+            let refund = events.field("refund").unwrap();
+            assert_eq!(refund.status, FieldStatus::Disappeared);
+            assert_eq!(refund.percent, Some(-100.0));
+
+            // leaderboard order, with members that dropped out last
+            let geo = comparison.metric("top/geo").unwrap().unwrap();
+            let order: Vec<&str> = geo.fields.iter().map(|f| f.field.as_str()).collect();
+            assert_eq!(order, vec!["US", "FR", "DE"]);
+            assert_eq!(geo.fields[0].rank_change(), Some(1));
+            assert_eq!(geo.fields[1].previous_rank, None);
+            assert_eq!(geo.fields[2].previous_rank, Some(1));
+
+            let uniques = comparison
+                .metric("bmp/ip")
+                .unwrap()
+                .unwrap()
+                .field("count")
+                .unwrap();
+            assert_eq!(uniques.delta, 0.0);
+            assert_eq!(uniques.status, FieldStatus::Matched);
+            assert!(comparison.metric("ad/event").is_err());
+
+            assert!(t.compare("2026-09-08", "someday").is_err());
+        }
+
+        #[test]
+        fn test_compare_adv_fields_and_summary() {
+            use crate::compare::FieldStatus;
+            use chrono::TimeZone;
+
+            let t = make_traqq(test_config());
+            for (geo, amount, day) in [("US", 100.0, 7), ("US", 30.0, 8), ("DE", 20.0, 8)] {
+                t.record_at(
+                    IncomingEvent {
+                        event: "sale".to_string(),
+                        properties: serde_json::json!({ "geo": geo, "amount": amount }),
+                    },
+                    Utc.with_ymd_and_hms(2026, 9, day, 12, 0, 0).unwrap(),
+                )
+                .unwrap();
+            }
+
+            let comparison = t.compare("2026-09-08", "2026-09-07").unwrap();
+
+            let fields = comparison.metric("adv/event~geo/amount").unwrap().unwrap();
+            assert!(!fields.summary);
+            let us = fields.field("sale~US").unwrap();
+            assert_eq!((us.current, us.previous, us.delta), (30.0, 100.0, -70.0));
+            assert_eq!(fields.field("sale~DE").unwrap().status, FieldStatus::New);
+            assert!(fields.field("sum").is_none());
+
+            let summary = comparison
+                .metric_summary("adv/event~geo/amount")
+                .unwrap()
+                .unwrap();
+            assert!(summary.summary);
+            let sum = summary.field("sum").unwrap();
+            assert_eq!((sum.current, sum.previous), (50.0, 100.0));
+            let count = summary.field("count").unwrap();
+            assert_eq!((count.current, count.previous), (2.0, 1.0));
+            assert!(comparison.metric_summary("add/event").unwrap().is_none());
+        }
+
+        #[test]
+        fn test_compare_reads_leaderboards_deeper_than_shown() {
+            use crate::compare::FieldStatus;
+            use chrono::TimeZone;
+
+            // shown 1 deep, read 2 deep
+            let mut config = test_config();
+            config.mapping.top_depth = HashMap::from([("geo".to_string(), 1)]);
+            let t = make_traqq(config);
+            let record = |geo: &str, times: usize, day: u32| {
+                for _ in 0..times {
+                    t.record_at(
+                        IncomingEvent {
+                            event: "sale".to_string(),
+                            properties: serde_json::json!({ "geo": geo }),
+                        },
+                        Utc.with_ymd_and_hms(2026, 9, day, 12, 0, 0).unwrap(),
+                    )
+                    .unwrap();
+                }
+            };
+            for (geo, times) in [("DE", 3), ("US", 2), ("FR", 1)] {
+                record(geo, times, 7);
+            }
+            for (geo, times) in [("FR", 3), ("DE", 2)] {
+                record(geo, times, 8);
+            }
+
+            let comparison = t.compare("2026-09-08", "2026-09-07").unwrap();
+            let geo = comparison.metric("top/geo").unwrap().unwrap();
+            let order: Vec<&str> = geo.fields.iter().map(|f| f.field.as_str()).collect();
+            assert_eq!(order, vec!["FR", "DE"]);
+
+            // DE fell to second but was still read, so it keeps its ranks
+            let de = geo.field("DE").unwrap();
+            assert_eq!(de.status, FieldStatus::Matched);
+            assert_eq!((de.rank, de.previous_rank), (Some(2), Some(1)));
+            assert_eq!(de.rank_change(), Some(-1));
+
+            // FR was below the read depth before, not absent
+            let fr = geo.field("FR").unwrap();
+            assert_eq!(fr.status, FieldStatus::BelowDepth);
+            assert_eq!((fr.previous_rank, fr.percent), (None, None));
+        }
+
+        #[test]
+        fn test_closed_buckets_are_cached_until_backfilled() {
+            use chrono::TimeZone;
+
+            let mut config = test_config();
+            config.limits.query_cache_bytes = 64 << 20;
+            let t = make_traqq(config);
+            let day = Utc.with_ymd_and_hms(2026, 9, 7, 12, 0, 0).unwrap();
+            record_sale_at(&t, "1.1.1.1", day);
+
+            let start = day.timestamp() - 12 * 3600;
+            let sales =
+                |t: &Traqq| match &t.query(start, start).unwrap().find_str("add/event")[0].result {
+                    MetricData::Hash(h) => h["sale"],
+                    other => panic!("expected Hash data, got {:?}", other),
+                };
+
+            assert_eq!(sales(&t), 1);
+            assert_eq!(sales(&t), 1);
+            let stats = t.cache_stats();
+            assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+
+            // a write behind the cache's back is not seen...
+            t.storage
+                .hash_increment(&format!("q:add:d:{}:event", start), "sale", 5)
+                .unwrap();
+            assert_eq!(sales(&t), 1);
+
+            // ...but recording into the bucket invalidates it
+            record_sale_at(&t, "2.2.2.2", day);
+            assert_eq!(sales(&t), 7);
+            assert_eq!(t.cache_stats().invalidations, 1);
+
+            // today is still open and never cached
+            let now = Utc::now().timestamp();
+            t.query(now, now).unwrap();
+            assert_eq!(t.cache_stats().entries, 1);
+        }
+
+        #[test]
+        fn test_query_cache_is_off_by_default() {
+            let config = test_config();
+            assert_eq!(config.limits.query_cache_bytes, 0);
+            let t = make_traqq(config);
+
+            t.query(0, 86400).unwrap();
+            t.query(0, 86400).unwrap();
+            assert_eq!(t.cache_stats(), crate::cache::CacheStats::default());
+        }
+
+        #[test]
+        fn test_typed_query_builder() {
+            use crate::query::{Query, Summary};
+            use chrono::TimeZone;
+
+            let t = make_traqq(test_config());
+            let first = Utc.with_ymd_and_hms(2026, 9, 7, 12, 0, 0).unwrap();
+            record_sale_at(&t, "1.1.1.1", first);
+            record_sale_at(&t, "2.2.2.2", first + chrono::Duration::days(1));
+            record_sale_at(&t, "1.1.1.1", first + chrono::Duration::days(1));
+
+            let range = "2026-09-07..2026-09-08";
+            let daily = Query::add("event").range(range).run(&t).unwrap();
+            let counts: Vec<(Option<i64>, i64)> = daily
+                .rows
+                .iter()
+                .map(|r| (r.bucket, r.value["sale"]))
+                .collect();
+            let day = first.timestamp() - 12 * 3600;
+            assert_eq!(counts, vec![(Some(day), 1), (Some(day + 86400), 2)]);
+
+            let uniques = Query::bmp("ip").range(range).merge().run(&t).unwrap();
+            assert_eq!(uniques.rows.len(), 1);
+            assert_eq!(uniques.rows[0].bucket, None);
+            assert_eq!(uniques.rows[0].value, 2);
+
+            // compound patterns match in any order; summaries and per-field
+            // sums share a pattern but come back separately
+            let summary = Query::summary("geo~event", "amount")
+                .range(range)
+                .merge()
+                .run(&t)
+                .unwrap();
+            assert_eq!(
+                summary.rows[0].value,
+                Summary {
+                    sum: 30.0,
+                    count: 3
+                }
+            );
+            assert_eq!(summary.rows[0].value.avg(), 10.0);
+            let sums = Query::adv("event~geo", "amount")
+                .range(range)
+                .merge()
+                .run(&t)
+                .unwrap();
+            assert_eq!(sums.rows[0].value["sale~US"], 30.0);
+
+            let top = Query::leaderboard("geo")
+                .between(day, day + 86400)
+                .merge()
+                .top(1)
+                .run(&t)
+                .unwrap();
+            assert_eq!(top.rows[0].value, vec![("US".to_string(), 3.0)]);
+        }
+
+        #[test]
+        fn test_typed_query_validates_patterns() {
+            use crate::query::Query;
+
+            let t = make_traqq(test_config());
+            let err = Query::add("evnt").run(&t).unwrap_err();
+            assert_eq!(
+                err, "no add mapping matches 'evnt' (configured: event)",
+                "{}",
+                err
+            );
+            let err = Query::adv("event~geo", "price").run(&t).unwrap_err();
+            assert!(err.contains("event~geo/amount"), "{}", err);
+            assert!(Query::bmp("geo").run(&t).is_err());
+            assert!(Query::leaderboard("ge*").run(&t).is_err());
+            assert!(Query::add("event").range("someday").run(&t).is_err());
+
+            assert_eq!("addv".parse::<MetricType>(), Ok(MetricType::Adv));
+            assert!("tpo"
+                .parse::<MetricType>()
+                .unwrap_err()
+                .contains("expected"));
+            assert_eq!(
+                Query::leaderboard("geo").merge().find_options().metric_type,
+                MetricType::Top
+            );
+        }
+
+        #[test]
+        fn test_query_str() {
+            use chrono::TimeZone;
+
+            let mut config = test_config();
+            config.mapping.add = vec!["event".to_string(), "event~ip".to_string()];
+            let t = make_traqq(config);
+            let day = Utc.with_ymd_and_hms(2026, 9, 7, 12, 0, 0).unwrap();
+            let sales = [
+                ("purchase", "US", 10.0, 0),
+                ("purchase", "US", 30.0, 1),
+                ("purchase", "DE", 5.0, 1),
+                ("refund", "US", 7.0, 1),
+            ];
+            for (event, geo, amount, offset) in sales {
+                t.record_at(
+                    IncomingEvent {
+                        event: event.to_string(),
+                        properties: serde_json::json!({ "ip": "1.1.1.1", "geo": geo, "amount": amount }),
+                    },
+                    day + chrono::Duration::days(offset),
+                )
+                .unwrap();
+            }
+            let range = "2026-09-07..2026-09-08";
+            let rows = |q: &str| -> Vec<(Vec<String>, f64)> {
+                t.query_str(&format!("{} {}", q, range))
+                    .unwrap()
+                    .rows
+                    .into_iter()
+                    .map(|r| (r.group, r.value))
+                    .collect()
+            };
+
+            assert_eq!(
+                rows("sum adv/event~geo/amount where event=purchase group by geo"),
+                vec![
+                    (vec!["US".to_string()], 40.0),
+                    (vec!["DE".to_string()], 5.0)
+                ]
+            );
+            assert_eq!(
+                rows("count add/event~ip where ip=1.1.1.1 group by event top 1"),
+                vec![(vec!["purchase".to_string()], 3.0)]
+            );
+            assert_eq!(rows("sum add/event where event=pur*"), vec![(vec![], 3.0)]);
+            assert_eq!(rows("avg adv/event~geo/amount"), vec![(vec![], 13.0)]);
+            // adv reads pattern then add_key, as in find_str
+            assert!(t.query_str("sum adv/amount/event~geo").is_err());
+            assert_eq!(rows("uniques bmp/ip"), vec![(vec![], 1.0)]);
+
+            let per_day = t
+                .query_str(&format!(
+                    "sum add/event where event=refund {} per day",
+                    range
+                ))
+                .unwrap();
+            let points: Vec<(Option<i64>, f64)> =
+                per_day.rows.iter().map(|r| (r.bucket, r.value)).collect();
+            let start = day.timestamp() - 12 * 3600;
+            assert_eq!(points, vec![(Some(start), 0.0), (Some(start + 86400), 1.0)]);
+
+            let err = t
+                .query_str("sum add/event~ip group by country")
+                .unwrap_err();
+            assert!(err.contains("unknown dimension 'country'"), "{}", err);
+            let err = t.query_str("sum add/geo").unwrap_err();
+            assert!(err.contains("no add mapping matches 'geo'"), "{}", err);
+            let err = t.query_str("sum add/event last fortnight").unwrap_err();
+            assert!(err.starts_with("invalid range"), "{}", err);
+        }
+
+        #[test]
+        fn test_query_stream() {
+            use chrono::TimeZone;
+
+            let t = make_traqq(test_config());
+            let day = Utc.with_ymd_and_hms(2026, 9, 7, 12, 0, 0).unwrap();
+            for (event, offset) in [("sale", 0), ("sale", 2), ("click", 2), ("view", 2)] {
+                t.record_at(
+                    IncomingEvent {
+                        event: event.to_string(),
+                        properties: serde_json::json!({ "ip": "1.1.1.1" }),
+                    },
+                    day + chrono::Duration::days(offset),
+                )
+                .unwrap();
+            }
+
+            let (min, max) = (day.timestamp(), day.timestamp() + 2 * 86400);
+            let filter = QueryFilter {
+                accept: vec!["add/event".to_string()],
+                ..QueryFilter::default()
+            };
+            let whole = t
+                .query_filtered(min, max, BucketType::Daily, &filter)
+                .unwrap();
+            let streamed: Vec<DayResult> = t
+                .query_stream(min, max, BucketType::Daily, &filter)
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(streamed.len(), 3);
+            for (a, b) in whole.days.iter().zip(&streamed) {
+                assert_eq!(a.timestamp, b.timestamp);
+                assert_eq!(a.results.len(), b.results.len());
+            }
+
+            // pages rank fields by count, ties by name
+            let page = |offset, limit| -> Vec<String> {
+                let last = t
+                    .query_stream(min, max, BucketType::Daily, &filter)
+                    .unwrap()
+                    .page(crate::stream::Page { offset, limit })
+                    .last()
+                    .unwrap()
+                    .unwrap();
+                let MetricData::Hash(h) = &last.results[0].result else {
+                    panic!("expected a hash");
+                };
+                let mut fields: Vec<String> = h.keys().cloned().collect();
+                fields.sort();
+                fields
+            };
+            assert_eq!(page(0, 2), vec!["click", "sale"]);
+            assert_eq!(page(2, 2), vec!["view"]);
+            assert!(page(3, 2).is_empty());
+
+            let mut hourly = test_config();
+            hourly.time.store_hourly = false;
+            let err = make_traqq(hourly)
+                .query_stream(min, max, BucketType::Hourly, &filter)
+                .err()
+                .unwrap();
+            assert!(err.contains("not stored"), "{}", err);
+        }
+
+        #[test]
+        fn test_anomalies() {
+            use chrono::TimeZone;
+
+            let mut config = test_config();
+            config.mapping.anomaly.insert(
+                "add/ev*".to_string(),
+                crate::anomaly::AnomalyConfig {
+                    window: 7,
+                    min_points: 7,
+                    ..Default::default()
+                },
+            );
+            let t = make_traqq(config.clone());
+            let first = Utc.with_ymd_and_hms(2026, 9, 1, 12, 0, 0).unwrap();
+            // 3 or 4 sales a day, then 20 on the 15th
+            for day in 0..15 {
+                let sales = if day == 14 { 20 } else { 3 + day % 2 };
+                for _ in 0..sales {
+                    t.record_at(
+                        IncomingEvent {
+                            event: "sale".to_string(),
+                            properties: serde_json::json!({ "ip": "1.1.1.1" }),
+                        },
+                        first + chrono::Duration::days(day),
+                    )
+                    .unwrap();
+                }
+            }
+
+            let fields = vec!["sale".to_string()];
+            let reports = t
+                .anomalies("add/event", &fields, "2026-09-15", BucketType::Daily)
+                .unwrap();
+            assert_eq!(reports.len(), 1);
+            assert_eq!(reports[0].config.window, 7);
+            let spike = &reports[0].anomalies;
+            assert_eq!(spike.len(), 1);
+            assert_eq!(spike[0].value, 20.0);
+            assert!(spike[0].high < 20.0 && spike[0].expected < 4.0);
+
+            // the window reaches back before the range, and the days before are normal
+            let quiet = t
+                .anomalies(
+                    "add/event",
+                    &fields,
+                    "2026-09-08..2026-09-14",
+                    BucketType::Daily,
+                )
+                .unwrap();
+            assert!(quiet[0].anomalies.is_empty());
+
+            config.mapping.anomaly.insert(
+                "add/event".to_string(),
+                crate::anomaly::AnomalyConfig {
+                    window: 1,
+                    ..Default::default()
+                },
+            );
+            let err = config.validate().unwrap_err();
+            assert!(err.contains("anomaly window"), "{}", err);
+        }
+
+        #[test]
+        fn test_anomalies_project_the_open_bucket() {
+            let t = make_traqq(test_config());
+            let now = Utc::now();
+            let record = |sales: usize, at: DateTime<Utc>| {
+                for _ in 0..sales {
+                    t.record_at(
+                        IncomingEvent {
+                            event: "sale".to_string(),
+                            properties: serde_json::json!({ "ip": "1.1.1.1" }),
+                        },
+                        at,
+                    )
+                    .unwrap();
+                }
+            };
+            for day in 1..=8 {
+                record(
+                    if day % 2 == 0 { 8 } else { 12 },
+                    now - chrono::Duration::days(day),
+                );
+            }
+
+            // today is on pace for a normal day, so its partial count isn't low
+            let today = t
+                .config
+                .bucket_start(now.timestamp(), BucketType::Daily)
+                .unwrap();
+            let elapsed = (now.timestamp() - today) as f64 / 86400.0;
+            record((10.0 * elapsed).round() as usize, now);
+            let fields = vec!["sale".to_string()];
+            let reports = t
+                .anomalies("add/event", &fields, "today", BucketType::Daily)
+                .unwrap();
+            assert!(reports[0].anomalies.is_empty(), "{:?}", reports);
+
+            // until enough of it has passed the open bucket isn't scored at all
+            record(100, now);
+            let reports = t
+                .anomalies("add/event", &fields, "today", BucketType::Daily)
+                .unwrap();
+            if elapsed >= constants::ANOMALY_MIN_ELAPSED {
+                assert_eq!(reports[0].anomalies.len(), 1);
+                assert!(reports[0].anomalies[0].projected);
+            } else {
+                assert!(reports[0].anomalies.is_empty());
+            }
+
+            let err = t
+                .anomalies("top/geo", &[], "today", BucketType::Daily)
+                .unwrap_err();
+            assert!(err.contains("fields are required"), "{}", err);
+        }
+
+        #[test]
+        fn test_anomaly_config_prefers_the_most_specific_glob() {
+            let mut mapping = MappingConfig::default();
+            for (rule, window) in [("add/*", 3), ("add/ev*", 5), ("a*/event", 4)] {
+                mapping.anomaly.insert(
+                    rule.to_string(),
+                    crate::anomaly::AnomalyConfig {
+                        window,
+                        min_points: 2,
+                        ..Default::default()
+                    },
+                );
+            }
+
+            let window = |metric: &str| crate::anomaly::config_for(&mapping, metric).window;
+            // "add/ev*" and "a*/event" both match; the one with more literal characters wins
+            assert_eq!(window("add/event"), 4);
+            assert_eq!(window("add/evt"), 5);
+            assert_eq!(window("add/geo"), 3);
+        }
+
+        #[test]
+        fn test_alert_rules() {
+            use crate::alert::{AlertRule, Comparison, Evaluator};
+            use chrono::TimeZone;
+
+            let rule =
+                |name: &str, field: &str, per: Option<&str>, comparison, threshold| AlertRule {
+                    name: name.to_string(),
+                    metric: "add/event".to_string(),
+                    field: field.to_string(),
+                    per: per.map(String::from),
+                    window: 3600,
+                    comparison,
+                    threshold,
+                    cooldown: 1800,
+                    granularity: None,
+                };
+            let mut config = test_config();
+            config.time.store_hourly = true;
+            config.alerts.rules = vec![
+                rule("few_sales", "sale", None, Comparison::Below, 5.0),
+                rule("errors", "error", Some("*"), Comparison::Above, 0.05),
+            ];
+            config.validate().unwrap();
+
+            let t = make_traqq(config.clone());
+            let start = Utc.with_ymd_and_hms(2026, 9, 7, 12, 10, 0).unwrap();
+            let record = |event: &str, times: usize, at: i64| {
+                for _ in 0..times {
+                    t.record_at(
+                        IncomingEvent {
+                            event: event.to_string(),
+                            properties: serde_json::json!({ "ip": "1.1.1.1" }),
+                        },
+                        start + chrono::Duration::seconds(at),
+                    )
+                    .unwrap();
+                }
+            };
+            let mut evaluator = Evaluator::new(config.alerts.rules.clone());
+            let mut fired = |at: i64| -> Vec<String> {
+                evaluator
+                    .evaluate(&t, start.timestamp() + at)
+                    .firings
+                    .into_iter()
+                    .map(|f| f.rule)
+                    .collect()
+            };
+
+            record("sale", 3, 0);
+            record("error", 1, 0);
+            assert_eq!(fired(60), vec!["few_sales", "errors"]);
+            // both are cooling down
+            assert!(fired(120).is_empty());
+
+            // 1 error in 14 events is still above 5%
+            record("sale", 10, 100);
+            assert_eq!(fired(1900), vec!["errors"]);
+
+            // two hours on nothing has happened: no sales, and no rate without events
+            assert_eq!(fired(2 * 3600 + 60), vec!["few_sales"]);
+
+            let message = Evaluator::new(vec![rule(
+                "errors",
+                "error",
+                Some("*"),
+                Comparison::Above,
+                0.05,
+            )])
+            .evaluate(&t, start.timestamp() + 1900)
+            .firings
+            .remove(0)
+            .message;
+            assert_eq!(
+                message,
+                "errors: add/event error per * over the last 3600s is 0.07142857142857142 (> 0.05)"
+            );
+
+            // a rule that can't be read doesn't swallow the firings before it
+            let evaluation = Evaluator::new(vec![
+                rule("few_sales", "sale", None, Comparison::Below, 100.0),
+                AlertRule {
+                    granularity: Some(BucketType::Minute),
+                    ..rule("broken", "sale", None, Comparison::Below, 1.0)
+                },
+            ])
+            .evaluate(&t, start.timestamp() + 60);
+            assert_eq!(evaluation.firings.len(), 1);
+            assert_eq!(evaluation.firings[0].rule, "few_sales");
+            assert_eq!(evaluation.errors.len(), 1);
+            assert!(
+                evaluation.errors[0].starts_with("broken: "),
+                "{:?}",
+                evaluation.errors
+            );
+
+            config
+                .alerts
+                .rules
+                .push(rule("errors", "error", None, Comparison::Above, 1.0));
+            assert_eq!(
+                config.validate().unwrap_err(),
+                "duplicate alert rule: errors"
+            );
+            config.alerts.rules = vec![AlertRule {
+                granularity: Some(BucketType::Minute),
+                ..rule("per_minute", "sale", None, Comparison::Below, 1.0)
+            }];
+            let err = config.validate().unwrap_err();
+            assert!(err.contains("minute buckets are not stored"), "{}", err);
+        }
+
+        #[test]
+        fn test_catalog() {
+            use chrono::TimeZone;
+
+            let t = make_traqq(test_config());
+            let first = Utc.with_ymd_and_hms(2026, 9, 1, 12, 0, 0).unwrap();
+            let record = |event: &str, geo: &str, day: i64| {
+                t.record_at(
+                    IncomingEvent {
+                        event: event.to_string(),
+                        properties: serde_json::json!({
+                            "ip": "1.1.1.1",
+                            "geo": geo,
+                            "amount": 5.0,
+                        }),
+                    },
+                    first + chrono::Duration::days(day),
+                )
+                .unwrap();
+            };
+            record("sale", "US", 0);
+            record("click", "US", 0);
+            record("sale", "CA", 1);
+
+            let catalog = t.catalog("2026-09-01..2026-09-03").unwrap();
+            assert_eq!(catalog.days.len(), 3);
+            assert_eq!(catalog.days[0].date, "2026-09-01");
+            // nothing was recorded on the third day
+            assert!(catalog.days[2].metrics.is_empty());
+
+            let day: Vec<(String, Option<usize>)> = catalog.days[0]
+                .metrics
+                .iter()
+                .map(|e| (e.metric(), e.fields))
+                .collect();
+            assert_eq!(
+                day,
+                vec![
+                    ("add/event".to_string(), Some(2)),
+                    ("adv/event~geo/amount".to_string(), Some(2)),
+                    ("bmp/ip".to_string(), None),
+                    ("top/geo".to_string(), Some(1)),
+                ]
+            );
+
+            assert_eq!(
+                catalog.metrics(),
+                vec!["add/event", "adv/event~geo/amount", "bmp/ip", "top/geo"]
+            );
+            assert_eq!(catalog.patterns("adv"), vec!["event~geo"]);
+            assert_eq!(catalog.cardinality("add/event"), Some(2));
+            assert_eq!(catalog.cardinality("bmp/ip"), None);
+
+            // an expired key is left out while its tracking set lives on
+            let day_1 = t
+                .config
+                .bucket_start(
+                    (first + chrono::Duration::days(1)).timestamp(),
+                    BucketType::Daily,
+                )
+                .unwrap();
+            t.storage
+                .delete(&[
+                    format!("q:bmp:d:{}:ip", day_1),
+                    format!("q:top:d:{}:geo", day_1),
+                ])
+                .unwrap();
+            let catalog = t.catalog("2026-09-02").unwrap();
+            let metrics: Vec<String> = catalog.days[0].metrics.iter().map(|e| e.metric()).collect();
+            assert_eq!(metrics, vec!["add/event", "adv/event~geo/amount"]);
+
+            assert!(t.catalog("someday").is_err());
+        }
+
+        #[test]
+        fn test_dimension_drill_down() {
+            let hash: HashMap<String, i64> = [
+                ("purchase~US", 4),
+                ("purchase~DE", 2),
+                ("refund~US", 1),
+                ("__other__", 3),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+            let result = MetricResult {
+                metric_type: "add".to_string(),
+                key: vec!["event".to_string(), "geo".to_string()],
+                location: "q:add:d:0:event~geo".to_string(),
+                add_key: None,
+                result: MetricData::Hash(hash),
+            };
+
+            assert_eq!(
+                result.dimensions("purchase~US"),
+                vec![("event", "purchase"), ("geo", "US")]
+            );
+            assert_eq!(
+                result.dimensions("__other__"),
+                vec![("event", "__other__"), ("geo", "__other__")]
+            );
+
+            let by_geo = result.group_by(&["geo"]).unwrap();
+            assert_eq!(by_geo.key, vec!["geo".to_string()]);
+            let MetricData::Hash(h) = &by_geo.result else {
+                panic!("expected a hash, got {:?}", by_geo.result);
+            };
+            assert_eq!((h["US"], h["DE"], h["__other__"]), (5, 2, 3));
+
+            let purchases = result.filter_dimension("event", "pur*").unwrap();
+            let MetricData::Hash(h) = purchases.group_by(&[]).unwrap().result else {
+                panic!("expected a hash");
+            };
+            assert_eq!(h.len(), 1);
+            assert_eq!(h[""], 6);
+
+            // reordering the key reorders the fields
+            let swapped = result.group_by(&["geo", "event"]).unwrap();
+            let MetricData::Hash(h) = &swapped.result else {
+                panic!("expected a hash");
+            };
+            assert_eq!(h["US~refund"], 1);
+
+            let err = result.group_by(&["country"]).unwrap_err();
+            assert_eq!(
+                err,
+                "unknown dimension 'country': add/event~geo has event, geo"
+            );
+        }
+
+        #[test]
+        fn test_query_find_merge_top() {
+            let t = make_traqq(test_config());
+            record_test_events(&t);
+
+            let now = Utc::now().timestamp();
+            let day_start = now - (now % 86400);
+            let result = t.query(day_start - 86400, day_start).unwrap();
+
+            let merged = result.find(FindOptions {
+                metric_type: MetricType::Top,
+                key: "geo".to_string(),
+                add_key: None,
+                merge: true,
+                top: None,
+            });
+
+            assert_eq!(merged.len(), 1);
+            if let MetricData::Ranked(pairs) = &merged[0].result {
+                assert_eq!(pairs[0].0, "US");
+                assert!((pairs[0].1 - 2.0).abs() < f64::EPSILON);
+            } else {
+                panic!("expected Ranked data after merge");
+            }
+        }
+        #[test]
+        fn test_query_hours() {
+            let mut config = test_config();
+            config.time.store_hourly = true;
+            let t = make_traqq(config);
+            // 2026-09-14 10:30:15 UTC
+            let at = DateTime::from_timestamp(1789381815, 0).unwrap();
+            record_test_events_at(&t, at);
+
+            let now = at.timestamp();
+            let hour_start = now - (now % 3600);
+            let result = t.query_hours(hour_start - 7200, now).unwrap();
+
+            assert_eq!(result.days.len(), 3, "should return three hour buckets");
+            assert_eq!(result.days[2].timestamp, hour_start);
+            assert!(result.days[0].results.is_empty());
+
+            let current = &result.days[2];
+            assert!(
+                current.date.ends_with(":00:00+00:00"),
+                "got {}",
+                current.date
+            );
+            assert!(current.results.iter().all(|r| r.location.contains(":h:")));
+
+            let add = result.find_str("add/event");
+            assert_eq!(add.len(), 1);
+            if let MetricData::Hash(h) = &add[0].result {
+                assert_eq!(h.get("sale"), Some(&2));
+            } else {
+                panic!("expected Hash data for add");
+            }
+            assert!(result.try_find_str("ad/event").is_err());
+            assert!(result.try_find_str("add").is_err());
+        }
+
+        #[test]
+        fn test_query_hours_requires_hourly_storage() {
+            let t = make_traqq(test_config());
+            assert!(t.query_hours(0, 3600).is_err());
+            assert!(t.query_by(0, 3600, BucketType::Daily).is_ok());
+        }
+
+        #[test]
+        fn test_query_minutes() {
+            let mut config = test_config();
+            config.time.store_minutely = true;
+            let t = make_traqq(config);
+            let at = DateTime::from_timestamp(1789381815, 0).unwrap();
+            record_test_events_at(&t, at);
+
+            let now = at.timestamp();
+            let minute_start = now - (now % 60);
+            let result = t.query_minutes(minute_start - 120, now).unwrap();
+
+            assert_eq!(result.days.len(), 3, "should return three minute buckets");
+            let current = &result.days[2];
+            assert_eq!(current.timestamp, minute_start);
+            assert!(current.results.iter().all(|r| r.location.contains(":m:")));
+
+            let bmp = result.find_str("bmp/ip");
+            assert_eq!(bmp.len(), 1);
+            if let MetricData::Count(c) = &bmp[0].result {
+                assert_eq!(*c, 2);
+            } else {
+                panic!("expected Count data for bmp");
+            }
+
+            // daily buckets are unaffected by minute storage
+            let daily = t.query(now - (now % 86400), now).unwrap();
+            assert!(daily.days[0]
+                .results
+                .iter()
+                .all(|r| r.location.contains(":d:")));
+        }
+
+        #[test]
+        fn test_minute_buckets_expire() {
+            let mut config = test_config();
+            config.time.store_minutely = true;
+            config.time.minute_ttl = 0;
+            assert!(config.validate().unwrap_err().contains("minute_ttl"));
+
+            config.time.minute_ttl = 1;
+            let clock = Clock::new();
+            let t = Traqq::new(
+                config,
+                Box::new(MemoryStorage::with_clock(clock.clone())),
+                "q",
+            )
+            .unwrap();
+            let at = DateTime::from_timestamp(1789381815, 0).unwrap();
+            record_test_events_at(&t, at);
+            clock.advance(std::time::Duration::from_secs(2));
+
+            let now = at.timestamp();
+            let result = t.query_minutes(now - 60, now).unwrap();
+            assert!(result.days.iter().all(|d| d.results.is_empty()));
+
+            // daily data is kept
+            let daily = t.query(now - (now % 86400), now).unwrap();
+            assert!(!daily.days[0].results.is_empty());
+        }
+
+        #[test]
+        fn test_retention_expires_bucket_keys() {
+            let mut config = test_config();
+            config.time.store_hourly = true;
+            config.time.daily_retention = 1;
+            let clock = Clock::new();
+            let t = Traqq::new(
+                config,
+                Box::new(MemoryStorage::with_clock(clock.clone())),
+                "q",
+            )
+            .unwrap();
+            let at = DateTime::from_timestamp(1789381815, 0).unwrap();
+            record_test_events_at(&t, at);
+
+            let now = at.timestamp();
+            let today = now - (now % 86400);
+            let keys_key = format!("q:k:d:{}", today);
+            assert!(!t.storage.set_members(&keys_key).unwrap().is_empty());
+
+            clock.advance(std::time::Duration::from_secs(2));
+
+            // daily keys and their tracking set are gone, hourly ones are kept
+            assert!(t.storage.set_members(&keys_key).unwrap().is_empty());
+            assert!(t.query(today, now).unwrap().days[0].results.is_empty());
+            let hours = t.query_hours(now, now).unwrap();
+            assert!(!hours.days[0].results.is_empty());
+        }
+
+        #[test]
+        fn test_retention_starts_when_a_key_is_created() {
+            let mut config = test_config();
+            config.time.daily_retention = 10;
+            let clock = Clock::new();
+            let t = Traqq::new(
+                config,
+                Box::new(MemoryStorage::with_clock(clock.clone())),
+                "q",
+            )
+            .unwrap();
+            let at = DateTime::from_timestamp(1789381815, 0).unwrap();
+            let record = |properties: serde_json::Value| {
+                t.record_at(
+                    IncomingEvent {
+                        event: "sale".to_string(),
+                        properties,
+                    },
+                    at,
+                )
+                .unwrap();
+            };
+            let today = at.timestamp() - at.timestamp() % 86400;
+            let events_key = format!("q:add:d:{}:event", today);
+            let geo_key = format!("q:top:d:{}:geo", today);
+
+            record(serde_json::json!({ "ip": "1.1.1.1" }));
+            clock.advance(std::time::Duration::from_secs(6));
+            // creates the geo key and counts into the existing event key
+            record(serde_json::json!({ "ip": "1.1.1.1", "geo": "US" }));
+            clock.advance(std::time::Duration::from_secs(5));
+
+            // the event key kept its first ttl, the geo key has its own
+            assert!(!t.storage.exists(&events_key).unwrap());
+            assert!(t.storage.exists(&geo_key).unwrap());
+        }
+
+        #[test]
+        fn test_weekly_and_monthly_rollups() {
+            let mut config = test_config();
+            config.time.store_weekly = true;
+            config.time.store_monthly = true;
+            let t = make_traqq(config);
+            record_test_events(&t);
+
+            let now = Utc::now().timestamp();
+            for granularity in [BucketType::Weekly, BucketType::Monthly] {
+                let result = t.query_by(now, now, granularity).unwrap();
+                assert_eq!(result.days.len(), 1);
+                assert!(result.days[0].timestamp <= now);
+
+                let bmp = result.find_str("bmp/ip");
+                assert_eq!(
+                    bmp.len(),
+                    1,
+                    "{} rollup should hold bmp",
+                    granularity.name()
+                );
+                if let MetricData::Count(c) = &bmp[0].result {
+                    assert_eq!(*c, 2);
+                } else {
+                    panic!("expected Count data for bmp");
+                }
+            }
+        }
+
+        #[test]
+        fn test_week_and_month_bucket_boundaries() {
+            let mut config = test_config();
+            config.time.timezone = "America/New_York".to_string();
+
+            // 2026-09-17 15:00 EDT, a thursday
+            let ts = 1789671600;
+            let week = config.bucket_start(ts, BucketType::Weekly).unwrap();
+            let month = config.bucket_start(ts, BucketType::Monthly).unwrap();
+
+            // monday 2026-09-14 and 2026-09-01, both 00:00 EDT
+            assert_eq!(week, 1789358400);
+            assert_eq!(month, 1788235200);
+            assert_eq!(config.bucket_label(week, BucketType::Weekly), "2026-W38");
+            assert_eq!(config.bucket_label(month, BucketType::Monthly), "2026-09");
+
+            // october starts 30 days later
+            assert_eq!(
+                config
+                    .next_bucket_start(month, BucketType::Monthly)
+                    .unwrap(),
+                month + 30 * 86400
+            );
+        }
+
+        #[test]
+        fn test_query_auto_picks_coarsest_exact_granularity() {
+            let mut config = test_config();
+            config.time.store_weekly = true;
+            config.time.store_monthly = true;
+            let t = make_traqq(config);
+
+            // 2026-09-01 .. 2026-09-30 is exactly one month
+            let sept_1 = 1788220800;
+            let sept_30 = sept_1 + 29 * 86400;
+            let result = t.query_auto(sept_1, sept_30).unwrap();
+            assert_eq!(result.days.len(), 1);
+            assert_eq!(result.days[0].date, "2026-09");
+
+            // monday 2026-09-07 .. sunday 2026-09-20 is exactly two weeks
+            let sept_7 = sept_1 + 6 * 86400;
+            let result = t.query_auto(sept_7, sept_7 + 13 * 86400).unwrap();
+            let labels: Vec<&str> = result.days.iter().map(|d| d.date.as_str()).collect();
+            assert_eq!(labels, vec!["2026-W37", "2026-W38"]);
+
+            // a partial week falls back to days
+            let result = t.query_auto(sept_7, sept_7 + 2 * 86400).unwrap();
+            assert_eq!(result.days.len(), 3);
+            assert_eq!(result.days[0].date, "2026-09-07");
+        }
+
+        #[test]
+        fn test_query_auto_mixes_months_weeks_and_days() {
+            use chrono::TimeZone;
+
+            let mut config = test_config();
+            config.time.store_weekly = true;
+            config.time.store_monthly = true;
+            let t = make_traqq(config);
+            for (month, day, ip) in [
+                (8, 30, "1.1.1.1"),
+                (9, 15, "1.1.1.1"),
+                (10, 2, "2.2.2.2"),
+                (10, 14, "1.1.1.1"),
+            ] {
+                t.record_at(
+                    IncomingEvent {
+                        event: "sale".to_string(),
+                        properties: serde_json::json!({ "ip": ip }),
+                    },
+                    Utc.with_ymd_and_hms(2026, month, day, 12, 0, 0).unwrap(),
+                )
+                .unwrap();
+            }
+
+            // sunday 2026-08-30 .. sunday 2026-10-18: september whole, the
+            // weeks of october that fit, days around them
+            let first = Utc.with_ymd_and_hms(2026, 8, 30, 0, 0, 0).unwrap();
+            let last = Utc.with_ymd_and_hms(2026, 10, 18, 0, 0, 0).unwrap();
+            let result = t.query_auto(first.timestamp(), last.timestamp()).unwrap();
+            let labels: Vec<&str> = result.days.iter().map(|d| d.date.as_str()).collect();
+            assert_eq!(
+                labels,
+                vec![
+                    "2026-08-30",
+                    "2026-08-31",
+                    "2026-09",
+                    "2026-10-01",
+                    "2026-10-02",
+                    "2026-10-03",
+                    "2026-10-04",
+                    "2026-W41",
+                    "2026-W42",
+                ]
+            );
+
+            // uniques are a hyperloglog union across every bucket type
+            assert_eq!(result.unique_counts.get("ip"), Some(&2));
+            let merged = result.merged();
+            let sales = merged.iter().find(|r| r.metric_type == "add").unwrap();
+            match &sales.result {
+                MetricData::Hash(h) => assert_eq!(h.get("sale"), Some(&4)),
+                other => panic!("expected Hash data, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_query_auto_reads_days_when_rollup_is_untracked() {
+            let mut config = test_config();
+            config.time.store_monthly = true;
+            let t = make_traqq(config);
+            record_daily_noon_events(&t, 2026, 9, &[3, 15]);
+
+            let sept_1 = 1788220800;
+            let sept_30 = sept_1 + 29 * 86400;
+            assert_eq!(t.query_auto(sept_1, sept_30).unwrap().days.len(), 1);
+
+            // as if the month was recorded before store_monthly was enabled
+            t.storage.delete(&[format!("q:k:M:{}", sept_1)]).unwrap();
+            let result = t.query_auto(sept_1, sept_30).unwrap();
+            assert_eq!(result.days.len(), 30);
+            let sales = result.find_str("add/event");
+            assert_eq!(sales.len(), 2);
+
+            // a month with nothing recorded stays one empty bucket
+            let oct_1 = sept_1 + 30 * 86400;
+            let result = t.query_auto(oct_1, oct_1 + 30 * 86400).unwrap();
+            assert_eq!(result.days.len(), 1);
+            assert!(result.days[0].results.is_empty());
+        }
+
+        fn record_daily_noon_events(t: &Traqq, year: i32, month: u32, days: &[u32]) {
+            use chrono::TimeZone;
+
+            let tz: chrono_tz::Tz = t.config.time.timezone.parse().unwrap();
+            for &day in days {
+                let noon = tz.with_ymd_and_hms(year, month, day, 12, 0, 0).unwrap();
+                t.record_at(
+                    IncomingEvent {
+                        event: "sale".to_string(),
+                        properties: serde_json::json!({ "ip": "1.1.1.1", "geo": "US" }),
+                    },
+                    noon.with_timezone(&Utc),
+                )
+                .unwrap();
+            }
+        }
+
+        fn assert_dst_range(year: i32, month: u32, days: &[u32]) {
+            use chrono::TimeZone;
+
+            let mut config = test_config();
+            config.time.timezone = "America/New_York".to_string();
+            let t = make_traqq(config);
+            record_daily_noon_events(&t, year, month, days);
+
+            let tz = chrono_tz::America::New_York;
+            let first = tz
+                .with_ymd_and_hms(year, month, days[0], 0, 0, 0)
+                .unwrap()
+                .timestamp();
+            let last = tz
+                .with_ymd_and_hms(year, month, *days.last().unwrap(), 0, 0, 0)
+                .unwrap()
+                .timestamp();
+
+            let result = t.query(first, last).unwrap();
+            let labels: Vec<String> = result.days.iter().map(|d| d.date.clone()).collect();
+            let expected: Vec<String> = days
+                .iter()
+                .map(|d| format!("{}-{:02}-{:02}", year, month, d))
+                .collect();
+            assert_eq!(labels, expected);
+
+            for day in &result.days {
+                assert!(!day.results.is_empty(), "{} came back empty", day.date);
+            }
+
+            let merged = result.find(FindOptions {
+                metric_type: MetricType::Add,
+                key: "event".to_string(),
+                add_key: None,
+                merge: true,
+                top: None,
+            });
+            if let MetricData::Hash(h) = &merged[0].result {
+                assert_eq!(h.get("sale"), Some(&(days.len() as i64)));
+            } else {
+                panic!("expected Hash data for add");
+            }
+        }
+
+        #[test]
+        fn test_query_across_spring_forward() {
+            // 2026-03-08 is a 23-hour day in America/New_York
+            assert_dst_range(2026, 3, &[6, 7, 8, 9, 10]);
+        }
+
+        #[test]
+        fn test_query_across_fall_back() {
+            // 2026-11-01 is a 25-hour day in America/New_York
+            assert_dst_range(2026, 11, &[1, 2, 3, 4]);
+        }
+
+        fn record_sale_at(t: &Traqq, ip: &str, at: DateTime<Utc>) {
+            t.record_at(
+                IncomingEvent {
+                    event: "sale".to_string(),
+                    properties: serde_json::json!({ "ip": ip, "geo": "US", "amount": 10.0 }),
+                },
+                at,
+            )
+            .unwrap();
+        }
+
+        #[test]
+        fn test_compact_removes_hourly_buckets() {
+            use chrono::TimeZone;
+
+            let mut config = test_config();
+            config.time.store_hourly = true;
+            config.time.store_monthly = true;
+            let t = make_traqq(config);
+
+            let day = Utc.with_ymd_and_hms(2026, 9, 10, 0, 0, 0).unwrap();
+            for (hour, ip) in [
+                (1, "1.1.1.1"),
+                (9, "2.2.2.2"),
+                (9, "1.1.1.1"),
+                (23, "3.3.3.3"),
+            ] {
+                record_sale_at(&t, ip, day + chrono::Duration::hours(hour));
+            }
+            // the next day's hour is newer than the cutoff
+            record_sale_at(&t, "4.4.4.4", day + chrono::Duration::hours(25));
+
+            let daily_before = t.query(day.timestamp(), day.timestamp()).unwrap();
+            let cutoff = day.timestamp() + 86400;
+            let result = t.compact(cutoff).unwrap();
+
+            assert!(result.warnings.is_empty(), "{:?}", result.warnings);
+            assert_eq!(result.buckets, 3);
+            assert!(result.keys > 3);
+
+            let hours = t.query_hours(day.timestamp(), cutoff - 1).unwrap();
+            assert!(hours.days.iter().all(|h| h.results.is_empty()));
+            let later = t.query_hours(cutoff, cutoff + 3600).unwrap();
+            assert!(!later.days[1].results.is_empty());
+
+            // rollups are untouched
+            let daily_after = t.query(day.timestamp(), day.timestamp()).unwrap();
+            assert_eq!(
+                format!("{:?}", daily_after.find_str("add/event")[0].result),
+                format!("{:?}", daily_before.find_str("add/event")[0].result)
+            );
+
+            // a second run has nothing left to do
+            assert_eq!(t.compact(cutoff).unwrap().buckets, 0);
+        }
+
+        #[test]
+        fn test_compact_keeps_hours_missing_from_rollup() {
+            use chrono::TimeZone;
+
+            let mut config = test_config();
+            config.time.store_hourly = true;
+            let t = make_traqq(config);
+
+            let day = Utc.with_ymd_and_hms(2026, 9, 10, 0, 0, 0).unwrap();
+            record_sale_at(&t, "1.1.1.1", day + chrono::Duration::hours(3));
+            t.storage
+                .delete(&[format!("q:add:d:{}:event", day.timestamp())])
+                .unwrap();
+
+            let result = t.compact(day.timestamp() + 86400).unwrap();
+            assert_eq!(result.buckets, 0);
+            assert_eq!(result.warnings.len(), 1);
+            assert!(
+                result.warnings[0].contains("add:event"),
+                "{:?}",
+                result.warnings
+            );
+
+            let hours = t
+                .query_hours(day.timestamp(), day.timestamp() + 86399)
+                .unwrap();
+            assert!(!hours.days[3].results.is_empty());
+        }
+
+        #[test]
+        fn test_query_range_expressions() {
+            let t = make_traqq(test_config());
+            record_test_events(&t);
+
+            let today = t.query_range("today").unwrap();
+            assert_eq!(today.days.len(), 1);
+            assert!(!today.days[0].results.is_empty());
+
+            let week = t.query_range("last 7d").unwrap();
+            assert_eq!(week.days.len(), 7);
+            assert_eq!(week.days[6].date, today.days[0].date);
+
+            assert!(t.query_range("yesterday").unwrap().days[0]
+                .results
+                .is_empty());
+            assert!(t.query_range("last fortnight").is_err());
+        }
+
+        #[test]
+        fn test_query_in_other_timezone_reaggregates_hours() {
+            use chrono::TimeZone;
+
+            let mut config = test_config();
+            config.time.store_hourly = true;
+            let t = make_traqq(config);
+
+            // written in UTC; 02:00 UTC on the 10th is still the 9th in new york
+            let hits = [
+                (2, "1.1.1.1"),
+                (15, "1.1.1.1"),
+                (16, "1.1.1.1"),
+                (16, "2.2.2.2"),
+            ];
+            for (hour, ip) in hits {
+                t.record_at(
+                    IncomingEvent {
+                        event: "sale".to_string(),
+                        properties: serde_json::json!({ "ip": ip }),
+                    },
+                    Utc.with_ymd_and_hms(2026, 9, 10, hour, 0, 0).unwrap(),
+                )
+                .unwrap();
+            }
+
+            let ny = chrono_tz::America::New_York;
+            let sept_9 = ny
+                .with_ymd_and_hms(2026, 9, 9, 0, 0, 0)
+                .unwrap()
+                .timestamp();
+            let sept_10 = ny
+                .with_ymd_and_hms(2026, 9, 10, 0, 0, 0)
+                .unwrap()
+                .timestamp();
+            let result = t
+                .query_in_timezone(sept_9, sept_10, "America/New_York")
+                .unwrap();
+
+            assert_eq!(result.days.len(), 2);
+            assert_eq!(result.days[0].date, "2026-09-09");
+            assert_eq!(result.days[0].timestamp, sept_9);
+
+            let per_day = |day: &DayResult, metric_type: &str| {
+                day.results
+                    .iter()
+                    .find(|r| r.metric_type == metric_type)
+                    .map(|r| r.result.clone())
+            };
+
+            match per_day(&result.days[0], "add") {
+                Some(MetricData::Hash(h)) => assert_eq!(h.get("sale"), Some(&1)),
+                other => panic!("expected Hash data, got {:?}", other),
+            }
+            match per_day(&result.days[1], "add") {
+                Some(MetricData::Hash(h)) => assert_eq!(h.get("sale"), Some(&3)),
+                other => panic!("expected Hash data, got {:?}", other),
+            }
+            // 1.1.1.1 appears in two hours but is one unique
+            match per_day(&result.days[1], "bmp") {
+                Some(MetricData::Count(c)) => assert_eq!(c, 2),
+                other => panic!("expected Count data, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_query_in_other_timezone_warns_about_missing_hours() {
+            use chrono::TimeZone;
+
+            let mut config = test_config();
+            config.time.store_hourly = true;
+            let t = make_traqq(config);
+
+            let day = Utc.with_ymd_and_hms(2026, 9, 10, 0, 0, 0).unwrap();
+            record_sale_at(&t, "1.1.1.1", day + chrono::Duration::hours(12));
+            let (min, max) = (day.timestamp(), day.timestamp() + 86399);
+            let before = t.query_in_timezone(min, max, "Asia/Tokyo").unwrap();
+            assert!(before.warnings.is_empty(), "{:?}", before.warnings);
+
+            // the day's hours are compacted away, leaving only the daily bucket
+            assert_eq!(t.compact(day.timestamp() + 86400).unwrap().buckets, 1);
+            let after = t.query_in_timezone(min, max, "Asia/Tokyo").unwrap();
+            assert_eq!(after.warnings.len(), 1, "{:?}", after.warnings);
+            assert!(
+                after.warnings[0].starts_with("2026-09-10 has no hourly buckets"),
+                "{:?}",
+                after.warnings
+            );
+        }
+
+        #[test]
+        fn test_query_in_other_timezone_requires_hourly_data() {
+            let t = make_traqq(test_config());
+            let err = match t.query_in_timezone(0, 86400, "Asia/Tokyo") {
+                Err(e) => e,
+                Ok(_) => panic!("expected an error without hourly buckets"),
+            };
+            assert!(err.contains("store_hourly"), "got {}", err);
+        }
+
+        #[test]
+        fn test_hour_buckets_in_configured_timezone() {
+            let mut config = test_config();
+            config.time.timezone = "America/New_York".to_string();
+            config.time.store_hourly = true;
+
+            // 2024-11-03 is the fall-back day: 01:00 local occurs twice
+            // 05:00 UTC = 01:00 EDT, 06:00 UTC = 01:00 EST
+            let first = 1730610000;
+            let second = first + 3600;
+            assert_eq!(
+                config.bucket_start(second, BucketType::Hourly).unwrap(),
+                first
+            );
+            assert_eq!(
+                config.next_bucket_start(first, BucketType::Hourly).unwrap(),
+                second + 3600
+            );
+            assert_eq!(
+                config.bucket_label(first, BucketType::Hourly),
+                "2024-11-03T01:00:00-04:00"
+            );
+            assert_eq!(config.bucket_label(first, BucketType::Daily), "2024-11-03");
+        }
+    }
+
+    mod server_client_tests {
+        use super::*;
+        use crate::client::Client;
+        use crate::server::{self, Response};
+        use crate::storage::memory::MemoryStorage;
+        use crate::stream::Page;
+        use std::io::{BufRead, BufReader, Write};
+        use std::sync::Arc;
+        use std::thread;
+        use std::time::Duration;
+
+        #[test]
+        fn test_server_client_record_and_query() {
+            let config = TraqqConfig {
+                time: TimeConfig {
+                    store_hourly: false,
+                    timezone: "UTC".to_string(),
+                    ..TimeConfig::default()
+                },
+                mapping: MappingConfig {
+                    bitmap: vec!["ip".to_string()],
+                    add: vec!["event".to_string()],
+                    add_value: vec![],
+                    top: vec!["geo".to_string()],
+                    ..MappingConfig::default()
+                },
+                limits: LimitsConfig::default(),
+                alerts: alert::AlertsConfig::default(),
+            };
+
+            let traqq =
+                Arc::new(Traqq::new(config, Box::new(MemoryStorage::new()), "srv").unwrap());
+
+            // start server on a random-ish port
+            let addr = "127.0.0.1:19876";
+            let server_traqq = Arc::clone(&traqq);
+            let _server_handle = thread::spawn(move || {
+                let _ = server::run(server_traqq, addr);
+            });
+
+            // give the server a moment to bind
+            thread::sleep(Duration::from_millis(50));
+
+            let mut client = Client::connect(addr).unwrap();
+
+            // record events
+            let r = client
+                .record(serde_json::json!({
+                    "event": "sale",
+                    "ip": "1.1.1.1",
+                    "geo": "US",
+                }))
+                .unwrap();
+            assert!(r.success, "record should succeed");
+
+            let r = client
+                .record(serde_json::json!({
+                    "event": "click",
+                    "ip": "2.2.2.2",
+                    "geo": "UK",
+                }))
+                .unwrap();
+            assert!(r.success);
+
+            // query days
+            let r = client.query_days(1).unwrap();
+            assert!(r.success, "query_days should succeed");
+            assert!(r.data.is_some(), "query should return data");
+
+            // find specific metrics
+            let now = chrono::Utc::now().timestamp();
+            let day_start = now - (now % 86400);
+
+            let r = client
                 .find(day_start, day_start, "add", "event", None, false)
                 .unwrap();
             assert!(r.success, "find should succeed");
@@ -1467,5 +3433,284 @@ mod tests {
             // the server thread will block on accept() after we drop the client,
             // so we just let it be (it's a daemon thread effectively)
         }
+
+        /// serve the `test_server_client_record_and_query` config on `addr`, with
+        /// a sale and a click recorded
+        fn serve(addr: &'static str) -> Client {
+            let config = TraqqConfig {
+                time: TimeConfig {
+                    store_hourly: false,
+                    timezone: "UTC".to_string(),
+                    ..TimeConfig::default()
+                },
+                mapping: MappingConfig {
+                    bitmap: vec!["ip".to_string()],
+                    add: vec!["event".to_string()],
+                    add_value: vec![],
+                    top: vec!["geo".to_string()],
+                    ..MappingConfig::default()
+                },
+                limits: LimitsConfig::default(),
+                alerts: alert::AlertsConfig::default(),
+            };
+            let traqq =
+                Arc::new(Traqq::new(config, Box::new(MemoryStorage::new()), "srv").unwrap());
+
+            thread::spawn(move || {
+                let _ = server::run(traqq, addr);
+            });
+            thread::sleep(Duration::from_millis(50));
+
+            let mut client = Client::connect(addr).unwrap();
+            for (event, ip, geo) in [("sale", "1.1.1.1", "US"), ("click", "2.2.2.2", "UK")] {
+                let r = client
+                    .record(serde_json::json!({ "event": event, "ip": ip, "geo": geo }))
+                    .unwrap();
+                assert!(r.success, "record should succeed");
+            }
+            client
+        }
+
+        #[test]
+        fn test_server_query_range() {
+            let mut client = serve("127.0.0.1:19878");
+
+            let r = client
+                .query_range("today", BucketType::Daily, None)
+                .unwrap();
+            assert!(r.success, "query_range should succeed");
+            let days: Vec<serde_json::Value> = serde_json::from_value(r.data.unwrap()).unwrap();
+            assert_eq!(days.len(), 1);
+
+            let r = client
+                .query_range("next tuesday", BucketType::Daily, None)
+                .unwrap();
+            assert!(!r.success);
+            assert!(r.error.unwrap().starts_with("invalid range"));
+        }
+
+        #[test]
+        fn test_server_series() {
+            let mut client = serve("127.0.0.1:19879");
+
+            let r = client
+                .series(
+                    "add/event",
+                    &["sale".to_string()],
+                    "today",
+                    BucketType::Daily,
+                )
+                .unwrap();
+            assert!(r.success, "series should succeed");
+            let series: Vec<Series> = serde_json::from_value(r.data.unwrap()).unwrap();
+            assert_eq!(series[0].points.len(), 1);
+            assert_eq!(series[0].points[0].1, 1.0);
+
+            // only bmp series default their field
+            let r = client
+                .series("bmp/ip", &[], "today", BucketType::Daily)
+                .unwrap();
+            let series: Vec<Series> = serde_json::from_value(r.data.unwrap()).unwrap();
+            assert_eq!(series[0].field, "count");
+            let r = client
+                .series("add/event", &[], "today", BucketType::Daily)
+                .unwrap();
+            assert!(!r.success, "add series without fields are rejected");
+            assert!(r.error.unwrap().contains("fields are required"));
+        }
+
+        #[test]
+        fn test_server_find_rejects_unknown_metric_type() {
+            let addr = "127.0.0.1:19880";
+            let _client = serve(addr);
+            let now = chrono::Utc::now().timestamp();
+            let day_start = now - (now % 86400);
+
+            // the typed client can't send a bad type, so write the line raw
+            let mut raw = std::net::TcpStream::connect(addr).unwrap();
+            writeln!(
+                raw,
+                "{}",
+                serde_json::json!({
+                    "cmd": "find", "min": day_start, "max": day_start,
+                    "metric_type": "ad", "key": "event",
+                })
+            )
+            .unwrap();
+            let mut line = String::new();
+            BufReader::new(&raw).read_line(&mut line).unwrap();
+            let r: Response = serde_json::from_str(&line).unwrap();
+            assert!(!r.success, "unknown metric types are rejected");
+            assert!(r.error.unwrap().contains("unknown metric type"));
+        }
+
+        #[test]
+        fn test_server_query_auto() {
+            let mut client = serve("127.0.0.1:19881");
+
+            // no rollups stored, so auto reads days
+            let r = client.query_auto("today", None).unwrap();
+            assert!(r.success, "auto query should succeed");
+            let days = r.data.unwrap();
+            assert_eq!(days.as_array().unwrap().len(), 1);
+        }
+
+        #[test]
+        fn test_server_ql() {
+            let mut client = serve("127.0.0.1:19882");
+
+            let r = client.ql("count add/event top 1").unwrap();
+            assert!(r.success, "ql should succeed");
+            let table: crate::ql::Table = serde_json::from_value(r.data.unwrap()).unwrap();
+            assert_eq!(table.rows[0].value, 2.0);
+            let r = client.ql("count ad/event").unwrap();
+            assert!(r.error.unwrap().contains("(column 7)"));
+        }
+
+        #[test]
+        fn test_server_compare() {
+            let mut client = serve("127.0.0.1:19883");
+
+            let r = client.compare("today", "yesterday").unwrap();
+            assert!(r.success, "compare should succeed");
+            let comparison: crate::compare::Comparison =
+                serde_json::from_value(r.data.unwrap()).unwrap();
+            let sale = comparison
+                .metric("add/event")
+                .unwrap()
+                .unwrap()
+                .field("sale")
+                .unwrap();
+            assert_eq!(sale.status, crate::compare::FieldStatus::New);
+            assert_eq!(sale.delta, 1.0);
+        }
+
+        #[test]
+        fn test_server_anomalies() {
+            let mut client = serve("127.0.0.1:19884");
+
+            let r = client
+                .anomalies("bmp/ip", &[], "today", BucketType::Daily)
+                .unwrap();
+            assert!(r.success, "anomalies should succeed");
+            let reports: Vec<crate::anomaly::Report> =
+                serde_json::from_value(r.data.unwrap()).unwrap();
+            assert_eq!(reports[0].field, "count");
+            let r = client
+                .anomalies("add/event", &[], "today", BucketType::Daily)
+                .unwrap();
+            assert!(r.error.unwrap().contains("fields are required"));
+        }
+
+        #[test]
+        fn test_server_query_stream() {
+            let mut client = serve("127.0.0.1:19885");
+
+            let chunks: Vec<Response> = client
+                .query_stream("last 2d", BucketType::Daily, None)
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(chunks.len(), 2, "one chunk per day");
+
+            // ties rank by name, so the first page of add/event is click
+            let page = Page {
+                offset: 0,
+                limit: 1,
+            };
+            let mut chunks = client
+                .query_stream("today", BucketType::Daily, Some(page))
+                .unwrap();
+            let today = chunks.next().unwrap().unwrap().data.unwrap();
+            let events = today["results"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|r| r["metric_type"] == "add")
+                .unwrap();
+            assert_eq!(events["result"]["Hash"], serde_json::json!({ "click": 1 }));
+            assert!(chunks.next().is_none());
+            drop(chunks);
+
+            let mut chunks = client
+                .query_stream("someday", BucketType::Daily, None)
+                .unwrap();
+            assert!(chunks
+                .next()
+                .unwrap()
+                .unwrap_err()
+                .starts_with("invalid range"));
+            assert!(chunks.next().is_none());
+            drop(chunks);
+
+            // a stream dropped part way leaves the connection usable
+            let first = client
+                .query_stream("last 7d", BucketType::Daily, None)
+                .unwrap()
+                .next();
+            assert!(first.unwrap().is_ok());
+            let r = client.query_days(1).unwrap();
+            assert!(r.success);
+        }
+
+        #[test]
+        fn test_server_alert_subscription() {
+            let file =
+                std::env::temp_dir().join(format!("traqq-alerts-{}.jsonl", std::process::id()));
+            let _ = std::fs::remove_file(&file);
+
+            let alerts = alert::AlertsConfig {
+                rules: vec![alert::AlertRule {
+                    name: "any_sale".to_string(),
+                    metric: "add/event".to_string(),
+                    field: "sale".to_string(),
+                    per: None,
+                    window: 3600,
+                    comparison: alert::Comparison::Above,
+                    threshold: 0.0,
+                    cooldown: 3600,
+                    granularity: None,
+                }],
+                interval: 1,
+                file: Some(file.to_string_lossy().into_owned()),
+            };
+            let config = TraqqConfig {
+                alerts,
+                ..TraqqConfig::default()
+            };
+            let traqq =
+                Arc::new(Traqq::new(config, Box::new(MemoryStorage::new()), "alerts").unwrap());
+
+            let addr = "127.0.0.1:19877";
+            let server_traqq = Arc::clone(&traqq);
+            thread::spawn(move || {
+                let _ = server::run(server_traqq, addr);
+            });
+            thread::sleep(Duration::from_millis(50));
+
+            let mut firings = Client::connect(addr).unwrap().subscribe_alerts().unwrap();
+            let (tx, rx) = std::sync::mpsc::channel();
+            thread::spawn(move || {
+                let _ = tx.send(firings.next());
+            });
+
+            let mut client = Client::connect(addr).unwrap();
+            let r = client
+                .record(serde_json::json!({ "event": "sale" }))
+                .unwrap();
+            assert!(r.success);
+
+            let firing = rx
+                .recv_timeout(Duration::from_secs(10))
+                .expect("no alert within 10s")
+                .unwrap()
+                .unwrap();
+            assert_eq!((firing.rule.as_str(), firing.value), ("any_sale", 1.0));
+
+            let logged = std::fs::read_to_string(&file).unwrap();
+            let line: alert::Firing = serde_json::from_str(logged.lines().next().unwrap()).unwrap();
+            assert_eq!(line, firing);
+            let _ = std::fs::remove_file(&file);
+        }
     }
 }
@@ -4,6 +4,7 @@ use rand::distributions::Alphanumeric;
 use rand::prelude::SliceRandom;
 use rand::{thread_rng, Rng};
 use serde_json::json;
+use std::collections::HashSet;
 
 use crate::constants;
 
@@ -178,6 +179,15 @@ pub fn sort_keys(keys: &[String]) -> Vec<String> {
     sorted
 }
 
+// remove duplicates while keeping first-seen order
+pub fn dedup<T: Clone + Eq + std::hash::Hash>(items: Vec<T>) -> Vec<T> {
+    let mut seen = HashSet::new();
+    items
+        .into_iter()
+        .filter(|item| seen.insert(item.clone()))
+        .collect()
+}
+
 pub fn sanitize_value(value: &str, max_length: usize) -> Result<Option<String>, String> {
     let max_length = if max_length == 0 {
         constants::MAX_VALUE_LENGTH
@@ -208,6 +218,75 @@ pub fn parse_timezone(tz: &str) -> Result<chrono_tz::Tz, String> {
     tz.parse().map_err(|_| format!("invalid timezone: {}", tz))
 }
 
+// true if a property name can stand in for a glob segment: it has to fit
+// into a storage key as-is, so no separators and no longer than `max_length`
+pub fn is_expandable_name(name: &str, max_length: usize) -> bool {
+    !name.is_empty()
+        && !name.contains(&constants::INVALID_CHARS[..])
+        && name.chars().count() <= max_length
+}
+
+// true if a pattern segment contains wildcard characters
+pub fn is_glob(segment: &str) -> bool {
+    segment.contains(['*', '?'])
+}
+
+// match a name against a glob: `*` matches any run, `?` any single char
+pub fn glob_match(glob: &str, name: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut g, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if g < glob.len() && (glob[g] == '?' || glob[g] == name[n]) {
+            g += 1;
+            n += 1;
+        } else if g < glob.len() && glob[g] == '*' {
+            backtrack = Some((g, n));
+            g += 1;
+        } else if let Some((star, matched)) = backtrack {
+            g = star + 1;
+            n = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+// match a compound pattern against a glob in any segment order,
+// so `geo~event` matches `event~geo`
+pub fn pattern_match(glob: &str, pattern: &str) -> bool {
+    let sorted = |p: &str| {
+        let mut segments: Vec<&str> = p.split('~').collect();
+        segments.sort();
+        segments.join("~")
+    };
+    glob_match(glob, pattern) || glob_match(&sorted(glob), &sorted(pattern))
+}
+
+// the value of the most specific key that `matches`: a literal key before
+// any glob, then the glob with the most literal characters, ties broken by
+// key order so overlapping globs resolve the same way every time
+pub fn most_specific<'a, V>(
+    entries: impl IntoIterator<Item = (&'a String, &'a V)>,
+    matches: impl Fn(&str) -> bool,
+) -> Option<&'a V> {
+    let specificity = |key: &str| {
+        let literals = key.chars().filter(|c| !matches!(c, '*' | '?')).count();
+        (!is_glob(key), literals)
+    };
+
+    entries
+        .into_iter()
+        .filter(|(key, _)| matches(key))
+        .min_by(|(a, _), (b, _)| specificity(b).cmp(&specificity(a)).then_with(|| a.cmp(b)))
+        .map(|(_, value)| value)
+}
+
 // validate a string mapping pattern
 pub fn validate_mapping_pattern(pattern: &str) -> Result<(), String> {
     let validation_rules = [
@@ -220,6 +299,12 @@ pub fn validate_mapping_pattern(pattern: &str) -> Result<(), String> {
             pattern.starts_with('~') || pattern.ends_with('~'),
             "pattern cannot start or end with separator",
         ),
+        (
+            pattern
+                .split('~')
+                .any(|s| is_glob(s) && s.chars().all(|c| c == '*' || c == '?')),
+            "wildcard segment must contain at least one literal character",
+        ),
     ];
 
     validation_rules
@@ -231,6 +316,7 @@ pub fn validate_mapping_pattern(pattern: &str) -> Result<(), String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
 
     #[test]
     fn test_create_test_event() {
@@ -258,14 +344,50 @@ mod tests {
     fn test_validate_mapping_pattern() {
         let pattern = "~event~";
         let result = validate_mapping_pattern(pattern);
-        assert_eq!(result.is_err(), true);
+        assert!(result.is_err());
 
         let pattern = "event";
         let result = validate_mapping_pattern(pattern);
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
 
         let pattern = "event~source";
         let result = validate_mapping_pattern(pattern);
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
+
+        assert!(validate_mapping_pattern("event~utm_*").is_ok());
+        assert!(validate_mapping_pattern("event~*").is_err());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("utm_*", "utm_source"));
+        assert!(glob_match("utm_*", "utm_"));
+        assert!(glob_match("*_id", "offer_id"));
+        assert!(glob_match("utm_?ource", "utm_source"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("utm_*", "event"));
+        assert!(!glob_match("utm_?", "utm_source"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+    }
+
+    #[test]
+    fn test_pattern_match_ignores_segment_order() {
+        assert!(pattern_match("geo~event", "event~geo"));
+        assert!(pattern_match("event~*", "event~geo"));
+        assert!(!pattern_match("event~geo", "event~os"));
+    }
+
+    #[test]
+    fn test_most_specific_prefers_literals_then_longer_globs() {
+        let entries: HashMap<String, usize> = [("*", 1), ("geo*", 2), ("ge*", 3), ("geo", 4)]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+        let lookup = |name: &str| most_specific(&entries, |key| glob_match(key, name)).copied();
+
+        assert_eq!(lookup("geo"), Some(4));
+        assert_eq!(lookup("geo_city"), Some(2));
+        assert_eq!(lookup("gender"), Some(3));
+        assert_eq!(lookup("event"), Some(1));
     }
 }